@@ -0,0 +1,188 @@
+//! Comparing encoded frames: static runs, scene cuts and loop points.
+
+use anyhow::{Context, Result};
+use image::{RgbImage, imageops};
+
+/// Frames are compared at this size when looking for scene cuts and loops
+const THUMBNAIL_SIZE: u32 = 64;
+
+pub fn decode_encoded(data: &[u8]) -> Result<RgbImage> {
+    Ok(image::load_from_memory(data)
+        .context("Failed to decode frame")?
+        .into_rgb8())
+}
+
+/// Small copy of a frame, fast to compare and blind to noise and small motion
+pub fn thumbnail(frame: &[u8]) -> Result<RgbImage> {
+    Ok(imageops::thumbnail(
+        &decode_encoded(frame)?,
+        THUMBNAIL_SIZE,
+        THUMBNAIL_SIZE,
+    ))
+}
+
+/// Mean absolute difference per channel (0-255), infinite for different sizes
+pub fn mean_difference(a: &RgbImage, b: &RgbImage) -> f64 {
+    if a.dimensions() != b.dimensions() {
+        return f64::INFINITY;
+    }
+
+    let total: u64 = a
+        .as_raw()
+        .iter()
+        .zip(b.as_raw())
+        .map(|(&x, &y)| x.abs_diff(y) as u64)
+        .sum();
+    total as f64 / a.as_raw().len().max(1) as f64
+}
+
+/// Follows frames in order and tells which ones look like the last different frame
+///
+/// A frame within `threshold` (mean difference) of the reference frame is static,
+/// any other frame becomes the new reference.
+pub struct StaticRun {
+    threshold: f64,
+    reference: Option<(Vec<u8>, Option<RgbImage>)>,
+}
+
+impl StaticRun {
+    pub fn new(threshold: f64) -> Self {
+        StaticRun {
+            threshold,
+            reference: None,
+        }
+    }
+
+    /// Forgets the reference frame, e.g. at the start of a part
+    pub fn reset(&mut self) {
+        self.reference = None;
+    }
+
+    pub fn is_static(&mut self, frame: &[u8]) -> Result<bool> {
+        if let Some((data, decoded)) = &mut self.reference {
+            if data == frame {
+                return Ok(true);
+            }
+            if self.threshold > 0.0 {
+                if decoded.is_none() {
+                    *decoded = Some(decode_encoded(data)?);
+                }
+                let image = decode_encoded(frame)?;
+                if mean_difference(decoded.as_ref().unwrap(), &image) <= self.threshold {
+                    return Ok(true);
+                }
+                self.reference = Some((frame.to_vec(), Some(image)));
+                return Ok(false);
+            }
+        }
+
+        self.reference = Some((frame.to_vec(), None));
+        Ok(false)
+    }
+}
+
+/// Follows frames in order and tells which ones start a new scene
+pub struct SceneDetector {
+    threshold: f64,
+    previous: Option<RgbImage>,
+}
+
+impl SceneDetector {
+    /// `threshold` is the mean difference (0-255) between consecutive frames that counts as a cut
+    pub fn new(threshold: f64) -> Self {
+        SceneDetector {
+            threshold,
+            previous: None,
+        }
+    }
+
+    pub fn is_cut(&mut self, frame: &[u8]) -> Result<bool> {
+        let thumbnail = thumbnail(frame)?;
+        let cut = self
+            .previous
+            .as_ref()
+            .is_some_and(|previous| mean_difference(previous, &thumbnail) >= self.threshold);
+        self.previous = Some(thumbnail);
+        Ok(cut)
+    }
+}
+
+/// Most similar pair of a frame in `starts` and one in `ends`, as indices into both
+///
+/// `allowed` filters pairs by index; ties go to the first start and the last end.
+pub fn best_match(
+    starts: &[RgbImage],
+    ends: &[RgbImage],
+    allowed: impl Fn(usize, usize) -> bool,
+) -> Option<(usize, usize, f64)> {
+    let mut best: Option<(usize, usize, f64)> = None;
+    for (s, start) in starts.iter().enumerate() {
+        for (e, end) in ends.iter().enumerate().rev() {
+            if !allowed(s, e) {
+                continue;
+            }
+            let difference = mean_difference(start, end);
+            if best.is_none_or(|(_, _, lowest)| difference < lowest) {
+                best = Some((s, e, difference));
+            }
+        }
+    }
+    best
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::animation::encode_frame;
+    use crate::frames::{FrameQuality, ImageFormat};
+    use image::Rgb;
+
+    fn solid(value: u8) -> Vec<u8> {
+        let image = RgbImage::from_pixel(8, 8, Rgb([value; 3]));
+        encode_frame(&image, ImageFormat::Png, FrameQuality::default()).unwrap()
+    }
+
+    fn static_flags(threshold: f64, frames: &[Vec<u8>]) -> Vec<bool> {
+        let mut run = StaticRun::new(threshold);
+        frames.iter().map(|frame| run.is_static(frame).unwrap()).collect()
+    }
+
+    #[test]
+    fn only_identical_frames_are_static_without_a_threshold() {
+        let frames = [solid(10), solid(10), solid(12), solid(12), solid(10)];
+        assert_eq!(static_flags(0.0, &frames), [false, true, false, true, false]);
+    }
+
+    #[test]
+    fn frames_within_the_threshold_of_the_reference_are_static() {
+        // the reference stays on 10, so a slow drift ends the run once it adds up
+        let frames = [solid(10), solid(12), solid(14), solid(16), solid(17)];
+        assert_eq!(static_flags(4.0, &frames), [false, true, true, false, true]);
+    }
+
+    #[test]
+    fn reset_forgets_the_reference() {
+        let mut run = StaticRun::new(0.0);
+        assert!(!run.is_static(&solid(10)).unwrap());
+        assert!(run.is_static(&solid(10)).unwrap());
+        run.reset();
+        assert!(!run.is_static(&solid(10)).unwrap());
+    }
+
+    #[test]
+    fn hard_cuts_start_a_new_scene() {
+        let mut scenes = SceneDetector::new(30.0);
+        let frames = [solid(0), solid(2), solid(4), solid(200), solid(202), solid(20)];
+        let cuts: Vec<bool> = frames.iter().map(|f| scenes.is_cut(f).unwrap()).collect();
+        assert_eq!(cuts, [false, false, false, true, false, true]);
+    }
+
+    #[test]
+    fn gradual_changes_are_not_cuts() {
+        // 250 levels of change in total, but never 30 between two frames
+        let mut scenes = SceneDetector::new(30.0);
+        for value in (0..=250).step_by(25) {
+            assert!(!scenes.is_cut(&solid(value)).unwrap());
+        }
+    }
+}
@@ -0,0 +1,295 @@
+//! In-process decoding of GIF, APNG and animated WebP files.
+
+use crate::frames::{FrameQuality, ImageFormat, ScaleMode, rgb_color};
+use anyhow::{Context, Result};
+use image::codecs::gif::GifDecoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::{PngDecoder, PngEncoder};
+use image::codecs::webp::WebPDecoder;
+use image::imageops::{self, FilterType};
+use image::{
+    AnimationDecoder, Frame, ImageBuffer, ImageEncoder, ImageReader, Pixel, RgbImage, Rgba, RgbaImage,
+};
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::Path;
+
+/// JPEG quality used for decoded frames
+pub const JPEG_QUALITY: u8 = 90;
+
+/// Browsers play delays this short as 100 ms, and so do we
+const MIN_DELAY: f64 = 0.02;
+const DEFAULT_DELAY: f64 = 0.1;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AnimationKind {
+    Gif,
+    Png,
+    WebP,
+}
+
+/// Fully composited frames of an animated image
+pub struct Animation {
+    pub kind: AnimationKind,
+    pub width: u32,
+    pub height: u32,
+    pub frames: Vec<RgbaImage>,
+    /// How long each frame is shown, in seconds
+    pub delays: Vec<f64>,
+}
+
+impl AnimationKind {
+    /// Detects the format from the file signature, `None` for anything else (e.g. videos)
+    pub fn detect(path: &Path) -> Result<Option<Self>> {
+        let mut header = [0u8; 12];
+        let mut file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        let len = file.read(&mut header)?;
+        let header = &header[..len];
+
+        Ok(if header.starts_with(b"GIF87a") || header.starts_with(b"GIF89a") {
+            Some(AnimationKind::Gif)
+        } else if header.starts_with(b"\x89PNG\r\n\x1a\n") {
+            Some(AnimationKind::Png)
+        } else if header.len() == 12 && &header[..4] == b"RIFF" && &header[8..] == b"WEBP" {
+            Some(AnimationKind::WebP)
+        } else {
+            None
+        })
+    }
+}
+
+impl Animation {
+    /// Decodes `path` if it is a GIF, (A)PNG or WebP file, `None` otherwise
+    pub fn open(path: &Path) -> Result<Option<Self>> {
+        let Some(kind) = AnimationKind::detect(path)? else {
+            return Ok(None);
+        };
+
+        let reader = || -> Result<BufReader<File>> { Ok(BufReader::new(File::open(path)?)) };
+        let context = || format!("Failed to decode {}", path.display());
+
+        let frames: Vec<Frame> = match kind {
+            AnimationKind::Gif => GifDecoder::new(reader()?)
+                .with_context(context)?
+                .into_frames()
+                .collect_frames()
+                .with_context(context)?,
+            AnimationKind::Png => {
+                let decoder = PngDecoder::new(reader()?).with_context(context)?;
+                if decoder.is_apng().with_context(context)? {
+                    decoder
+                        .apng()
+                        .with_context(context)?
+                        .into_frames()
+                        .collect_frames()
+                        .with_context(context)?
+                } else {
+                    Vec::new()
+                }
+            }
+            AnimationKind::WebP => {
+                let decoder = WebPDecoder::new(reader()?).with_context(context)?;
+                if decoder.has_animation() {
+                    decoder.into_frames().collect_frames().with_context(context)?
+                } else {
+                    Vec::new()
+                }
+            }
+        };
+
+        // still pictures become a single frame
+        let (frames, delays) = if frames.is_empty() {
+            let image = ImageReader::open(path)?
+                .with_guessed_format()?
+                .decode()
+                .with_context(context)?
+                .into_rgba8();
+            (vec![image], vec![DEFAULT_DELAY])
+        } else {
+            frames
+                .into_iter()
+                .map(|frame| {
+                    let (numer, denom) = frame.delay().numer_denom_ms();
+                    let delay = numer as f64 / denom.max(1) as f64 / 1000.0;
+                    let delay = if delay < MIN_DELAY { DEFAULT_DELAY } else { delay };
+                    (frame.into_buffer(), delay)
+                })
+                .unzip()
+        };
+
+        let (width, height) = frames[0].dimensions();
+        Ok(Some(Animation {
+            kind,
+            width,
+            height,
+            frames,
+            delays,
+        }))
+    }
+
+    pub fn duration(&self) -> f64 {
+        self.delays.iter().sum()
+    }
+
+    /// Frame rate matching the shortest frame delay, at most 60
+    pub fn natural_fps(&self) -> u32 {
+        let shortest = self.delays.iter().copied().fold(f64::MAX, f64::min);
+        ((1.0 / shortest).round() as u32).clamp(1, 60)
+    }
+
+    /// Source frame shown at each output frame when played at `fps`
+    pub fn resample(&self, fps: u32) -> Vec<usize> {
+        if self.frames.len() == 1 {
+            return vec![0];
+        }
+
+        let count = ((self.duration() * fps as f64).round() as usize).max(1);
+        let mut indices = Vec::with_capacity(count);
+        let mut source = 0;
+        let mut source_end = self.delays[0];
+
+        for idx in 0..count {
+            // sample the middle of each output frame
+            let time = (idx as f64 + 0.5) / fps as f64;
+            while time >= source_end && source + 1 < self.frames.len() {
+                source += 1;
+                source_end += self.delays[source];
+            }
+            indices.push(source);
+        }
+
+        indices
+    }
+}
+
+/// Scales `image` onto a `width`x`height` canvas of the background color and encodes it
+pub fn render_frame(
+    image: &RgbaImage,
+    width: u32,
+    height: u32,
+    scale_mode: ScaleMode,
+    background: Option<&str>,
+    format: ImageFormat,
+    quality: FrameQuality,
+) -> Result<Vec<u8>> {
+    let (src_width, src_height) = image.dimensions();
+    let placement = scale_mode.placement(src_width, src_height, width, height);
+    let scaled_width = ((src_width as f64 * placement.scale_x).round() as u32).max(1);
+    let scaled_height = ((src_height as f64 * placement.scale_y).round() as u32).max(1);
+
+    let [r, g, b] = rgb_color(background);
+    let mut canvas = RgbaImage::from_pixel(width, height, Rgba([r, g, b, 255]));
+    let scaled;
+    let source = if (scaled_width, scaled_height) == (src_width, src_height) {
+        image
+    } else {
+        scaled = imageops::resize(image, scaled_width, scaled_height, FilterType::CatmullRom);
+        &scaled
+    };
+    imageops::overlay(
+        &mut canvas,
+        source,
+        placement.offset_x.round() as i64,
+        placement.offset_y.round() as i64,
+    );
+
+    // transparency is flattened onto the background, like the FFmpeg path does
+    let rgb = image::DynamicImage::ImageRgba8(canvas).into_rgb8();
+    encode_frame(&rgb, format, quality)
+}
+
+/// Mixes `b` into `a`, `amount` 0 gives `a` and 1 gives `b`
+pub fn blend<P: Pixel<Subpixel = u8>>(
+    a: &ImageBuffer<P, Vec<u8>>,
+    b: &ImageBuffer<P, Vec<u8>>,
+    amount: f64,
+) -> ImageBuffer<P, Vec<u8>> {
+    let mut mixed = a.clone();
+    for (value, &other) in mixed.iter_mut().zip(b.iter()) {
+        *value = (*value as f64 * (1.0 - amount) + other as f64 * amount).round() as u8;
+    }
+    mixed
+}
+
+pub fn encode_frame(image: &RgbImage, format: ImageFormat, quality: FrameQuality) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    match format {
+        ImageFormat::Jpg => {
+            JpegEncoder::new_with_quality(&mut encoded, quality.jpeg.unwrap_or(JPEG_QUALITY))
+                .encode_image(image)
+                .context("Failed to encode JPEG frame")?
+        }
+        ImageFormat::Png => PngEncoder::new(&mut encoded)
+            .write_image(image, image.width(), image.height(), image::ExtendedColorType::Rgb8)
+            .context("Failed to encode PNG frame")?,
+    }
+
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::codecs::gif::GifEncoder;
+    use image::{Delay, ImageFormat as Format};
+    use tempfile::TempDir;
+
+    fn animation(delays: &[f64]) -> Animation {
+        Animation {
+            kind: AnimationKind::Gif,
+            width: 4,
+            height: 4,
+            frames: vec![RgbaImage::new(4, 4); delays.len()],
+            delays: delays.to_vec(),
+        }
+    }
+
+    /// GIF of solid frames shown for `delays_ms` each
+    fn gif(dir: &Path, delays_ms: &[u32]) -> std::path::PathBuf {
+        let path = dir.join("anim.gif");
+        let mut encoder = GifEncoder::new(File::create(&path).unwrap());
+        for (idx, &ms) in delays_ms.iter().enumerate() {
+            let image = RgbaImage::from_pixel(4, 4, Rgba([idx as u8 * 60, 0, 0, 255]));
+            let delay = Delay::from_numer_denom_ms(ms, 1);
+            encoder.encode_frame(Frame::from_parts(image, 0, 0, delay)).unwrap();
+        }
+        drop(encoder);
+        path
+    }
+
+    #[test]
+    fn uneven_delays_are_sampled_at_the_shortest_one() {
+        let anim = animation(&[0.1, 0.3, 0.05]);
+        assert_eq!(anim.natural_fps(), 20);
+        assert_eq!(anim.resample(20), [0, 0, 1, 1, 1, 1, 1, 1, 2]);
+        // frames shorter than an output frame are skipped when none is sampled in them
+        assert_eq!(anim.resample(4), [1, 1]);
+    }
+
+    #[test]
+    fn zero_and_too_short_delays_play_at_10_fps() {
+        let temp = TempDir::new().unwrap();
+        let anim = Animation::open(&gif(temp.path(), &[0, 10, 40, 200])).unwrap().unwrap();
+        assert_eq!(anim.frames.len(), 4);
+        assert_eq!(anim.delays, [0.1, 0.1, 0.04, 0.2]);
+        assert_eq!(anim.natural_fps(), 25);
+
+        let anim = Animation::open(&gif(temp.path(), &[0, 0, 0])).unwrap().unwrap();
+        assert_eq!(anim.natural_fps(), 10);
+        assert_eq!(anim.resample(10), [0, 1, 2]);
+    }
+
+    #[test]
+    fn still_pictures_are_a_single_frame() {
+        let temp = TempDir::new().unwrap();
+        let path = temp.path().join("still.png");
+        RgbaImage::new(6, 3).save_with_format(&path, Format::Png).unwrap();
+
+        let anim = Animation::open(&path).unwrap().unwrap();
+        assert_eq!((anim.width, anim.height, anim.frames.len()), (6, 3, 1));
+        assert_eq!(anim.natural_fps(), 10);
+        assert_eq!(anim.resample(30), [0]);
+        assert_eq!(animation(&[2.5]).resample(60), [0]);
+    }
+}
@@ -0,0 +1,343 @@
+//! Reading and writing bootanimation.zip archives.
+
+use crate::desc::Desc;
+use anyhow::{Context, Result};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use zip::write::FileOptions;
+use zip::{CompressionMethod, DateTime, ZipArchive, ZipWriter};
+
+/// Extensions Android accepts for animation frames
+pub const FRAME_EXTENSIONS: [&str; 3] = ["png", "jpg", "jpeg"];
+
+pub fn is_frame_file(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| FRAME_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Frames of a part directory, sorted by file name
+pub fn list_frames(part_dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut frames: Vec<PathBuf> = fs::read_dir(part_dir)
+        .with_context(|| format!("Failed to read {}", part_dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_frame_file(path))
+        .collect();
+
+    frames.sort();
+    Ok(frames)
+}
+
+/// Last contiguous run of digits in the file stem, 0 if there is none
+pub fn extract_last_number(path: &Path) -> u32 {
+    let filename = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
+
+    // find the last contiguous sequence of digits
+    let mut last_num = String::new();
+    let mut current_num = String::new();
+
+    for ch in filename.chars() {
+        if ch.is_ascii_digit() {
+            current_num.push(ch);
+        } else if !current_num.is_empty() {
+            last_num = current_num.clone();
+            current_num.clear();
+        }
+    }
+
+    // if we ended with digits, that's our number
+    if !current_num.is_empty() {
+        last_num = current_num;
+    }
+
+    // parse the numeric part, default to 0 if no digits found
+    last_num.parse().unwrap_or(0)
+}
+
+/// Sorts frames by their trailing number so `frame2` comes before `frame10`
+pub fn sort_frames_naturally(frames: &mut [PathBuf]) {
+    frames.sort_by(|a, b| {
+        extract_last_number(a)
+            .cmp(&extract_last_number(b))
+            .then_with(|| a.file_name().cmp(&b.file_name()))
+    });
+}
+
+/// desc.txt of a bootanimation.zip and the frame count of each part directory, without extracting
+///
+/// Counts follow `Desc::part_dirs` order; directories missing from the zip count 0 frames.
+pub fn read_part_frames(zip_path: &Path) -> Result<(Desc, Vec<(String, u32)>)> {
+    let file = File::open(zip_path)
+        .with_context(|| format!("Failed to open {}", zip_path.display()))?;
+    let mut archive = ZipArchive::new(file)?;
+
+    let desc = {
+        let mut entry = archive
+            .by_name("desc.txt")
+            .context("desc.txt not found in bootanimation.zip")?;
+        let mut content = String::new();
+        entry.read_to_string(&mut content)?;
+        Desc::parse(&content)?
+    };
+
+    let names: Vec<PathBuf> = archive.file_names().map(PathBuf::from).collect();
+    let counts = desc
+        .part_dirs()
+        .into_iter()
+        .map(|dir| {
+            let frames = names
+                .iter()
+                .filter(|name| name.parent() == Some(Path::new(dir)) && is_frame_file(name))
+                .count() as u32;
+            (dir.to_string(), frames)
+        })
+        .collect();
+
+    Ok((desc, counts))
+}
+
+pub fn extract_zip(zip_path: &Path, extract_dir: &Path) -> Result<()> {
+    fs::create_dir_all(extract_dir)?;
+    let file = File::open(zip_path)
+        .with_context(|| format!("Failed to open {}", zip_path.display()))?;
+    let mut archive = ZipArchive::new(file)?;
+
+    for i in 0..archive.len() {
+        let mut file = archive.by_index(i)?;
+        let outpath = extract_dir.join(file.mangled_name());
+
+        if file.name().ends_with('/') {
+            fs::create_dir_all(&outpath)?;
+        } else {
+            if let Some(p) = outpath.parent() {
+                fs::create_dir_all(p)?;
+            }
+            let mut outfile = File::create(&outpath)?;
+            std::io::copy(&mut file, &mut outfile)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Stored (uncompressed) entry options with a fixed timestamp and permissions
+///
+/// The timestamp comes from `SOURCE_DATE_EPOCH` when set, otherwise it is the
+/// zip epoch (1980-01-01), so the same input always gives the same archive.
+pub fn entry_options() -> FileOptions<'static, ()> {
+    // use 0 compression
+    FileOptions::<()>::default()
+        .compression_method(CompressionMethod::Stored)
+        .last_modified_time(source_date())
+        .unix_permissions(0o644)
+}
+
+fn source_date() -> DateTime {
+    env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse::<i64>().ok())
+        .map_or_else(DateTime::default, zip_date)
+}
+
+/// Zip timestamp of `epoch` seconds since 1970-01-01 UTC
+fn zip_date(epoch: i64) -> DateTime {
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let days = epoch.div_euclid(86400);
+    let secs = epoch.rem_euclid(86400);
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    // zip timestamps only cover 1980-2107 with two second precision
+    if year < 1980 {
+        return DateTime::default();
+    }
+    DateTime::from_date_and_time(
+        year.min(2107) as u16,
+        month as u8,
+        day as u8,
+        (secs / 3600) as u8,
+        (secs % 3600 / 60) as u8,
+        (secs % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// Writes stored entries to a bootanimation.zip one at a time
+pub struct BootanimationWriter {
+    zip: ZipWriter<BufWriter<File>>,
+    options: FileOptions<'static, ()>,
+    path: PathBuf,
+}
+
+impl BootanimationWriter {
+    pub fn create(output_path: &Path) -> Result<Self> {
+        let file = File::create(output_path).context("Failed to create output zip file")?;
+        Ok(BootanimationWriter {
+            zip: ZipWriter::new(BufWriter::new(file)),
+            options: entry_options(),
+            path: output_path.to_path_buf(),
+        })
+    }
+
+    pub fn add(&mut self, name: &str, content: &[u8]) -> Result<()> {
+        self.zip.start_file(name, self.options)?;
+        self.zip.write_all(content)?;
+        Ok(())
+    }
+
+    pub fn add_file(&mut self, name: &str, path: &Path) -> Result<()> {
+        let content =
+            fs::read(path).with_context(|| format!("Failed to read {}", path.display()))?;
+        self.add(name, &content)
+    }
+
+    pub fn finish(self) -> Result<()> {
+        self.zip.finish()?.flush()?;
+        Ok(())
+    }
+
+    /// Finishes an archive written in any order, then rewrites it in the order of
+    /// [`create_bootanimation_zip`]: desc.txt first, then the parts in desc.txt order
+    ///
+    /// Entries are copied as they are, so the result only depends on their contents.
+    pub fn finish_in_canonical_order(self) -> Result<()> {
+        let path = self.path.clone();
+        self.finish()?;
+
+        let mut source = ZipArchive::new(File::open(&path)?)?;
+        let part_dirs: Vec<String> = match source.by_name("desc.txt") {
+            Ok(mut entry) => {
+                let mut content = String::new();
+                entry.read_to_string(&mut content)?;
+                Desc::parse(&content)?
+                    .part_dirs()
+                    .iter()
+                    .map(|dir| dir.to_string())
+                    .collect()
+            }
+            Err(_) => Vec::new(),
+        };
+
+        let mut names = Vec::new();
+        for i in 0..source.len() {
+            names.push(source.by_index(i)?.name().to_string());
+        }
+        names.sort_by_cached_key(|name| {
+            let (dir, _) = name.split_once('/').unwrap_or(("", name));
+            let rank = match part_dirs.iter().position(|part| part == dir) {
+                _ if name == "desc.txt" => (0, 0),
+                Some(idx) => (1, idx),
+                None => (2, 0),
+            };
+            (rank, name.clone())
+        });
+
+        let dir = path.parent().filter(|p| !p.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let staged = tempfile::NamedTempFile::new_in(dir)?;
+        let mut zip = ZipWriter::new(BufWriter::new(staged.reopen()?));
+        for name in &names {
+            zip.raw_copy_file(source.by_name(name)?)?;
+        }
+        zip.finish()?.flush()?;
+        staged
+            .persist(&path)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        Ok(())
+    }
+}
+
+/// Writes `result_dir` as a bootanimation.zip
+///
+/// desc.txt comes first, then the part directories in desc.txt order (any other
+/// directories after them, by name), each with its files sorted by name.
+pub fn create_bootanimation_zip(result_dir: &Path, output_path: &Path) -> Result<()> {
+    println!("Creating bootanimation.zip...");
+
+    let mut zip = BootanimationWriter::create(output_path)?;
+
+    // add desc.txt
+    let desc_content = fs::read(result_dir.join("desc.txt"))?;
+    zip.add("desc.txt", &desc_content)?;
+
+    let mut part_names: Vec<String> = Desc::parse(&String::from_utf8_lossy(&desc_content))
+        .map(|desc| desc.part_dirs().iter().map(|d| d.to_string()).collect())
+        .unwrap_or_default();
+
+    let mut others: Vec<String> = fs::read_dir(result_dir)?
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| !part_names.contains(name))
+        .collect();
+    others.sort();
+    part_names.extend(others);
+
+    // walk through all part directories
+    for part_name in &part_names {
+        let path = result_dir.join(part_name);
+        if !path.is_dir() {
+            continue;
+        }
+
+        let mut files: Vec<PathBuf> = fs::read_dir(&path)?
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+            .filter(|p| p.is_file())
+            .collect();
+        files.sort();
+
+        // add all files in this part directory
+        for file_path in files {
+            let file_name = file_path.file_name().unwrap().to_str().unwrap();
+            zip.add_file(&format!("{}/{}", part_name, file_name), &file_path)?;
+        }
+    }
+
+    zip.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(epoch: i64) -> (u16, u8, u8, u8, u8, u8) {
+        let date = zip_date(epoch);
+        (
+            date.year(),
+            date.month(),
+            date.day(),
+            date.hour(),
+            date.minute(),
+            date.second(),
+        )
+    }
+
+    #[test]
+    fn zip_dates_follow_the_civil_calendar() {
+        // the zip epoch itself
+        assert_eq!(date(315_532_800), (1980, 1, 1, 0, 0, 0));
+        // leap days, including the 400 year rule of 2000
+        assert_eq!(date(951_782_400), (2000, 2, 29, 0, 0, 0));
+        assert_eq!(date(1_709_210_096), (2024, 2, 29, 12, 34, 56));
+        // two second precision
+        assert_eq!(date(1_709_251_199), (2024, 2, 29, 23, 59, 58));
+        assert_eq!(date(1_709_251_200), (2024, 3, 1, 0, 0, 0));
+    }
+
+    #[test]
+    fn zip_dates_before_1980_are_clamped() {
+        assert_eq!(date(0), date(315_532_800));
+        assert_eq!(date(-86_400), date(315_532_800));
+        assert_eq!(date(315_532_799), date(315_532_800));
+    }
+}
@@ -1,64 +1,219 @@
-use clap::Parser;
-use std::env;
+use anyhow::{Context, Result, bail};
+use bootanimation_tools::archive::{extract_zip, list_frames, sort_frames_naturally};
+use bootanimation_tools::desc::{Desc, Header, Part, PartKind};
+use bootanimation_tools::jobs::run_parallel;
+use bootanimation_tools::media::{
+    AudioClip, EncodeRequest, EncoderOptions, FfmpegCli, MediaBackend, VideoFormat,
+};
+use bootanimation_tools::sheet::{self, SheetRow};
+use clap::{Parser, ValueEnum};
 use std::fs;
-use std::io::Write;
 use std::path::{Path, PathBuf};
-use std::process::Command;
 use tempfile::TempDir;
-use zip::ZipArchive;
 
 #[derive(Parser)]
 #[command(name = "bootanimation-converter")]
 #[command(version = "1.0")]
-#[command(about = "Converts Android bootanimation.zip to MP4 video", long_about = None)]
+#[command(about = "Converts Android bootanimation.zip to video", long_about = None)]
 struct Cli {
     /// Input bootanimation.zip file
     #[arg(short, long)]
     input: PathBuf,
 
-    /// Output MP4 file
-    #[arg(short, long)]
-    output: PathBuf,
+    /// Output video file, or folder for a PNG sequence (optional with --contact-sheet or --thumbnail)
+    #[arg(short, long, required_unless_present_any = ["contact_sheet", "thumbnail"])]
+    output: Option<PathBuf>,
+
+    /// Output format (optional, guessed from the output extension, MP4 by default)
+    #[arg(short, long, value_enum)]
+    format: Option<OutputFormat>,
 
     /// Include audio from bootanimation if available
     #[arg(long)]
     with_audio: bool,
+
+    /// Plays of the first part that loops until boot (count 0) before boot completes
+    #[arg(long, default_value = "1")]
+    loops: u32,
+
+    /// Frames decoded in parallel for previews and PNG sequences (0 = one per CPU core)
+    #[arg(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// Video encoder, e.g. libx265 (optional, depends on the format if not specified)
+    #[arg(long)]
+    codec: Option<String>,
+
+    /// Constant rate factor, lower is better quality (optional, encoder default if not specified)
+    #[arg(long)]
+    crf: Option<u32>,
+
+    /// Encoder preset, e.g. veryfast or slow
+    #[arg(long)]
+    preset: Option<String>,
+
+    /// Output pixel format, e.g. yuv444p (optional, depends on the format if not specified)
+    #[arg(long)]
+    pix_fmt: Option<String>,
+
+    /// Output size as a factor of the desc.txt resolution (e.g. 0.5) or WIDTHxHEIGHT
+    #[arg(long)]
+    scale: Option<String>,
+
+    /// Write a contact sheet image with evenly spaced frames of every part
+    #[arg(long)]
+    contact_sheet: Option<PathBuf>,
+
+    /// Frames per part on the contact sheet
+    #[arg(long, default_value = "6", requires = "contact_sheet")]
+    sheet_columns: u32,
+
+    /// Width of a contact sheet tile in pixels
+    #[arg(long, default_value = "200", requires = "contact_sheet")]
+    sheet_tile_width: u32,
+
+    /// Write a thumbnail of a representative frame
+    #[arg(long)]
+    thumbnail: Option<PathBuf>,
+
+    /// Longest side of the thumbnail in pixels
+    #[arg(long, default_value = "320", requires = "thumbnail")]
+    thumbnail_size: u32,
+
+    /// Extra FFmpeg output arguments, given after `--`
+    #[arg(last = true)]
+    ffmpeg_args: Vec<String>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum OutputFormat {
+    /// H.264 MP4
+    Mp4,
+    /// VP9 WebM
+    Webm,
+    /// AV1 WebM
+    WebmAv1,
+    /// Animated GIF
+    Gif,
+    /// Animated PNG
+    Apng,
+    /// Animated WebP
+    Webp,
+    /// Lossless FFV1 in Matroska, for archiving
+    Mkv,
+    /// Numbered PNG frames in a folder
+    PngSequence,
+}
+
+impl OutputFormat {
+    /// Format matching the extension of `output`, a PNG sequence for folders
+    ///
+    /// Paths without an extension and extensions of other containers (e.g. `.mov`)
+    /// get H.264 like an MP4, FFmpeg picks the container from the extension.
+    fn from_output(output: &Path) -> Result<Self> {
+        let path = output.to_string_lossy();
+        if output.is_dir() || path.ends_with('/') || path.ends_with(std::path::MAIN_SEPARATOR) {
+            return Ok(OutputFormat::PngSequence);
+        }
+        let Some(ext) = output.extension() else {
+            return Ok(OutputFormat::Mp4);
+        };
+        let ext = ext.to_string_lossy().to_lowercase();
+        Ok(match ext.as_str() {
+            "webm" => OutputFormat::Webm,
+            "gif" => OutputFormat::Gif,
+            "apng" => OutputFormat::Apng,
+            "webp" => OutputFormat::Webp,
+            "mkv" => OutputFormat::Mkv,
+            "png" => bail!(
+                "{} would be a folder of PNG frames, use --format png-sequence for that or .apng for an animated PNG",
+                output.display()
+            ),
+            _ => OutputFormat::Mp4,
+        })
+    }
+
+    /// Encoding done by the media backend, `None` for PNG sequences
+    fn video(self) -> Option<VideoFormat> {
+        match self {
+            OutputFormat::Mp4 => Some(VideoFormat::Mp4),
+            OutputFormat::Webm => Some(VideoFormat::WebmVp9),
+            OutputFormat::WebmAv1 => Some(VideoFormat::WebmAv1),
+            OutputFormat::Gif => Some(VideoFormat::Gif),
+            OutputFormat::Apng => Some(VideoFormat::Apng),
+            OutputFormat::Webp => Some(VideoFormat::Webp),
+            OutputFormat::Mkv => Some(VideoFormat::Mkv),
+            OutputFormat::PngSequence => None,
+        }
+    }
 }
 
 struct Config {
     zip_path: PathBuf,
-    output_path: PathBuf,
+    output_path: Option<PathBuf>,
+    format: OutputFormat,
     with_audio: bool,
+    loops: u32,
+    jobs: usize,
+    encoder: EncoderOptions,
+    scale: Option<String>,
+    previews: Previews,
 }
 
-struct BootAnimDesc {
-    width: u32,
-    height: u32,
-    fps: u32,
+struct Previews {
+    contact_sheet: Option<PathBuf>,
+    sheet_columns: u32,
+    sheet_tile_width: u32,
+    thumbnail: Option<PathBuf>,
+    thumbnail_size: u32,
 }
 
 struct PartInfo {
-    path: PathBuf,
+    name: String,
+    /// Frames of one play in order, none for `$SYSTEM`
+    frames: Vec<PathBuf>,
     audio_path: Option<PathBuf>,
+    /// Collected frames of all its plays and pauses
     frame_count: u32,
 }
 
-fn main() {
+fn main() -> Result<()> {
     let cli = Cli::parse();
 
+    let format = match (cli.format, cli.output.as_deref()) {
+        (Some(format), _) => format,
+        (None, Some(output)) => OutputFormat::from_output(output)?,
+        (None, None) => OutputFormat::Mp4,
+    };
+
     let config = Config {
+        format,
         zip_path: cli.input,
         output_path: cli.output,
         with_audio: cli.with_audio,
+        loops: cli.loops,
+        jobs: cli.jobs,
+        encoder: EncoderOptions {
+            codec: cli.codec,
+            crf: cli.crf,
+            preset: cli.preset,
+            pix_fmt: cli.pix_fmt,
+            extra_args: cli.ffmpeg_args,
+        },
+        scale: cli.scale,
+        previews: Previews {
+            contact_sheet: cli.contact_sheet,
+            sheet_columns: cli.sheet_columns,
+            sheet_tile_width: cli.sheet_tile_width,
+            thumbnail: cli.thumbnail,
+            thumbnail_size: cli.thumbnail_size,
+        },
     };
 
-    if let Err(e) = run(config) {
-        eprintln!("Error: {}", e);
-        std::process::exit(1);
-    }
+    run(config, &FfmpegCli::from_env())
 }
 
-fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+fn run(config: Config, backend: &dyn MediaBackend) -> Result<()> {
     let temp_dir = TempDir::new()?;
     let work_dir = temp_dir.path();
 
@@ -68,170 +223,315 @@ fn run(config: Config) -> Result<(), Box<dyn std::error::Error>> {
     println!("Extracting bootanimation.zip...");
     extract_zip(&config.zip_path, &extract_dir)?;
 
-    let desc = parse_desc(&extract_dir.join("desc.txt"))?;
-    let resolution = format!("{}x{}", desc.width, desc.height);
-    println!("Resolution: {}, FPS: {}", resolution, desc.fps);
+    let desc_path = extract_dir.join("desc.txt");
+    if !desc_path.exists() {
+        bail!("desc.txt not found in bootanimation.zip");
+    }
+    let desc = Desc::read(&desc_path)?;
+    let header = &desc.header;
+    println!("Resolution: {}x{}, FPS: {}", header.width, header.height, header.fps);
+
+    if config.previews.contact_sheet.is_some() || config.previews.thumbnail.is_some() {
+        write_previews(&config.previews, &desc, &extract_dir, config.jobs)?;
+    }
+    let Some(output_path) = &config.output_path else {
+        return Ok(());
+    };
 
     fs::create_dir_all(&frames_dir)?;
 
-    // collect all part directories
-    let part_infos = collect_parts(&extract_dir)?;
-
-    if part_infos.is_empty() {
-        return Err("No valid parts found in bootanimation".into());
-    }
+    let Some(first_part) = desc.parts.iter().find(|p| !p.is_system()) else {
+        bail!("No valid parts found in bootanimation");
+    };
 
     // detect frame extension
-    let extension = detect_frame_extension(&part_infos[0].path)?;
+    let extension = detect_frame_extension(&extract_dir.join(&first_part.path))?;
     println!("Detected frame format: {}", extension.to_uppercase());
 
+    let part_infos = collect_parts(&desc, &extract_dir, &extension)?;
+
+    // parts play like on the device, the same timeline `bootanim audio` uses
+    let frame_counts: Vec<u32> = part_infos.iter().map(|p| p.frames.len() as u32).collect();
+    let timeline = desc.timeline(&frame_counts, config.loops);
+
     // copy ALL frames into one directory with sequential numbering
     println!("Collecting and renaming all frames...");
     let mut frame_counter = 1;
     let mut updated_parts = Vec::new();
 
-    for part in part_infos {
+    for ((part, info), play) in desc.parts.iter().zip(part_infos).zip(&timeline.parts) {
+        let Some(play) = play else {
+            println!("  {} skipped, boot has completed", info.name);
+            continue;
+        };
         let start_frame = frame_counter;
-        frame_counter = copy_frames_sequential(&part.path, &frames_dir, &extension, frame_counter)?;
-        let end_frame = frame_counter - 1;
-        let frame_count = end_frame - start_frame + 1;
+        frame_counter = copy_frames_sequential(
+            &info.frames,
+            play.plays,
+            part.pause,
+            &frames_dir,
+            &extension,
+            frame_counter,
+        )?;
+        let frame_count = frame_counter - start_frame;
 
-        println!("  {} frames from {}", frame_count, part.path.display());
+        println!(
+            "  {} frames from {} ({} {})",
+            frame_count,
+            info.name,
+            play.plays,
+            if play.plays == 1 { "play" } else { "plays" }
+        );
 
         updated_parts.push(PartInfo {
-            path: part.path.clone(),
-            audio_path: part.audio_path.clone(),
             frame_count,
+            ..info
         });
     }
 
     let total_frames = frame_counter - 1;
     println!("Total frames collected: {}", total_frames);
 
+    let encoder = &config.encoder;
+    let Some(format) = config.format.video() else {
+        if config.with_audio {
+            eprintln!("Warning: PNG sequences have no audio, --with-audio is ignored");
+        }
+        if config.scale.is_some()
+            || encoder.codec.is_some()
+            || encoder.crf.is_some()
+            || encoder.preset.is_some()
+            || encoder.pix_fmt.is_some()
+            || !encoder.extra_args.is_empty()
+        {
+            eprintln!("Warning: PNG sequences keep the frames as they are, encoder options are ignored");
+        }
+        export_png_sequence(&frames_dir, total_frames, &extension, output_path, config.jobs)?;
+        println!(
+            "PNG sequence successfully written to {}",
+            output_path.display()
+        );
+        return Ok(());
+    };
+
+    if encoder.crf.is_some() && encoder.codec.is_none() && !format.has_crf() {
+        eprintln!(
+            "Warning: the {} encoder has no --crf",
+            config.format.to_possible_value().unwrap().get_name()
+        );
+    }
+
+    let (width, height) = match &config.scale {
+        Some(scale) => scaled_size(scale, header.width, header.height)?,
+        None => (header.width, header.height),
+    };
+    if (width, height) != (header.width, header.height) {
+        println!("Output size: {}x{}", width, height);
+    }
+    let output = Header {
+        width,
+        height,
+        ..header.clone()
+    };
+
     // check if we have audio
-    let has_audio = config.with_audio && updated_parts.iter().any(|p| p.audio_path.is_some());
+    if config.with_audio && !format.has_audio() {
+        eprintln!(
+            "Warning: {} output has no audio track, --with-audio is ignored",
+            config.format.to_possible_value().unwrap().get_name()
+        );
+    }
+    let has_audio = config.with_audio
+        && format.has_audio()
+        && updated_parts.iter().any(|p| p.audio_path.is_some());
 
-    if has_audio {
-        println!("Processing with audio...");
-        process_with_audio(
-            &frames_dir,
-            &updated_parts,
-            &config.output_path,
-            &work_dir,
-            &resolution,
-            desc.fps,
-            &extension,
-        )?;
+    if config.with_audio && !has_audio {
+        println!("No audio found, processing without audio...");
+    }
+
+    // the whole timeline is one encode, audio is mixed in afterwards
+    println!("Encoding {} frames...", total_frames);
+    let video = if has_audio {
+        work_dir.join(format!("video.{}", format.extension()))
     } else {
-        if config.with_audio {
-            println!("No audio found, processing without audio...");
-        } else {
-            println!("Generating video without audio...");
-        }
-        generate_video_no_audio(
-            &frames_dir,
-            &config.output_path,
-            &resolution,
-            desc.fps,
-            &extension,
-        )?;
+        output_path.clone()
+    };
+    backend.encode_video(&EncodeRequest {
+        frames_dir: &frames_dir,
+        extension: &extension,
+        start_frame: 1,
+        frame_count: None,
+        fps: output.fps,
+        width: output.width,
+        height: output.height,
+        format,
+        encoder,
+        output: &video,
+    })?;
+
+    if has_audio {
+        mix_part_audio(backend, &video, &updated_parts, output.fps, format, output_path)?;
     }
 
     println!(
         "Video successfully generated at {}",
-        config.output_path.display()
+        output_path.display()
     );
 
     Ok(())
 }
 
-fn extract_zip(zip_path: &Path, extract_dir: &Path) -> Result<(), Box<dyn std::error::Error>> {
-    fs::create_dir_all(extract_dir)?;
-    let file = fs::File::open(zip_path)?;
-    let mut archive = ZipArchive::new(file)?;
-
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let outpath = extract_dir.join(file.mangled_name());
-
-        if file.name().ends_with('/') {
-            fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                fs::create_dir_all(p)?;
-            }
-            let mut outfile = fs::File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
+/// Renders the contact sheet and thumbnail straight from the extracted frames
+fn write_previews(
+    previews: &Previews,
+    desc: &Desc,
+    extract_dir: &Path,
+    jobs: usize,
+) -> Result<()> {
+    // frames of every part in desc order, none for `$SYSTEM`
+    let mut part_frames = Vec::new();
+    for part in &desc.parts {
+        let mut frames = Vec::new();
+        if !part.is_system() {
+            frames = list_frames(&extract_dir.join(&part.path))?;
+            sort_frames_naturally(&mut frames);
         }
+        part_frames.push(frames);
+    }
+    let rows: Vec<(&Part, &Vec<PathBuf>)> = desc
+        .parts
+        .iter()
+        .zip(&part_frames)
+        .filter(|(_, frames)| !frames.is_empty())
+        .collect();
+    if rows.is_empty() {
+        bail!("No frames to preview in bootanimation");
     }
-    Ok(())
-}
 
-fn parse_desc(desc_path: &Path) -> Result<BootAnimDesc, Box<dyn std::error::Error>> {
-    let content = fs::read_to_string(desc_path)?;
+    if let Some(path) = &previews.contact_sheet {
+        println!("Rendering contact sheet...");
+        let sheet_rows: Vec<SheetRow> = rows
+            .iter()
+            .map(|(part, frames)| SheetRow {
+                label: part_label(part, frames.len()),
+                frames: frames.to_vec(),
+            })
+            .collect();
+        let header = &desc.header;
+        sheet::contact_sheet(
+            &sheet_rows,
+            previews.sheet_columns,
+            previews.sheet_tile_width,
+            (header.width, header.height),
+            jobs,
+        )?
+        .save(path)?;
+        println!("Contact sheet written to {}", path.display());
+    }
 
-    for line in content.lines() {
-        let trimmed = line.trim();
-        if trimmed.is_empty() || trimmed.starts_with('#') {
-            continue;
-        }
+    if let Some(path) = &previews.thumbnail {
+        // the part that keeps looping until boot completes is what people see the longest
+        let counts: Vec<u32> = part_frames.iter().map(|frames| frames.len() as u32).collect();
+        let boot_part = desc
+            .timeline(&counts, 1)
+            .boot_part
+            .filter(|&idx| !part_frames[idx].is_empty());
+        let chosen = boot_part
+            .map(|idx| (&desc.parts[idx], &part_frames[idx]))
+            .or_else(|| rows.iter().copied().max_by_key(|(_, frames)| frames.len()));
+        let Some((part, frames)) = chosen else {
+            bail!("No frames to pick a thumbnail from");
+        };
+        let idx = sheet::representative_frame(frames, jobs)?;
+        sheet::thumbnail(&frames[idx], previews.thumbnail_size)?.save(path)?;
+        println!(
+            "Thumbnail of {} frame {} written to {}",
+            part.path,
+            idx + 1,
+            path.display()
+        );
+    }
 
-        let parts: Vec<&str> = trimmed.split_whitespace().collect();
+    Ok(())
+}
 
-        if parts.is_empty() {
-            continue;
-        }
+/// Contact sheet heading: part name, desc.txt line values and how the part plays
+fn part_label(part: &Part, frame_count: usize) -> String {
+    let plays = match part.count {
+        0 => "loops until boot".to_string(),
+        1 => "plays once".to_string(),
+        n => format!("plays {} times", n),
+    };
+    let kind = match part.kind {
+        PartKind::Interruptible => "interruptible",
+        PartKind::Complete => "completes",
+        PartKind::Fade => "fades out",
+    };
+    format!(
+        "{}  {} {} {}  {} frames, {}, {}",
+        part.path,
+        part.kind.as_char(),
+        part.count,
+        part.pause,
+        frame_count,
+        plays,
+        kind
+    )
+}
 
-        if parts[0] == "g" && parts.len() >= 6 {
-            // global format: g width height offsetx offsety fps
-            return Ok(BootAnimDesc {
-                width: parts[1].parse()?,
-                height: parts[2].parse()?,
-                fps: parts[5].parse()?,
-            });
-        } else if parts.len() >= 3 {
-            // original format: width height fps
-            return Ok(BootAnimDesc {
-                width: parts[0].parse()?,
-                height: parts[1].parse()?,
-                fps: parts[2].parse()?,
-            });
+/// `--scale` as a factor (`0.5`) or `WIDTHxHEIGHT`, factors give even sizes for YUV encoders
+fn scaled_size(scale: &str, width: u32, height: u32) -> Result<(u32, u32)> {
+    if let Some((w, h)) = scale.split_once(['x', 'X']) {
+        let size = (w.trim().parse::<u32>()?, h.trim().parse::<u32>()?);
+        if size.0 == 0 || size.1 == 0 {
+            bail!("Invalid --scale size: {}", scale);
         }
+        return Ok(size);
     }
 
-    Err("Unable to parse desc.txt".into())
+    let factor: f64 = scale
+        .parse()
+        .with_context(|| format!("Invalid --scale: {} (expected a factor like 0.5 or WIDTHxHEIGHT)", scale))?;
+    if factor <= 0.0 {
+        bail!("Invalid --scale factor: {}", scale);
+    }
+    let even = |value: u32| (((value as f64 * factor / 2.0).round() as u32) * 2).max(2);
+    Ok((even(width), even(height)))
 }
 
-fn collect_parts(extract_dir: &Path) -> Result<Vec<PartInfo>, Box<dyn std::error::Error>> {
+/// Every part in desc.txt order with its `extension` frames, `$SYSTEM` parts have none
+fn collect_parts(desc: &Desc, extract_dir: &Path, extension: &str) -> Result<Vec<PartInfo>> {
     let mut parts = Vec::new();
-
-    if let Ok(entries) = fs::read_dir(extract_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if path.is_dir() {
-                let audio_path = path.join("audio.wav");
-                let audio = if audio_path.exists() {
-                    Some(audio_path)
-                } else {
-                    None
-                };
-
-                parts.push(PartInfo {
-                    path,
-                    audio_path: audio,
-                    frame_count: 0, // will be updated later
-                });
+    for part in &desc.parts {
+        let mut info = PartInfo {
+            name: part.path.clone(),
+            frames: Vec::new(),
+            audio_path: None,
+            frame_count: 0, // will be updated later
+        };
+        if !part.is_system() {
+            let path = extract_dir.join(&part.path);
+            if !path.is_dir() {
+                bail!("Part {} is listed in desc.txt but missing from the zip", part.path);
             }
+            info.frames = list_frames(&path)?
+                .into_iter()
+                .filter(|frame| {
+                    frame
+                        .extension()
+                        .is_some_and(|ext| ext.to_string_lossy().to_lowercase() == extension)
+                })
+                .collect();
+            // sort by extracting the last contiguous numeric sequence from filename
+            sort_frames_naturally(&mut info.frames);
+            info.audio_path = Some(path.join("audio.wav")).filter(|audio| audio.exists());
         }
+        parts.push(info);
     }
-
-    // sort parts by directory name
-    parts.sort_by(|a, b| a.path.file_name().cmp(&b.path.file_name()));
-
     Ok(parts)
 }
 
-fn detect_frame_extension(dir: &Path) -> Result<String, Box<dyn std::error::Error>> {
+fn detect_frame_extension(dir: &Path) -> Result<String> {
     let mut png_count = 0;
     let mut jpg_count = 0;
 
@@ -254,304 +554,290 @@ fn detect_frame_extension(dir: &Path) -> Result<String, Box<dyn std::error::Erro
     } else if jpg_count > 0 {
         Ok("jpg".to_string())
     } else {
-        Err("No valid frames (PNG or JPG) found".into())
+        bail!("No valid frames (PNG or JPG) found")
     }
 }
 
+/// Copies `plays` plays of `frames` to `dst_dir`, numbered from `start_counter`
+///
+/// Like Android, the last shown frame is held for `pause` frames after every play.
 fn copy_frames_sequential(
-    src_dir: &Path,
+    frames: &[PathBuf],
+    plays: u32,
+    pause: u32,
     dst_dir: &Path,
     extension: &str,
     start_counter: u32,
-) -> Result<u32, Box<dyn std::error::Error>> {
-    let mut frames: Vec<PathBuf> = Vec::new();
+) -> Result<u32> {
+    let frame_path = |counter: u32| dst_dir.join(format!("{:05}.{}", counter, extension));
 
-    if let Ok(entries) = fs::read_dir(src_dir) {
-        for entry in entries.flatten() {
-            let path = entry.path();
-            if let Some(ext) = path.extension() {
-                if ext.to_string_lossy().to_lowercase() == extension {
-                    frames.push(path);
-                }
+    let mut counter = start_counter;
+    for _ in 0..plays {
+        for frame in frames {
+            fs::copy(frame, frame_path(counter))?;
+            counter += 1;
+        }
+        // nothing has been shown yet to hold at the very start
+        if counter > 1 {
+            for _ in 0..pause {
+                fs::copy(frame_path(counter - 1), frame_path(counter))?;
+                counter += 1;
             }
         }
     }
 
-    // sort by extracting the last contiguous numeric sequence from filename
-    frames.sort_by(|a, b| {
-        let num_a = extract_last_number(a);
-        let num_b = extract_last_number(b);
-        num_a.cmp(&num_b)
-    });
-
-    let mut counter = start_counter;
-    for frame in frames.iter() {
-        let new_name = format!("{:05}.{}", counter, extension);
-        fs::copy(frame, dst_dir.join(new_name))?;
-        counter += 1;
-    }
-
     Ok(counter)
 }
 
-fn extract_last_number(path: &Path) -> u32 {
-    let filename = path.file_stem().and_then(|s| s.to_str()).unwrap_or("");
-
-    // find the last contiguous sequence of digits
-    let mut last_num = String::new();
-    let mut current_num = String::new();
-
-    for ch in filename.chars() {
-        if ch.is_ascii_digit() {
-            current_num.push(ch);
-        } else {
-            if !current_num.is_empty() {
-                last_num = current_num.clone();
-                current_num.clear();
-            }
+/// Mixes the audio of every part into `video`, each starting with the part's first frame
+fn mix_part_audio(
+    backend: &dyn MediaBackend,
+    video: &Path,
+    parts: &[PartInfo],
+    fps: u32,
+    format: VideoFormat,
+    output: &Path,
+) -> Result<()> {
+    // like Android, a part's audio.wav starts with its first play and plays to its end,
+    // even over the next parts; parts without audio are silent
+    let mut clips = Vec::new();
+    let mut start = 0;
+    for part in parts {
+        if let Some(path) = part.audio_path.as_deref() {
+            clips.push(AudioClip {
+                path,
+                start: start as f64 / fps as f64,
+            });
         }
+        start += part.frame_count;
     }
+    let duration = start as f64 / fps as f64;
 
-    // if we ended with digits, that's our number
-    if !current_num.is_empty() {
-        last_num = current_num;
-    }
-
-    // parse the numeric part, default to 0 if no digits found
-    last_num.parse().unwrap_or(0)
+    println!(
+        "Mixing audio of {} {}...",
+        clips.len(),
+        if clips.len() == 1 { "part" } else { "parts" }
+    );
+    backend.add_audio(video, &clips, duration, format, output)
 }
 
-fn process_with_audio(
+/// Writes the collected frames to `output` as `00001.png`, `00002.png`...
+///
+/// PNG frames are copied as they are, JPEG frames are converted in-process.
+fn export_png_sequence(
     frames_dir: &Path,
-    parts: &[PartInfo],
-    output: &Path,
-    work_dir: &Path,
-    resolution: &str,
-    fps: u32,
+    total_frames: u32,
     extension: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let mut part_videos = Vec::new();
-    let mut current_frame = 1;
-
-    for (idx, part) in parts.iter().enumerate() {
-        if let Some(audio_path) = &part.audio_path {
-            println!(
-                "Processing part {} with audio ({} frames)",
-                idx, part.frame_count
-            );
-
-            let start_frame = current_frame;
-            let end_frame = current_frame + part.frame_count - 1;
-
-            let part_video = work_dir.join(format!("part{}.mp4", idx));
-            generate_video_segment(
-                frames_dir,
-                audio_path,
-                &part_video,
-                resolution,
-                fps,
-                extension,
-                start_frame,
-                end_frame,
-            )?;
-
-            part_videos.push(part_video);
-            current_frame = end_frame + 1;
+    output: &Path,
+    jobs: usize,
+) -> Result<()> {
+    if output.exists() && (!output.is_dir() || fs::read_dir(output)?.next().is_some()) {
+        bail!("Output folder is not empty: {}", output.display());
+    }
+    fs::create_dir_all(output)?;
+
+    println!("Writing {} PNG frames...", total_frames);
+    let frames: Vec<u32> = (1..=total_frames).collect();
+    let results = run_parallel(&frames, jobs, |_, &number| -> Result<()> {
+        let source = frames_dir.join(format!("{:05}.{}", number, extension));
+        let target = output.join(format!("{:05}.png", number));
+        if extension == "png" {
+            fs::copy(&source, &target)?;
         } else {
-            println!(
-                "Processing part {} without audio ({} frames)",
-                idx, part.frame_count
-            );
-
-            let start_frame = current_frame;
-            let end_frame = current_frame + part.frame_count - 1;
-
-            let part_video = work_dir.join(format!("part{}.mp4", idx));
-            generate_video_segment_no_audio(
-                frames_dir,
-                &part_video,
-                resolution,
-                fps,
-                extension,
-                start_frame,
-                end_frame,
-            )?;
-
-            part_videos.push(part_video);
-            current_frame = end_frame + 1;
+            image::open(&source)
+                .and_then(|image| image.save_with_format(&target, image::ImageFormat::Png))
+                .with_context(|| format!("Failed to convert {}", source.display()))?;
         }
-    }
+        Ok(())
+    });
 
-    if part_videos.len() > 1 {
-        println!("Merging {} video parts...", part_videos.len());
-        merge_videos(&part_videos, output, work_dir)?;
-    } else if part_videos.len() == 1 {
-        fs::copy(&part_videos[0], output)?;
+    for result in results {
+        result?;
     }
-
     Ok(())
 }
 
-fn generate_video_segment(
-    frames_dir: &Path,
-    audio_path: &Path,
-    output: &Path,
-    resolution: &str,
-    fps: u32,
-    extension: &str,
-    start_frame: u32,
-    end_frame: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ffmpeg = get_ffmpeg_path();
-    let pattern = frames_dir.join(format!("%05d.{}", extension));
-
-    let status = Command::new(&ffmpeg)
-        .args(&[
-            "-hide_banner",
-            "-y",
-            "-start_number",
-            &start_frame.to_string(),
-            "-framerate",
-            &fps.to_string(),
-            "-i",
-            pattern.to_str().unwrap(),
-            "-i",
-            audio_path.to_str().unwrap(),
-            "-frames:v",
-            &(end_frame - start_frame + 1).to_string(),
-            "-shortest",
-            "-c:v",
-            "libx264",
-            "-pix_fmt",
-            "yuv420p",
-            "-s",
-            resolution,
-            "-c:a",
-            "aac",
-            output.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !status.success() {
-        return Err("FFmpeg failed to generate video segment".into());
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootanimation_tools::archive::BootanimationWriter;
+    use bootanimation_tools::media::FakeBackend;
+
+    /// Writes a bootanimation.zip with `desc` and parts of (name, frames, has audio)
+    fn write_bootanimation(path: &Path, desc: &str, parts: &[(&str, u32, bool)]) {
+        let mut zip = BootanimationWriter::create(path).unwrap();
+        zip.add("desc.txt", desc.as_bytes()).unwrap();
+        for &(name, frames, audio) in parts {
+            for idx in 0..frames {
+                zip.add(&format!("{}/{:03}.png", name, idx), b"frame").unwrap();
+            }
+            if audio {
+                zip.add(&format!("{}/audio.wav", name), b"audio").unwrap();
+            }
+        }
+        zip.finish().unwrap();
     }
 
-    Ok(())
-}
+    fn convert(input: &Path, output: &Path, with_audio: bool) -> String {
+        convert_with_loops(input, output, with_audio, 1)
+    }
 
-fn generate_video_segment_no_audio(
-    frames_dir: &Path,
-    output: &Path,
-    resolution: &str,
-    fps: u32,
-    extension: &str,
-    start_frame: u32,
-    end_frame: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ffmpeg = get_ffmpeg_path();
-    let pattern = frames_dir.join(format!("%05d.{}", extension));
-
-    let status = Command::new(&ffmpeg)
-        .args(&[
-            "-hide_banner",
-            "-y",
-            "-start_number",
-            &start_frame.to_string(),
-            "-framerate",
-            &fps.to_string(),
-            "-i",
-            pattern.to_str().unwrap(),
-            "-frames:v",
-            &(end_frame - start_frame + 1).to_string(),
-            "-c:v",
-            "libx264",
-            "-pix_fmt",
-            "yuv420p",
-            "-s",
-            resolution,
-            output.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !status.success() {
-        return Err("FFmpeg failed to generate video segment".into());
+    fn convert_with_loops(input: &Path, output: &Path, with_audio: bool, loops: u32) -> String {
+        let config = Config {
+            zip_path: input.to_path_buf(),
+            output_path: Some(output.to_path_buf()),
+            format: OutputFormat::Mp4,
+            with_audio,
+            loops,
+            jobs: 2,
+            encoder: EncoderOptions::default(),
+            scale: None,
+            previews: Previews {
+                contact_sheet: None,
+                sheet_columns: 6,
+                sheet_tile_width: 200,
+                thumbnail: None,
+                thumbnail_size: 320,
+            },
+        };
+        run(config, &FakeBackend::new(16, 16, 10, 0, false)).unwrap();
+        fs::read_to_string(output).unwrap()
     }
 
-    Ok(())
-}
+    #[test]
+    fn output_format_follows_the_extension() {
+        let format = |path: &str| OutputFormat::from_output(Path::new(path));
+        assert_eq!(format("boot.MP4").unwrap(), OutputFormat::Mp4);
+        assert_eq!(format("boot.webm").unwrap(), OutputFormat::Webm);
+        assert_eq!(format("boot.apng").unwrap(), OutputFormat::Apng);
+        // anything else stays H.264, in the container FFmpeg picks for the extension
+        assert_eq!(format("boot").unwrap(), OutputFormat::Mp4);
+        assert_eq!(format("boot.mov").unwrap(), OutputFormat::Mp4);
+        assert_eq!(format("boot.avi").unwrap(), OutputFormat::Mp4);
+
+        // PNG sequences need a folder or an explicit --format
+        assert_eq!(format("out/frames/").unwrap(), OutputFormat::PngSequence);
+        let temp = TempDir::new().unwrap();
+        assert_eq!(OutputFormat::from_output(temp.path()).unwrap(), OutputFormat::PngSequence);
+        let err = format("preview.png").unwrap_err().to_string();
+        assert!(err.contains("--format png-sequence"), "{}", err);
+    }
 
-fn generate_video_no_audio(
-    frames_dir: &Path,
-    output: &Path,
-    resolution: &str,
-    fps: u32,
-    extension: &str,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let ffmpeg = get_ffmpeg_path();
-    let pattern = frames_dir.join(format!("%05d.{}", extension));
-
-    let status = Command::new(&ffmpeg)
-        .args(&[
-            "-hide_banner",
-            "-y",
-            "-framerate",
-            &fps.to_string(),
-            "-i",
-            pattern.to_str().unwrap(),
-            "-c:v",
-            "libx264",
-            "-pix_fmt",
-            "yuv420p",
-            "-s",
-            resolution,
-            output.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !status.success() {
-        return Err("FFmpeg failed to generate video".into());
+    #[test]
+    fn parts_are_encoded_once_and_mixed_with_their_audio() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("bootanimation.zip");
+        write_bootanimation(
+            &input,
+            "16 16 10\np 1 0 part0\nc 1 0 part1\n",
+            &[("part0", 3, true), ("part1", 2, true)],
+        );
+
+        // the same single encode with or without audio
+        assert_eq!(
+            convert(&input, &temp.path().join("silent.mp4"), false),
+            "frames 1+5 fps 10 size 16x16 format Mp4\n"
+        );
+        assert_eq!(
+            convert(&input, &temp.path().join("video.mp4"), true),
+            "frames 1+5 fps 10 size 16x16 format Mp4\n\
+             audio audio.wav at 0.000\n\
+             audio audio.wav at 0.300\n\
+             audio padded to 0.500\n"
+        );
     }
 
-    Ok(())
-}
+    #[test]
+    fn parts_play_in_desc_order() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("bootanimation.zip");
+        write_bootanimation(
+            &input,
+            "16 16 10\np 1 0 part1\np 1 0 $SYSTEM\nc 1 0 part0\n",
+            &[("part0", 3, false), ("part1", 2, true)],
+        );
+
+        assert_eq!(
+            convert(&input, &temp.path().join("video.mp4"), true),
+            "frames 1+5 fps 10 size 16x16 format Mp4\n\
+             audio audio.wav at 0.000\n\
+             audio padded to 0.500\n"
+        );
+    }
 
-fn merge_videos(
-    videos: &[PathBuf],
-    output: &Path,
-    work_dir: &Path,
-) -> Result<(), Box<dyn std::error::Error>> {
-    let concat_file = work_dir.join("concat_list.txt");
-    let mut file = fs::File::create(&concat_file)?;
-
-    for video in videos {
-        writeln!(file, "file '{}'", video.display())?;
-    }
-    drop(file);
-
-    let ffmpeg = get_ffmpeg_path();
-    let status = Command::new(&ffmpeg)
-        .args(&[
-            "-hide_banner",
-            "-y",
-            "-f",
-            "concat",
-            "-safe",
-            "0",
-            "-i",
-            concat_file.to_str().unwrap(),
-            "-c",
-            "copy",
-            output.to_str().unwrap(),
-        ])
-        .status()?;
-
-    if !status.success() {
-        return Err("FFmpeg failed to merge videos".into());
+    #[test]
+    fn video_and_audio_follow_the_boot_timeline() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("bootanimation.zip");
+        write_bootanimation(
+            &input,
+            "16 16 10\np 2 1 part0\np 0 0 part1\np 1 0 part2\nc 1 0 part3\n",
+            &[
+                ("part0", 2, true),
+                ("part1", 3, true),
+                ("part2", 2, true),
+                ("part3", 1, true),
+            ],
+        );
+
+        // part0 plays twice with a frame of pause, part1 loops twice until boot
+        // completes, then the p part is skipped and only the c part plays
+        assert_eq!(
+            convert_with_loops(&input, &temp.path().join("video.mp4"), true, 2),
+            "frames 1+13 fps 10 size 16x16 format Mp4\n\
+             audio audio.wav at 0.000\n\
+             audio audio.wav at 0.600\n\
+             audio audio.wav at 1.200\n\
+             audio padded to 1.300\n"
+        );
     }
 
-    Ok(())
-}
+    /// Thumbnail of an extracted animation whose parts are solid (name, frames, gray level)
+    fn thumbnail_level(desc: &str, parts: &[(&str, u32, u8)]) -> Result<u8> {
+        let temp = TempDir::new().unwrap();
+        let extract_dir = temp.path().join("extracted");
+        for &(name, frames, level) in parts {
+            let dir = extract_dir.join(name);
+            fs::create_dir_all(&dir).unwrap();
+            for idx in 0..frames {
+                image::RgbImage::from_pixel(8, 8, image::Rgb([level; 3]))
+                    .save(dir.join(format!("{:03}.png", idx)))
+                    .unwrap();
+            }
+        }
+        let thumbnail = temp.path().join("thumb.png");
+        let previews = Previews {
+            contact_sheet: None,
+            sheet_columns: 6,
+            sheet_tile_width: 200,
+            thumbnail: Some(thumbnail.clone()),
+            thumbnail_size: 16,
+        };
+        write_previews(&previews, &Desc::parse(desc)?, &extract_dir, 1)?;
+        Ok(image::open(&thumbnail).unwrap().to_rgb8().get_pixel(0, 0)[0])
+    }
 
-fn get_ffmpeg_path() -> String {
-    env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string())
+    #[test]
+    fn thumbnails_show_the_part_looping_until_boot() {
+        let parts = [("part0", 2, 50), ("part1", 2, 100), ("part2", 5, 150)];
+        // part2 is longer and loops too, but boot completes while part1 loops
+        assert_eq!(
+            thumbnail_level("8 8 10\np 1 0 part0\np 0 0 part1\np 0 0 part2\n", &parts).unwrap(),
+            100
+        );
+        // without a looping part, the longest one
+        assert_eq!(
+            thumbnail_level("8 8 10\np 1 0 part0\np 1 0 part1\np 1 0 part2\n", &parts).unwrap(),
+            150
+        );
+        // a looping part without frames is passed over
+        assert_eq!(
+            thumbnail_level("8 8 10\np 0 0 empty\np 1 0 part0\n", &[parts[0], ("empty", 0, 0)])
+                .unwrap(),
+            50
+        );
+
+        let err = thumbnail_level("8 8 10\np 0 0 $SYSTEM\n", &[]).unwrap_err();
+        assert!(err.to_string().contains("No frames"), "{}", err);
+    }
 }
@@ -0,0 +1,1698 @@
+use anyhow::{Context, Result, bail};
+use bootanimation_tools::archive::{
+    create_bootanimation_zip, extract_zip, is_frame_file, list_frames, read_part_frames,
+    sort_frames_naturally,
+};
+use bootanimation_tools::desc::{Desc, Part, PartKind, Trim, validate_color};
+use bootanimation_tools::frames::{FrameQuality, ImageFormat, Placement, ScaleMode};
+use bootanimation_tools::media::{AudioClip, AudioFormat, FfmpegCli, MediaBackend};
+use bootanimation_tools::memory::{
+    MemoryIssue, MemoryLimits, Severity, check_parts, megabytes, part_memory,
+};
+use clap::{Args, Parser, Subcommand};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tempfile::TempDir;
+
+#[derive(Parser)]
+#[command(name = "bootanim")]
+#[command(about = "Inspect and modify Android bootanimation.zip files", long_about = None)]
+struct Cli {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand)]
+enum Commands {
+    /// Show desc.txt settings, frame counts and decoded memory of a bootanimation.zip
+    Inspect(InspectArgs),
+    /// Export the boot sound, as one soundtrack or every part's audio.wav
+    Audio(AudioArgs),
+    /// Edit desc.txt and parts of a bootanimation.zip without re-encoding frames
+    Edit(EditArgs),
+    /// Rescale and re-encode every frame of a bootanimation.zip
+    Resize(ResizeArgs),
+    /// Join several bootanimation.zip files into one
+    Concat(ConcatArgs),
+    /// Extract a bootanimation.zip into a directory
+    Unpack(UnpackArgs),
+    /// Build a bootanimation.zip from a directory with desc.txt and part folders
+    Pack(PackArgs),
+}
+
+#[derive(Args)]
+struct InspectArgs {
+    /// Input bootanimation.zip file
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Warn when a part needs more decoded memory than this many megabytes (0 = never)
+    #[arg(long, default_value = "256")]
+    warn_part_memory: f64,
+
+    /// Fail when a part needs more decoded memory than this many megabytes
+    #[arg(long)]
+    fail_part_memory: Option<f64>,
+}
+
+#[derive(Args)]
+struct AudioArgs {
+    /// Input bootanimation.zip file
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output audio file, or folder with --per-part
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Export the audio.wav of every part on its own instead of one mixed soundtrack
+    #[arg(long)]
+    per_part: bool,
+
+    /// Audio format (optional, guessed from the output extension, WAV by default)
+    #[arg(short, long, value_enum)]
+    format: Option<AudioFormat>,
+
+    /// Plays of the first part that loops until boot (count 0) before boot completes
+    #[arg(long, default_value = "1", conflicts_with = "per_part")]
+    loops: u32,
+}
+
+#[derive(Args)]
+struct EditArgs {
+    /// Input bootanimation.zip file
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output bootanimation.zip path (edits the input in place if not specified)
+    #[arg(short, long)]
+    output: Option<PathBuf>,
+
+    /// Replace the frames of a part with the images in a directory
+    #[arg(long, value_name = "PART=DIR")]
+    replace: Vec<String>,
+
+    /// Remove a part
+    #[arg(long, value_name = "PART")]
+    remove: Vec<String>,
+
+    /// Insert a new part made of the images in a directory before the part at INDEX
+    #[arg(long, value_name = "INDEX=DIR")]
+    insert: Vec<String>,
+
+    /// New order of the remaining parts, comma separated
+    #[arg(long, value_name = "PART,...", value_delimiter = ',')]
+    order: Vec<String>,
+
+    /// Set a desc.txt field: fps, width, height, progress, offset-x, offset-y,
+    /// or PART.type, PART.count, PART.pause, PART.fade, PART.background, PART.clock
+    #[arg(short, long, value_name = "KEY=VALUE")]
+    set: Vec<String>,
+}
+
+#[derive(Args)]
+struct ResizeArgs {
+    /// Input bootanimation.zip file
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output bootanimation.zip path
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// New width (keeps aspect ratio if only the height is given)
+    #[arg(short = 'W', long, required_unless_present = "height")]
+    width: Option<u32>,
+
+    /// New height (keeps aspect ratio if only the width is given)
+    #[arg(short = 'H', long)]
+    height: Option<u32>,
+
+    /// How frames are fitted to the new resolution
+    #[arg(long, value_enum, default_value = "stretch")]
+    scale_mode: ScaleMode,
+
+    /// Convert frames to another image format
+    #[arg(long, value_enum)]
+    format: Option<ImageFormat>,
+
+    /// JPEG quality (1-100), only for JPEG frames as PNG frames stay lossless
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// Padding color for fit/pad modes in hex format (e.g., #000000)
+    #[arg(short, long)]
+    background: Option<String>,
+}
+
+#[derive(Args)]
+struct ConcatArgs {
+    /// Input bootanimation.zip files, played in the given order
+    #[arg(short, long, num_args = 1.., required = true)]
+    input: Vec<PathBuf>,
+
+    /// Output bootanimation.zip path
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Output width (optional, uses the first animation's width if not specified)
+    #[arg(short = 'W', long)]
+    width: Option<u32>,
+
+    /// Output height (optional, uses the first animation's height if not specified)
+    #[arg(short = 'H', long)]
+    height: Option<u32>,
+
+    /// Frame rate (optional, uses the first animation's fps if not specified)
+    #[arg(short, long)]
+    fps: Option<u32>,
+
+    /// How animations of another resolution are fitted
+    #[arg(long, value_enum, default_value = "fit")]
+    scale_mode: ScaleMode,
+
+    /// Padding color for fit/pad modes in hex format (e.g., #000000)
+    #[arg(short, long)]
+    background: Option<String>,
+}
+
+#[derive(Args)]
+struct UnpackArgs {
+    /// Input bootanimation.zip file
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Directory to extract into (must not exist or be empty)
+    #[arg(short, long)]
+    output: PathBuf,
+}
+
+#[derive(Args)]
+struct PackArgs {
+    /// Directory containing desc.txt and the part folders
+    #[arg(short, long)]
+    input: PathBuf,
+
+    /// Output bootanimation.zip path
+    #[arg(short, long)]
+    output: PathBuf,
+
+    /// Leave out stray files (.DS_Store, Thumbs.db, unused folders...) instead of failing
+    #[arg(long)]
+    skip_stray: bool,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    match cli.command {
+        Commands::Inspect(args) => inspect(args),
+        Commands::Audio(args) => audio(args),
+        Commands::Edit(args) => edit(args),
+        Commands::Resize(args) => resize(args),
+        Commands::Concat(args) => concat(args),
+        Commands::Unpack(args) => unpack(args),
+        Commands::Pack(args) => pack(args),
+    }
+}
+
+/// Resolves a part by directory name or desc.txt line index
+///
+/// A directory name wins over an index, `@N` always selects the part at index N.
+fn find_part(desc: &Desc, selector: &str) -> Result<usize> {
+    let index = match selector.strip_prefix('@') {
+        Some(index) => Some(
+            index
+                .parse::<usize>()
+                .with_context(|| format!("Invalid part index '{}'", selector))?,
+        ),
+        None => {
+            if let Some(index) = desc.parts.iter().position(|p| p.path == selector) {
+                return Ok(index);
+            }
+            selector.parse::<usize>().ok()
+        }
+    };
+
+    let Some(index) = index else {
+        bail!("No part named {} in desc.txt", selector);
+    };
+    if index >= desc.parts.len() {
+        bail!(
+            "Part index {} out of range (desc.txt has {} parts)",
+            index,
+            desc.parts.len()
+        );
+    }
+    Ok(index)
+}
+
+/// Removes, reorders and inserts parts, all indexes referring to the parts before the edit
+///
+/// `order` lists every part that is not removed, each new part goes right
+/// before the part at its index (or the next one left) and moves with it.
+fn rearrange_parts(
+    desc: &mut Desc,
+    removed: &[usize],
+    order: Option<&[usize]>,
+    inserted: Vec<(usize, Part)>,
+) -> Result<()> {
+    let count = desc.parts.len();
+    let kept: Vec<usize> = (0..count).filter(|i| !removed.contains(i)).collect();
+
+    let sequence = match order {
+        Some(order) => {
+            let mut sorted = order.to_vec();
+            sorted.sort();
+            if sorted != kept {
+                bail!(
+                    "--order must list every remaining part exactly once ({} parts)",
+                    kept.len()
+                );
+            }
+            order.to_vec()
+        }
+        None => kept.clone(),
+    };
+
+    let mut removed = removed.to_vec();
+    removed.sort();
+    for &index in removed.iter().rev() {
+        desc.remove_part(index);
+    }
+
+    // which input part every entry of desc.parts is, new parts have none
+    let current = |original: usize| kept.iter().position(|&k| k == original).unwrap();
+    let previous = desc.parts.clone();
+    desc.parts = sequence.iter().map(|&i| previous[current(i)].clone()).collect();
+    let mut origin: Vec<Option<usize>> = sequence.iter().map(|&i| Some(i)).collect();
+
+    for (index, part) in inserted {
+        let anchor = (index..count).find(|i| !removed.contains(i));
+        let position = match anchor {
+            Some(anchor) => origin.iter().position(|&o| o == Some(anchor)).unwrap(),
+            None => origin.len(),
+        };
+        desc.insert_part(position, part);
+        origin.insert(position, None);
+    }
+
+    Ok(())
+}
+
+fn split_assignment<'a>(arg: &'a str, what: &str) -> Result<(&'a str, &'a str)> {
+    arg.split_once('=')
+        .with_context(|| format!("Invalid argument '{}', expected {}", arg, what))
+}
+
+/// Copies images from `src_dir` into `part_dir`, renamed so lexical order matches natural order
+fn copy_frames_into_part(src_dir: &Path, part_dir: &Path) -> Result<usize> {
+    let mut frames: Vec<PathBuf> = fs::read_dir(src_dir)
+        .with_context(|| format!("Failed to read {}", src_dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_frame_file(path))
+        .collect();
+
+    if frames.is_empty() {
+        bail!("No frames (PNG or JPG) found in {}", src_dir.display());
+    }
+
+    sort_frames_naturally(&mut frames);
+
+    fs::create_dir_all(part_dir)?;
+    for (idx, frame) in frames.iter().enumerate() {
+        let ext = frame
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        fs::copy(frame, part_dir.join(format!("{:05}.{}", idx + 1, ext)))?;
+    }
+
+    // a part directory may come with its own sound
+    let audio = src_dir.join("audio.wav");
+    if audio.exists() {
+        fs::copy(&audio, part_dir.join("audio.wav"))?;
+    }
+
+    Ok(frames.len())
+}
+
+fn unused_part_name(root: &Path, desc: &Desc) -> String {
+    (0..)
+        .map(|idx| format!("part{}", idx))
+        .find(|name| !root.join(name).exists() && !desc.parts.iter().any(|p| &p.path == name))
+        .unwrap()
+}
+
+fn set_field(desc: &mut Desc, key: &str, value: &str) -> Result<()> {
+    let parse_number = |value: &str| -> Result<u32> {
+        value
+            .parse()
+            .with_context(|| format!("Invalid value '{}' for {}", value, key))
+    };
+
+    let Some((selector, field)) = key.rsplit_once('.') else {
+        let header = &mut desc.header;
+        match key {
+            "fps" => header.fps = parse_number(value)?,
+            "width" => header.width = parse_number(value)?,
+            "height" => header.height = parse_number(value)?,
+            "progress" => {
+                header.progress = match value {
+                    "none" => None,
+                    v => Some(parse_number(v)?),
+                }
+            }
+            "offset-x" => {
+                header.offset_x = parse_number(value)?;
+                header.is_global_format = true;
+            }
+            "offset-y" => {
+                header.offset_y = parse_number(value)?;
+                header.is_global_format = true;
+            }
+            _ => bail!("Unknown desc.txt field: {}", key),
+        }
+        return Ok(());
+    };
+
+    let index = find_part(desc, selector)?;
+    let part = &mut desc.parts[index];
+
+    match field {
+        "type" => {
+            part.kind = value
+                .chars()
+                .next()
+                .filter(|_| value.len() == 1)
+                .and_then(PartKind::from_char)
+                .with_context(|| format!("Invalid part type '{}', expected p, c or f", value))?;
+        }
+        "count" => part.count = parse_number(value)?,
+        "pause" => part.pause = parse_number(value)?,
+        "fade" => {
+            part.fade_frames = match value {
+                "none" => None,
+                v => Some(parse_number(v)?),
+            }
+        }
+        "background" => {
+            part.background = match value {
+                "none" => {
+                    part.clock.clear();
+                    None
+                }
+                v => Some(validate_color(v)?),
+            }
+        }
+        "clock" => {
+            let positions: Vec<String> = match value {
+                "none" => Vec::new(),
+                v => v.split_whitespace().map(|s| s.to_string()).collect(),
+            };
+            if positions.len() > 2 {
+                bail!("At most two clock positions are allowed");
+            }
+            if !positions.is_empty() && part.background.is_none() {
+                bail!("Clock positions need a background color, set {}.background first", selector);
+            }
+            part.clock = positions;
+        }
+        _ => bail!("Unknown part field: {}", field),
+    }
+
+    Ok(())
+}
+
+fn inspect(args: InspectArgs) -> Result<()> {
+    if !args.input.exists() {
+        bail!("Input bootanimation file does not exist: {}", args.input.display());
+    }
+
+    let (desc, frames) = read_part_frames(&args.input)?;
+    let header = &desc.header;
+    let part_frames = |path: &str| {
+        frames
+            .iter()
+            .find(|(name, _)| name == path)
+            .map_or(0, |&(_, count)| count)
+    };
+
+    println!("{}:", args.input.display());
+    println!("  Resolution: {}x{}", header.width, header.height);
+    println!("  FPS: {}", header.fps);
+    if header.is_global_format {
+        println!("  Format: global (with offsets x={}, y={})", header.offset_x, header.offset_y);
+    }
+    if let Some(progress) = header.progress {
+        println!("  Progress: {}", progress);
+    }
+    println!("  Decoded frame: {}", megabytes(header.frame_memory()));
+
+    println!("\nParts:");
+    for part in &desc.parts {
+        let line = format!("{} {} {} {}", part.kind.as_char(), part.count, part.pause, part.path);
+        if part.is_system() {
+            println!("  {}", line);
+            continue;
+        }
+        let count = part_frames(&part.path);
+        println!(
+            "  {}: {} frames, {:.2}s, {} decoded",
+            line,
+            count,
+            count as f64 / header.fps.max(1) as f64,
+            megabytes(part_memory(header, count))
+        );
+    }
+
+    let advice = |issue: &MemoryIssue| {
+        let (width, height) = issue.max_size;
+        match issue.max_frames {
+            0 => format!("resize it to {}x{} with bootanim resize", width, height),
+            most => format!(
+                "split it into parts of at most {} frames or resize it to {}x{} with bootanim resize",
+                most, width, height
+            ),
+        }
+    };
+
+    let limits = MemoryLimits {
+        warn: (args.warn_part_memory > 0.0).then_some(args.warn_part_memory),
+        fail: args.fail_part_memory,
+    };
+    let mut failed = Vec::new();
+    for issue in check_parts(header, &frames, limits) {
+        let message = format!("{}; {}", issue.describe(), advice(&issue));
+        match issue.severity {
+            Severity::Warning => eprintln!("Warning: {}", message),
+            Severity::Failure => failed.push(message),
+        }
+    }
+
+    if !failed.is_empty() {
+        bail!("{}", failed.join("\n"));
+    }
+    Ok(())
+}
+
+fn audio(args: AudioArgs) -> Result<()> {
+    if !args.input.exists() {
+        bail!("Input bootanimation file does not exist: {}", args.input.display());
+    }
+    let format = args
+        .format
+        .or_else(|| {
+            args.output
+                .extension()
+                .and_then(|ext| AudioFormat::from_extension(&ext.to_string_lossy()))
+        })
+        .unwrap_or(AudioFormat::Wav);
+
+    let temp_dir = TempDir::new()?;
+    let root = temp_dir.path().join("anim");
+    extract_zip(&args.input, &root)?;
+    let desc = Desc::read(&root.join("desc.txt"))?;
+    let audio_path = |dir: &str| Some(root.join(dir).join("audio.wav")).filter(|path| path.exists());
+    let backend = FfmpegCli::from_env();
+
+    if args.per_part {
+        if args.output.exists() && fs::read_dir(&args.output)?.next().is_some() {
+            bail!("Output directory is not empty: {}", args.output.display());
+        }
+        fs::create_dir_all(&args.output)?;
+
+        let mut exported = 0;
+        for dir in desc.part_dirs() {
+            let Some(source) = audio_path(dir) else {
+                continue;
+            };
+            let target = args.output.join(format!("{}.{}", dir, format.extension()));
+            println!("  {} -> {}", dir, target.display());
+            if format == AudioFormat::Wav {
+                fs::copy(&source, &target)?;
+            } else {
+                let clip = AudioClip {
+                    path: &source,
+                    start: 0.0,
+                };
+                backend.mix_audio(&[clip], 0.0, format, &target)?;
+            }
+            exported += 1;
+        }
+        if exported == 0 {
+            bail!("No part of {} has an audio.wav", args.input.display());
+        }
+
+        println!("Successfully exported {} audio files to {}", exported, args.output.display());
+        return Ok(());
+    }
+
+    // Android starts a part's audio.wav with the first play of the part
+    let mut frames = Vec::new();
+    for part in &desc.parts {
+        let part_dir = root.join(&part.path);
+        frames.push(if !part.is_system() && part_dir.is_dir() {
+            list_frames(&part_dir)?.len() as u32
+        } else {
+            0
+        });
+    }
+    let timeline = desc.timeline(&frames, args.loops);
+    let fps = desc.header.fps.max(1) as f64;
+    let seconds = |frame: u64| frame as f64 / fps;
+
+    let mut sources = Vec::new();
+    println!("Timeline:");
+    for (idx, (part, play)) in desc.parts.iter().zip(&timeline.parts).enumerate() {
+        let line = format!(
+            "{} {} {} {}",
+            part.kind.as_char(),
+            part.count,
+            part.pause,
+            part.path
+        );
+        let Some(play) = play else {
+            println!("  {:>9}  {} (skipped)", "", line);
+            continue;
+        };
+        let source = (!part.is_system()).then(|| audio_path(&part.path)).flatten();
+        println!(
+            "  {:>8.2}s  {}{}",
+            seconds(play.start),
+            line,
+            if source.is_some() { " (audio)" } else { "" }
+        );
+
+        if let Some(source) = source {
+            sources.push((source, seconds(play.start)));
+        }
+        if timeline.boot_part == Some(idx) {
+            println!(
+                "  {:>8.2}s  boot completes after {} {} of {}, only c parts play from here",
+                seconds(play.end),
+                play.plays,
+                if play.plays == 1 { "play" } else { "plays" },
+                part.path
+            );
+        }
+    }
+    let time = seconds(timeline.end);
+    println!("  {:>8.2}s  end", time);
+
+    if sources.is_empty() {
+        bail!("No part of {} has an audio.wav", args.input.display());
+    }
+    let clips: Vec<AudioClip> = sources
+        .iter()
+        .map(|(path, start)| AudioClip {
+            path,
+            start: *start,
+        })
+        .collect();
+
+    println!(
+        "Mixing audio of {} {}...",
+        clips.len(),
+        if clips.len() == 1 { "part" } else { "parts" }
+    );
+    backend.mix_audio(&clips, time, format, &args.output)?;
+
+    println!("Successfully wrote soundtrack: {}", args.output.display());
+    Ok(())
+}
+
+fn edit(args: EditArgs) -> Result<()> {
+    if !args.input.exists() {
+        bail!("Input bootanimation file does not exist: {}", args.input.display());
+    }
+
+    let temp_dir = TempDir::new()?;
+    let root = temp_dir.path().join("extracted");
+
+    println!("Extracting {}...", args.input.display());
+    extract_zip(&args.input, &root)?;
+
+    let desc_path = root.join("desc.txt");
+    if !desc_path.exists() {
+        bail!("desc.txt not found in bootanimation.zip");
+    }
+    let mut desc = Desc::read(&desc_path)?;
+
+    // every selector refers to the input desc.txt, so one change never shifts
+    // the parts another one points at
+    let replacements = args
+        .replace
+        .iter()
+        .map(|arg| {
+            let (selector, dir) = split_assignment(arg, "PART=DIR")?;
+            Ok((find_part(&desc, selector)?, dir))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut removed = Vec::new();
+    for selector in &args.remove {
+        let index = find_part(&desc, selector)?;
+        if removed.contains(&index) {
+            bail!("Part {} is removed more than once", desc.parts[index].path);
+        }
+        removed.push(index);
+    }
+
+    let order = if args.order.is_empty() {
+        None
+    } else {
+        Some(
+            args.order
+                .iter()
+                .map(|selector| find_part(&desc, selector))
+                .collect::<Result<Vec<_>>>()?,
+        )
+    };
+
+    let mut inserts = Vec::new();
+    for arg in &args.insert {
+        let (index, dir) = split_assignment(arg, "INDEX=DIR")?;
+        let index: usize = index
+            .strip_prefix('@')
+            .unwrap_or(index)
+            .parse()
+            .with_context(|| format!("Invalid part index '{}'", index))?;
+        if index > desc.parts.len() {
+            bail!(
+                "Insert position {} out of range (desc.txt has {} parts)",
+                index,
+                desc.parts.len()
+            );
+        }
+        inserts.push((index, dir));
+    }
+
+    for (index, dir) in replacements {
+        let part = &desc.parts[index];
+        if part.is_system() {
+            bail!("Cannot replace frames of a $SYSTEM part");
+        }
+
+        let part_dir = root.join(&part.path);
+        if part_dir.exists() {
+            for frame in list_frames(&part_dir)? {
+                fs::remove_file(frame)?;
+            }
+            // trims describe the old frames
+            let trim = part_dir.join("trim.txt");
+            if trim.exists() {
+                fs::remove_file(trim)?;
+                println!("Dropped trim.txt of {}", part.path);
+            }
+        }
+
+        let count = copy_frames_into_part(Path::new(dir), &part_dir)?;
+        println!("Replaced frames of {} with {} frames from {}", part.path, count, dir);
+    }
+
+    // part fields are set before parts move, so PART means the same as everywhere else
+    for arg in &args.set {
+        let (key, value) = split_assignment(arg, "KEY=VALUE")?;
+        set_field(&mut desc, key, value)?;
+    }
+
+    let mut inserted = Vec::new();
+    for (index, dir) in inserts {
+        let name = unused_part_name(&root, &desc);
+        let count = copy_frames_into_part(Path::new(dir), &root.join(&name))?;
+        println!("Inserted {} before part {} with {} frames from {}", name, index, count, dir);
+        inserted.push((index, Part::new(PartKind::Interruptible, 1, 0, &name)));
+    }
+
+    let removed_parts: Vec<Part> = removed.iter().map(|&i| desc.parts[i].clone()).collect();
+    rearrange_parts(&mut desc, &removed, order.as_deref(), inserted)?;
+
+    for part in removed_parts {
+        // drop the directory once nothing references it anymore
+        if !part.is_system() && !desc.parts.iter().any(|p| p.path == part.path) {
+            let part_dir = root.join(&part.path);
+            if part_dir.exists() {
+                fs::remove_dir_all(&part_dir)?;
+            }
+        }
+        println!("Removed {}", part.path);
+    }
+
+    if order.is_some() {
+        println!(
+            "Reordered parts: {}",
+            desc.parts
+                .iter()
+                .map(|p| p.path.as_str())
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+    }
+
+    // every remaining part must still have frames to show
+    for name in desc.part_dirs() {
+        let part_dir = root.join(name);
+        if !part_dir.is_dir() || list_frames(&part_dir)?.is_empty() {
+            bail!("Part {} has no frames", name);
+        }
+    }
+
+    if desc.parts.is_empty() {
+        bail!("Edit would leave the animation without parts");
+    }
+
+    desc.write(&desc_path)?;
+
+    println!("\nResulting desc.txt:");
+    print!("{}", desc);
+
+    // write next to the destination first so a failure never clobbers the input
+    let output = args.output.as_deref().unwrap_or(&args.input);
+    let output_dir = output
+        .parent()
+        .filter(|p| !p.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    let staged = tempfile::Builder::new()
+        .suffix(".zip")
+        .tempfile_in(output_dir)
+        .context("Failed to create temporary output file")?;
+
+    create_bootanimation_zip(&root, staged.path())?;
+    staged
+        .persist(output)
+        .with_context(|| format!("Failed to write {}", output.display()))?;
+
+    println!("Successfully wrote bootanimation: {}", output.display());
+
+    Ok(())
+}
+
+fn frame_extension(frame: &Path) -> String {
+    frame
+        .extension()
+        .map(|e| e.to_string_lossy().to_lowercase())
+        .unwrap_or_default()
+}
+
+fn rescale_position(position: &str, factor: f64) -> String {
+    // clock positions are either pixels (negative counts from the far edge) or keywords like "c"
+    match position.parse::<i64>() {
+        Ok(value) => ((value as f64 * factor).round() as i64).to_string(),
+        Err(_) => position.to_string(),
+    }
+}
+
+/// Runs one filter pass over a group of frames, writing them under their old names
+fn convert_frames(
+    backend: &dyn MediaBackend,
+    frames: &[PathBuf],
+    work_dir: &Path,
+    out_dir: &Path,
+    filter: &str,
+    format: ImageFormat,
+    quality: Option<u8>,
+) -> Result<()> {
+    let converted_dir = work_dir.join("converted");
+    if converted_dir.exists() {
+        fs::remove_dir_all(&converted_dir)?;
+    }
+    fs::create_dir_all(&converted_dir)?;
+
+    let quality = FrameQuality { jpeg: quality };
+    backend.filter_frames(frames, filter, format, quality, &converted_dir, work_dir)?;
+
+    for (idx, frame) in frames.iter().enumerate() {
+        let stem = frame.file_stem().unwrap().to_string_lossy();
+        fs::rename(
+            converted_dir.join(format!("{:06}.{}", idx + 1, format.extension())),
+            out_dir.join(format!("{}.{}", stem, format.extension())),
+        )?;
+    }
+
+    Ok(())
+}
+
+struct ResizeOptions {
+    width: u32,
+    height: u32,
+    scale_mode: ScaleMode,
+    format: Option<ImageFormat>,
+    quality: Option<u8>,
+    background: Option<String>,
+}
+
+/// Rescales every part of an extracted animation from `src_root` into `out_root`,
+/// updating `desc` to the new canvas
+/// Places a trimmed frame like the canvas and clips it to the `width`x`height` canvas
+///
+/// Returns the new trim and the filter scaling and cropping the frame to it. A frame
+/// that lands fully outside keeps its pixel nearest to the canvas, at the canvas edge.
+fn trim_filter(trim: Trim, placement: &Placement, width: u32, height: u32) -> (Trim, String) {
+    let x = trim.x as f64 * placement.scale_x + placement.offset_x;
+    let y = trim.y as f64 * placement.scale_y + placement.offset_y;
+    let w = ((trim.width as f64 * placement.scale_x).round() as u32).max(1);
+    let h = ((trim.height as f64 * placement.scale_y).round() as u32).max(1);
+
+    let (x0, y0) = (x.round() as i64, y.round() as i64);
+    let left = x0.clamp(0, width as i64 - 1);
+    let top = y0.clamp(0, height as i64 - 1);
+    let right = (x0 + w as i64).clamp(left + 1, width as i64);
+    let bottom = (y0 + h as i64).clamp(top + 1, height as i64);
+
+    let crop_x = (left - x0).clamp(0, w as i64 - 1) as u32;
+    let crop_y = (top - y0).clamp(0, h as i64 - 1) as u32;
+    let clipped = Trim {
+        width: ((right - left) as u32).min(w - crop_x),
+        height: ((bottom - top) as u32).min(h - crop_y),
+        x: left as u32,
+        y: top as u32,
+    };
+
+    let filter = if clipped.width == w && clipped.height == h {
+        format!("scale={}:{}", w, h)
+    } else {
+        format!(
+            "scale={}:{},crop={}:{}:{}:{}",
+            w, h, clipped.width, clipped.height, crop_x, crop_y
+        )
+    };
+    (clipped, filter)
+}
+
+fn resize_tree(
+    backend: &dyn MediaBackend,
+    src_root: &Path,
+    out_root: &Path,
+    work_dir: &Path,
+    desc: &mut Desc,
+    options: &ResizeOptions,
+) -> Result<()> {
+    let (src_width, src_height) = (desc.header.width, desc.header.height);
+    let (width, height) = (options.width, options.height);
+    println!("Resizing {}x{} -> {}x{} ({:?})", src_width, src_height, width, height, options.scale_mode);
+
+    let placement = options.scale_mode.placement(src_width, src_height, width, height);
+
+    for name in desc.part_dirs() {
+        let part_dir = src_root.join(name);
+        let out_dir = out_root.join(name);
+        fs::create_dir_all(&out_dir)?;
+
+        let frames = list_frames(&part_dir)?;
+
+        let trim_path = part_dir.join("trim.txt");
+        let trims = if trim_path.exists() {
+            let trims = Trim::read_all(&trim_path)?;
+            if trims.len() != frames.len() {
+                bail!(
+                    "{}: trim.txt has {} entries for {} frames",
+                    name,
+                    trims.len(),
+                    frames.len()
+                );
+            }
+            Some(trims)
+        } else {
+            None
+        };
+
+        // keep audio and anything else that is not a frame
+        for entry in fs::read_dir(&part_dir)? {
+            let path = entry?.path();
+            if path.is_file() && !frames.contains(&path) && path != trim_path {
+                fs::copy(&path, out_dir.join(path.file_name().unwrap()))?;
+            }
+        }
+
+        let mut new_trims = Vec::new();
+        let mut start = 0;
+
+        // frames sharing extension and trim go through one FFmpeg run
+        while start < frames.len() {
+            let key = |i: usize| (frame_extension(&frames[i]), trims.as_ref().map(|t| t[i]));
+            let mut end = start + 1;
+            while end < frames.len() && key(end) == key(start) {
+                end += 1;
+            }
+
+            let format = options
+                .format
+                .or_else(|| ImageFormat::from_extension(&frame_extension(&frames[start])))
+                .unwrap_or(ImageFormat::Png);
+
+            let filter = match trims.as_ref().map(|t| t[start]) {
+                None => options.scale_mode.filter(width, height, options.background.as_deref()),
+                Some(trim) => {
+                    let (clipped, filter) = trim_filter(trim, &placement, width, height);
+                    new_trims.extend(std::iter::repeat_n(clipped, end - start));
+                    filter
+                }
+            };
+
+            convert_frames(
+                backend,
+                &frames[start..end],
+                work_dir,
+                &out_dir,
+                &filter,
+                format,
+                options.quality,
+            )?;
+            start = end;
+        }
+
+        if trims.is_some() {
+            let lines: String = new_trims.iter().map(|t| format!("{}\n", t)).collect();
+            fs::write(out_dir.join("trim.txt"), lines)?;
+        }
+
+        println!("  {}: {} frames", name, frames.len());
+    }
+
+    // the header and pixel positions follow the new canvas
+    let factor_x = width as f64 / src_width as f64;
+    let factor_y = height as f64 / src_height as f64;
+    desc.header = desc.header.resized(width, height);
+    for part in &mut desc.parts {
+        for (idx, position) in part.clock.iter_mut().enumerate() {
+            let factor = if idx == 0 { factor_x } else { factor_y };
+            *position = rescale_position(position, factor);
+        }
+    }
+    Ok(())
+}
+
+fn resize(args: ResizeArgs) -> Result<()> {
+    if !args.input.exists() {
+        bail!("Input bootanimation file does not exist: {}", args.input.display());
+    }
+
+    if args.quality.is_some() && args.format == Some(ImageFormat::Png) {
+        bail!("--quality only applies to JPEG frames, it cannot be used with --format png");
+    }
+
+    let background = args.background.as_deref().map(validate_color).transpose()?;
+
+    let backend = FfmpegCli::from_env();
+    let temp_dir = TempDir::new()?;
+    let src_root = temp_dir.path().join("extracted");
+    let out_root = temp_dir.path().join("resized");
+    let work_dir = temp_dir.path().join("work");
+    fs::create_dir_all(&out_root)?;
+    fs::create_dir_all(&work_dir)?;
+
+    println!("Extracting {}...", args.input.display());
+    extract_zip(&args.input, &src_root)?;
+
+    let desc_path = src_root.join("desc.txt");
+    if !desc_path.exists() {
+        bail!("desc.txt not found in bootanimation.zip");
+    }
+    let mut desc = Desc::read(&desc_path)?;
+
+    let (src_width, src_height) = (desc.header.width, desc.header.height);
+    let (width, height) = match (args.width, args.height) {
+        (Some(w), Some(h)) => (w, h),
+        (Some(w), None) => (w, (w as f64 * src_height as f64 / src_width as f64).round() as u32),
+        (None, Some(h)) => ((h as f64 * src_width as f64 / src_height as f64).round() as u32, h),
+        (None, None) => unreachable!("clap requires a width or height"),
+    };
+
+    let options = ResizeOptions {
+        width,
+        height,
+        scale_mode: args.scale_mode,
+        format: args.format,
+        quality: args.quality,
+        background,
+    };
+    resize_tree(&backend, &src_root, &out_root, &work_dir, &mut desc, &options)?;
+    desc.write(&out_root.join("desc.txt"))?;
+
+    create_bootanimation_zip(&out_root, &args.output)?;
+
+    println!("Successfully wrote bootanimation: {}", args.output.display());
+
+    Ok(())
+}
+
+/// Copies a part directory to `dst_dir`, dropping or repeating frames to go from `src_fps` to `dst_fps`
+fn resample_part(src_dir: &Path, dst_dir: &Path, src_fps: u32, dst_fps: u32) -> Result<usize> {
+    if src_fps == 0 || dst_fps == 0 {
+        bail!("Cannot resample {} from {} to {} fps", src_dir.display(), src_fps, dst_fps);
+    }
+    fs::create_dir_all(dst_dir)?;
+
+    let frames = list_frames(src_dir)?;
+    let trim_path = src_dir.join("trim.txt");
+    // an empty trim.txt trims nothing
+    let trims = if trim_path.exists() {
+        Some(Trim::read_all(&trim_path)?).filter(|trims| !trims.is_empty())
+    } else {
+        None
+    };
+
+    for entry in fs::read_dir(src_dir)? {
+        let path = entry?.path();
+        if path.is_file() && !frames.contains(&path) && path != trim_path {
+            fs::copy(&path, dst_dir.join(path.file_name().unwrap()))?;
+        }
+    }
+
+    if frames.is_empty() {
+        return Ok(0);
+    }
+
+    if src_fps == dst_fps {
+        for frame in &frames {
+            fs::copy(frame, dst_dir.join(frame.file_name().unwrap()))?;
+        }
+        if trims.is_some() {
+            fs::copy(&trim_path, dst_dir.join("trim.txt"))?;
+        }
+        return Ok(frames.len());
+    }
+
+    // nearest source frame for every output frame keeps the duration
+    let count = ((frames.len() as u64 * dst_fps as u64) as f64 / src_fps as f64)
+        .round()
+        .max(1.0) as usize;
+    let mut new_trims = Vec::new();
+
+    for idx in 0..count {
+        let src_idx = ((idx as u64 * src_fps as u64 / dst_fps as u64) as usize).min(frames.len() - 1);
+        let frame = &frames[src_idx];
+        fs::copy(
+            frame,
+            dst_dir.join(format!("{:05}.{}", idx + 1, frame_extension(frame))),
+        )?;
+        if let Some(ref trims) = trims {
+            new_trims.push(trims[src_idx.min(trims.len() - 1)]);
+        }
+    }
+
+    if trims.is_some() {
+        let lines: String = new_trims.iter().map(|t| format!("{}\n", t)).collect();
+        fs::write(dst_dir.join("trim.txt"), lines)?;
+    }
+
+    Ok(count)
+}
+
+fn concat(args: ConcatArgs) -> Result<()> {
+    if args.input.len() < 2 {
+        bail!("At least two bootanimations are needed to concatenate");
+    }
+
+    let backend = FfmpegCli::from_env();
+    let background = args.background.as_deref().map(validate_color).transpose()?;
+
+    let temp_dir = TempDir::new()?;
+    let out_root = temp_dir.path().join("result");
+    fs::create_dir_all(&out_root)?;
+
+    let mut sources = Vec::new();
+    for (idx, input) in args.input.iter().enumerate() {
+        if !input.exists() {
+            bail!("Input bootanimation file does not exist: {}", input.display());
+        }
+
+        let root = temp_dir.path().join(format!("input{}", idx));
+        println!("Extracting {}...", input.display());
+        extract_zip(input, &root)?;
+
+        let desc_path = root.join("desc.txt");
+        if !desc_path.exists() {
+            bail!("desc.txt not found in {}", input.display());
+        }
+        let desc = Desc::read(&desc_path)?;
+        println!(
+            "  {}x{} @ {} fps, {} parts",
+            desc.header.width,
+            desc.header.height,
+            desc.header.fps,
+            desc.parts.len()
+        );
+        sources.push((root, desc));
+    }
+
+    // the first animation decides everything the CLI leaves open
+    let first = &sources[0].1.header;
+    let width = args.width.unwrap_or(first.width);
+    let height = args.height.unwrap_or(first.height);
+    let fps = args.fps.unwrap_or(first.fps);
+    if fps == 0 {
+        bail!("Invalid fps: 0");
+    }
+
+    let mut header = first.resized(width, height);
+    header.fps = fps;
+    let mut merged = Desc {
+        header,
+        extra_lines: sources[0].1.extra_lines.clone(),
+        parts: Vec::new(),
+    };
+
+    println!("\nOutput configuration:");
+    println!("  Resolution: {}x{}", width, height);
+    println!("  FPS: {}", fps);
+
+    let mut next_part = 0;
+
+    for (idx, (root, desc)) in sources.iter_mut().enumerate() {
+        let input = &args.input[idx];
+
+        for line in &desc.extra_lines {
+            if !merged.extra_lines.iter().any(|kept| kept.text == line.text) {
+                eprintln!("Warning: dropping '{}' from {}", line.text, input.display());
+            }
+        }
+
+        // bring the animation to the output canvas first
+        let mut src_root = root.clone();
+        if desc.header.width != width || desc.header.height != height {
+            let sized_root = temp_dir.path().join(format!("sized{}", idx));
+            let work_dir = temp_dir.path().join(format!("work{}", idx));
+            fs::create_dir_all(&sized_root)?;
+            fs::create_dir_all(&work_dir)?;
+
+            let options = ResizeOptions {
+                width,
+                height,
+                scale_mode: args.scale_mode,
+                format: None,
+                quality: None,
+                background: background.clone(),
+            };
+            resize_tree(&backend, root, &sized_root, &work_dir, desc, &options)?;
+            src_root = sized_root;
+        }
+
+        let src_fps = desc.header.fps;
+        if src_fps != fps {
+            println!("Resampling {} from {} to {} fps", input.display(), src_fps, fps);
+        }
+
+        // parts are renumbered so names never clash between animations
+        let mut renamed: Vec<(String, String)> = Vec::new();
+        for name in desc.part_dirs() {
+            let new_name = format!("part{}", next_part);
+            next_part += 1;
+            let count = resample_part(&src_root.join(name), &out_root.join(&new_name), src_fps, fps)?;
+            println!("  {} -> {} ({} frames)", name, new_name, count);
+            renamed.push((name.to_string(), new_name));
+        }
+
+        for part in &desc.parts {
+            let mut part = part.clone();
+            if let Some((_, new_name)) = renamed.iter().find(|(old, _)| *old == part.path) {
+                part.path = new_name.clone();
+            }
+            if src_fps != fps {
+                part.pause = (part.pause as f64 * fps as f64 / src_fps as f64).round() as u32;
+                part.fade_frames = part
+                    .fade_frames
+                    .map(|f| (f as f64 * fps as f64 / src_fps as f64).round() as u32);
+            }
+            merged.parts.push(part);
+        }
+    }
+
+    let last_input_start = merged.parts.len() - sources.last().map(|(_, d)| d.parts.len()).unwrap_or(0);
+    for warning in boot_warnings(&merged, last_input_start) {
+        eprintln!("Warning: {}", warning);
+    }
+
+    merged.write(&out_root.join("desc.txt"))?;
+
+    println!("\nResulting desc.txt:");
+    print!("{}", merged);
+
+    create_bootanimation_zip(&out_root, &args.output)?;
+
+    println!("Successfully wrote bootanimation: {}", args.output.display());
+
+    Ok(())
+}
+
+/// What an endless part before `from` does to the parts of the animations after it
+///
+/// Boot completes after the part loops, then only `c` parts still play and the others
+/// are skipped, as laid out by [`Desc::timeline`].
+fn boot_warnings(desc: &Desc, from: usize) -> Vec<String> {
+    let timeline = desc.timeline(&vec![0; desc.parts.len()], 1);
+    let Some(boot) = timeline.boot_part.filter(|&boot| boot < from) else {
+        return Vec::new();
+    };
+
+    let names = |skipped: bool| -> Vec<&str> {
+        desc.parts
+            .iter()
+            .zip(&timeline.parts)
+            .skip(boot + 1)
+            .filter(|(_, play)| play.is_none() == skipped)
+            .map(|(part, _)| part.path.as_str())
+            .collect()
+    };
+    let looping = &desc.parts[boot].path;
+    let mut warnings = Vec::new();
+    let skipped = names(true);
+    if !skipped.is_empty() {
+        warnings.push(format!(
+            "{} loops until boot completes, {} after it never play (only c parts play once booting has finished)",
+            looping,
+            skipped.join(", ")
+        ));
+    }
+    let held_back = names(false);
+    if !held_back.is_empty() {
+        warnings.push(format!(
+            "{} loops until boot completes, {} after it only play once booting has finished",
+            looping,
+            held_back.join(", ")
+        ));
+    }
+    warnings
+}
+
+fn unpack(args: UnpackArgs) -> Result<()> {
+    if !args.input.exists() {
+        bail!("Input bootanimation file does not exist: {}", args.input.display());
+    }
+    if args.output.exists() && fs::read_dir(&args.output)?.next().is_some() {
+        bail!("Output directory is not empty: {}", args.output.display());
+    }
+
+    println!("Extracting {}...", args.input.display());
+    extract_zip(&args.input, &args.output)?;
+
+    let desc_path = args.output.join("desc.txt");
+    if !desc_path.exists() {
+        eprintln!("Warning: desc.txt not found in bootanimation.zip");
+    } else {
+        let desc = Desc::read(&desc_path)?;
+        for name in desc.part_dirs() {
+            let part_dir = args.output.join(name);
+            let frames = if part_dir.is_dir() { list_frames(&part_dir)?.len() } else { 0 };
+            println!("  {}: {} frames", name, frames);
+        }
+    }
+
+    println!("Successfully unpacked to {}", args.output.display());
+
+    Ok(())
+}
+
+fn pack(args: PackArgs) -> Result<()> {
+    let desc_path = args.input.join("desc.txt");
+    if !desc_path.exists() {
+        bail!("desc.txt not found in {}", args.input.display());
+    }
+    let desc = Desc::read(&desc_path)?;
+    let part_dirs = desc.part_dirs();
+
+    let temp_dir = TempDir::new()?;
+    let staged_root = temp_dir.path().join("result");
+    fs::create_dir_all(&staged_root)?;
+
+    let mut stray = Vec::new();
+    let mut problems = Vec::new();
+
+    // only desc.txt and referenced part folders belong at the top level
+    for entry in fs::read_dir(&args.input)? {
+        let path = entry?.path();
+        let name = path.file_name().unwrap().to_string_lossy().to_string();
+        let referenced = path.is_dir() && part_dirs.contains(&name.as_str());
+        if name != "desc.txt" && !referenced {
+            stray.push(path);
+        }
+    }
+
+    for name in &part_dirs {
+        let part_dir = args.input.join(name);
+        if !part_dir.is_dir() {
+            problems.push(format!("{} is listed in desc.txt but has no folder", name));
+            continue;
+        }
+
+        let mut frames = list_frames(&part_dir)?;
+        if frames.is_empty() {
+            problems.push(format!("{} has no frames (PNG or JPG)", name));
+            continue;
+        }
+
+        for entry in fs::read_dir(&part_dir)? {
+            let path = entry?.path();
+            let file_name = path.file_name().unwrap().to_string_lossy().to_string();
+            let known = file_name == "audio.wav" || file_name == "trim.txt";
+            // hidden files like .DS_Store or ._frame.png are never frames
+            if path.is_dir() || file_name.starts_with('.') || (!known && !frames.contains(&path)) {
+                stray.push(path);
+            }
+        }
+        frames.retain(|f| !f.file_name().unwrap().to_string_lossy().starts_with('.'));
+
+        // trim.txt lines follow the frames in plain name order, like the device reads them
+        let trim_path = part_dir.join("trim.txt");
+        let mut trims = None;
+        if trim_path.exists() {
+            let lines = Trim::read_all(&trim_path)?;
+            if lines.len() == frames.len() {
+                trims = Some(lines);
+            } else {
+                problems.push(format!(
+                    "{}: trim.txt has {} entries for {} frames",
+                    name,
+                    lines.len(),
+                    frames.len()
+                ));
+            }
+        }
+
+        // Android plays frames in plain name order, so frame2 would follow frame10
+        let lexical = frames.clone();
+        sort_frames_naturally(&mut frames);
+        let staged_dir = staged_root.join(name);
+        fs::create_dir_all(&staged_dir)?;
+
+        if frames == lexical {
+            for frame in &frames {
+                fs::copy(frame, staged_dir.join(frame.file_name().unwrap()))?;
+            }
+            if trim_path.exists() {
+                fs::copy(&trim_path, staged_dir.join("trim.txt"))?;
+            }
+        } else {
+            let digits = frames.len().to_string().len().max(5);
+            for (idx, frame) in frames.iter().enumerate() {
+                let new_name = format!("{:0digits$}.{}", idx + 1, frame_extension(frame), digits = digits);
+                fs::copy(frame, staged_dir.join(new_name))?;
+            }
+            // every frame keeps its own trim line under its new name
+            if let Some(trims) = &trims {
+                let lines: String = frames
+                    .iter()
+                    .map(|frame| {
+                        let line = lexical.iter().position(|f| f == frame).unwrap();
+                        format!("{}\n", trims[line])
+                    })
+                    .collect();
+                fs::write(staged_dir.join("trim.txt"), lines)?;
+            }
+            println!("  {}: renamed {} frames to keep their numeric order", name, frames.len());
+        }
+
+        let audio_path = part_dir.join("audio.wav");
+        if audio_path.exists() {
+            fs::copy(&audio_path, staged_dir.join("audio.wav"))?;
+        }
+
+        println!("  {}: {} frames", name, frames.len());
+    }
+
+    if !problems.is_empty() {
+        bail!("Invalid animation folder:\n  {}", problems.join("\n  "));
+    }
+
+    if !stray.is_empty() {
+        let list: Vec<String> = stray.iter().map(|p| p.display().to_string()).collect();
+        if args.skip_stray {
+            for path in &list {
+                println!("Skipping stray file {}", path);
+            }
+        } else {
+            bail!(
+                "Refusing to pack stray files (use --skip-stray to leave them out):\n  {}",
+                list.join("\n  ")
+            );
+        }
+    }
+
+    fs::copy(&desc_path, staged_root.join("desc.txt"))?;
+    create_bootanimation_zip(&staged_root, &args.output)?;
+
+    println!("Successfully wrote bootanimation: {}", args.output.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn desc(parts: &[&str]) -> Desc {
+        let mut content = String::from("1080 1920 30\n");
+        for part in parts {
+            content.push_str(&format!("p 1 0 {}\n", part));
+        }
+        Desc::parse(&content).unwrap()
+    }
+
+    fn paths(desc: &Desc) -> Vec<&str> {
+        desc.parts.iter().map(|p| p.path.as_str()).collect()
+    }
+
+    fn new_part(name: &str) -> Part {
+        Part::new(PartKind::Interruptible, 1, 0, name)
+    }
+
+    #[test]
+    fn directory_names_win_over_indexes() {
+        let desc = desc(&["1", "0", "part2"]);
+        assert_eq!(find_part(&desc, "0").unwrap(), 1);
+        assert_eq!(find_part(&desc, "@0").unwrap(), 0);
+        assert_eq!(find_part(&desc, "2").unwrap(), 2);
+        assert!(find_part(&desc, "@3").is_err());
+        assert!(find_part(&desc, "part9").is_err());
+    }
+
+    #[test]
+    fn multiple_removals_refer_to_the_input_parts() {
+        let mut desc = desc(&["part0", "part1", "part2", "part3"]);
+        let removed = [find_part(&desc, "0").unwrap(), find_part(&desc, "1").unwrap()];
+        rearrange_parts(&mut desc, &removed, None, Vec::new()).unwrap();
+        assert_eq!(paths(&desc), ["part2", "part3"]);
+    }
+
+    #[test]
+    fn inserted_parts_move_with_the_part_they_precede() {
+        let mut desc = desc(&["part0", "part1", "part2"]);
+        rearrange_parts(
+            &mut desc,
+            &[1],
+            Some(&[2, 0]),
+            vec![(0, new_part("intro")), (1, new_part("middle")), (3, new_part("outro"))],
+        )
+        .unwrap();
+        // middle was inserted before the removed part1, so it precedes part2
+        assert_eq!(paths(&desc), ["middle", "part2", "intro", "part0", "outro"]);
+    }
+
+    #[test]
+    fn order_must_list_every_remaining_part() {
+        let mut desc = desc(&["part0", "part1", "part2"]);
+        assert!(rearrange_parts(&mut desc, &[0], Some(&[2, 1, 0]), Vec::new()).is_err());
+        assert!(rearrange_parts(&mut desc, &[], Some(&[2, 2, 0]), Vec::new()).is_err());
+        rearrange_parts(&mut desc, &[0], Some(&[2, 1]), Vec::new()).unwrap();
+        assert_eq!(paths(&desc), ["part2", "part1"]);
+    }
+
+    /// Part directory with `count` placeholder frames and one trim entry per frame
+    fn part_with_trims(root: &Path, count: usize) -> PathBuf {
+        let dir = root.join("src");
+        fs::create_dir_all(&dir).unwrap();
+        let mut trims = String::new();
+        for idx in 0..count {
+            fs::write(dir.join(format!("{:05}.png", idx + 1)), format!("frame {}", idx)).unwrap();
+            trims.push_str(&format!("10x10+{}+0\n", idx));
+        }
+        fs::write(dir.join("trim.txt"), trims).unwrap();
+        dir
+    }
+
+    fn resampled(dir: &Path) -> (Vec<String>, Vec<Trim>) {
+        let frames = list_frames(dir)
+            .unwrap()
+            .iter()
+            .map(|f| fs::read_to_string(f).unwrap())
+            .collect();
+        (frames, Trim::read_all(&dir.join("trim.txt")).unwrap())
+    }
+
+    #[test]
+    fn resampling_down_keeps_frames_and_trims_together() {
+        let temp = TempDir::new().unwrap();
+        let src = part_with_trims(temp.path(), 6);
+        let dst = temp.path().join("dst");
+
+        assert_eq!(resample_part(&src, &dst, 30, 15).unwrap(), 3);
+        let (frames, trims) = resampled(&dst);
+        assert_eq!(frames, ["frame 0", "frame 2", "frame 4"]);
+        assert_eq!(trims.iter().map(|t| t.x).collect::<Vec<_>>(), [0, 2, 4]);
+    }
+
+    #[test]
+    fn resampling_up_repeats_frames_and_trims() {
+        let temp = TempDir::new().unwrap();
+        let src = part_with_trims(temp.path(), 3);
+        let dst = temp.path().join("dst");
+
+        assert_eq!(resample_part(&src, &dst, 10, 20).unwrap(), 6);
+        let (frames, trims) = resampled(&dst);
+        assert_eq!(
+            frames,
+            ["frame 0", "frame 0", "frame 1", "frame 1", "frame 2", "frame 2"]
+        );
+        assert_eq!(trims.iter().map(|t| t.x).collect::<Vec<_>>(), [0, 0, 1, 1, 2, 2]);
+    }
+
+    #[test]
+    fn resampling_an_empty_part_writes_nothing() {
+        let temp = TempDir::new().unwrap();
+        let src = temp.path().join("src");
+        fs::create_dir_all(&src).unwrap();
+        fs::write(src.join("trim.txt"), "").unwrap();
+        let dst = temp.path().join("dst");
+
+        assert_eq!(resample_part(&src, &dst, 30, 24).unwrap(), 0);
+        assert!(list_frames(&dst).unwrap().is_empty());
+        assert!(resample_part(&src, &dst, 0, 24).is_err());
+        assert!(resample_part(&src, &dst, 30, 0).is_err());
+    }
+
+    #[test]
+    fn trims_scale_with_the_canvas() {
+        let placement = ScaleMode::Stretch.placement(200, 100, 100, 50);
+        let (trim, filter) = trim_filter(Trim::parse("40x20+10+10").unwrap(), &placement, 100, 50);
+        assert_eq!(trim.to_string(), "20x10+5+5");
+        assert_eq!(filter, "scale=20:10");
+    }
+
+    #[test]
+    fn trims_partly_outside_the_canvas_are_clipped() {
+        // filling 200x100 with a 100x100 picture scales it by 2 and cuts 50 rows off
+        // the top and bottom
+        let placement = ScaleMode::Fill.placement(100, 100, 200, 100);
+        let (trim, filter) = trim_filter(Trim::parse("100x30+0+10").unwrap(), &placement, 200, 100);
+        assert_eq!(trim.to_string(), "200x30+0+0");
+        assert_eq!(filter, "scale=200:60,crop=200:30:0:30");
+
+        let (trim, filter) = trim_filter(Trim::parse("50x30+50+60").unwrap(), &placement, 200, 100);
+        assert_eq!(trim.to_string(), "100x30+100+70");
+        assert_eq!(filter, "scale=100:60,crop=100:30:0:0");
+    }
+
+    #[test]
+    fn trims_fully_outside_the_canvas_keep_their_nearest_row() {
+        let placement = ScaleMode::Fill.placement(100, 100, 200, 100);
+        let (trim, filter) = trim_filter(Trim::parse("100x10+0+0").unwrap(), &placement, 200, 100);
+        assert_eq!(trim.to_string(), "200x1+0+0");
+        assert_eq!(filter, "scale=200:20,crop=200:1:0:19");
+
+        let (trim, filter) = trim_filter(Trim::parse("100x10+0+90").unwrap(), &placement, 200, 100);
+        assert_eq!(trim.to_string(), "200x1+0+99");
+        assert_eq!(filter, "scale=200:20,crop=200:1:0:0");
+    }
+
+    #[test]
+    fn endless_parts_skip_p_parts_of_later_animations() {
+        let desc = Desc::parse(
+            "720 1280 30\n\
+             p 1 0 part0\n\
+             p 0 0 part1\n\
+             p 1 0 part2\n\
+             c 1 0 part3\n\
+             f 0 0 part4\n",
+        )
+        .unwrap();
+        assert_eq!(
+            boot_warnings(&desc, 2),
+            [
+                "part1 loops until boot completes, part2, part4 after it never play \
+                 (only c parts play once booting has finished)",
+                "part1 loops until boot completes, part3 after it only play once booting has finished",
+            ]
+        );
+        // the endless part of the last animation holds nothing back
+        assert!(boot_warnings(&desc, 1).is_empty());
+    }
+
+    /// Animation folder with the named frames (and trim lines) in one part
+    fn animation_folder(root: &Path, frames: &[&str], trims: Option<&str>) {
+        let part = root.join("part0");
+        fs::create_dir_all(&part).unwrap();
+        fs::write(root.join("desc.txt"), "64 64 10\np 0 0 part0\n").unwrap();
+        for name in frames {
+            fs::write(part.join(name), name).unwrap();
+        }
+        if let Some(trims) = trims {
+            fs::write(part.join("trim.txt"), trims).unwrap();
+        }
+        fs::write(part.join("audio.wav"), "audio").unwrap();
+    }
+
+    fn pack_folder(input: &Path, output: &Path) {
+        pack(PackArgs {
+            input: input.to_path_buf(),
+            output: output.to_path_buf(),
+            skip_stray: false,
+        })
+        .unwrap();
+    }
+
+    #[test]
+    fn unpack_and_pack_round_trip_unchanged() {
+        let temp = TempDir::new().unwrap();
+        let folder = temp.path().join("folder");
+        animation_folder(&folder, &["00001.png", "00002.png"], Some("8x8+0+0\n4x4+2+2\n"));
+        let original = temp.path().join("original.zip");
+        pack_folder(&folder, &original);
+
+        let unpacked = temp.path().join("unpacked");
+        unpack(UnpackArgs {
+            input: original.clone(),
+            output: unpacked.clone(),
+        })
+        .unwrap();
+        let repacked = temp.path().join("repacked.zip");
+        pack_folder(&unpacked, &repacked);
+
+        assert_eq!(fs::read(&repacked).unwrap(), fs::read(&original).unwrap());
+    }
+
+    #[test]
+    fn renamed_frames_keep_their_trim_lines() {
+        let temp = TempDir::new().unwrap();
+        let folder = temp.path().join("folder");
+        // the lines follow the plain name order: frame1, frame10, frame2
+        animation_folder(
+            &folder,
+            &["frame1.png", "frame2.png", "frame10.png"],
+            Some("1x1+0+0\n10x10+0+0\n2x2+0+0\n"),
+        );
+        let output = temp.path().join("bootanimation.zip");
+        pack_folder(&folder, &output);
+
+        let unpacked = temp.path().join("unpacked");
+        unpack(UnpackArgs {
+            input: output,
+            output: unpacked.clone(),
+        })
+        .unwrap();
+        let part = unpacked.join("part0");
+        let frames: Vec<String> = list_frames(&part)
+            .unwrap()
+            .iter()
+            .map(|frame| fs::read_to_string(frame).unwrap())
+            .collect();
+        assert_eq!(frames, ["frame1.png", "frame2.png", "frame10.png"]);
+        assert_eq!(
+            fs::read_to_string(part.join("trim.txt")).unwrap(),
+            "1x1+0+0\n2x2+0+0\n10x10+0+0\n"
+        );
+        assert_eq!(fs::read_to_string(part.join("audio.wav")).unwrap(), "audio");
+    }
+}
+
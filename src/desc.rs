@@ -0,0 +1,598 @@
+//! Parsing and writing of `desc.txt` and per-part `trim.txt` files.
+
+use anyhow::{Context, Result, bail};
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+/// Path used by parts that play the stock system animation
+pub const SYSTEM_PART: &str = "$SYSTEM";
+
+/// First line of desc.txt
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Header {
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    /// Optional fourth field of the standard format
+    pub progress: Option<u32>,
+    /// `g width height offsetx offsety fps` instead of `width height fps`
+    pub is_global_format: bool,
+    pub offset_x: u32,
+    pub offset_y: u32,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum PartKind {
+    /// `p`: interrupted as soon as boot completes
+    Interruptible,
+    /// `c`: always played to the end
+    Complete,
+    /// `f`: faded out when boot completes
+    Fade,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Part {
+    pub kind: PartKind,
+    /// Number of plays, 0 loops until boot completes
+    pub count: u32,
+    /// Frames to hold the last frame after each play
+    pub pause: u32,
+    /// Directory inside the zip, or `$SYSTEM`
+    pub path: String,
+    /// Frames to fade over, only written for `f` parts
+    pub fade_frames: Option<u32>,
+    pub background: Option<String>,
+    /// Clock positions, at most two
+    pub clock: Vec<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Desc {
+    pub header: Header,
+    /// Lines that are neither header nor part (e.g. `dynamic_colors`, comments)
+    pub extra_lines: Vec<ExtraLine>,
+    pub parts: Vec<Part>,
+}
+
+/// Line of desc.txt kept verbatim at its place
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtraLine {
+    /// Header and part lines written before it, 0 above the header
+    pub position: usize,
+    pub text: String,
+}
+
+/// How the device plays the parts of a desc.txt, in frames from the start
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Timeline {
+    /// Every part in desc order, `None` for parts skipped once boot has completed
+    pub parts: Vec<Option<PartPlay>>,
+    /// Part that loops until boot completes, boot completes at the end of its plays
+    pub boot_part: Option<usize>,
+    pub end: u64,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct PartPlay {
+    pub start: u64,
+    pub plays: u32,
+    /// First frame after the last play and its pause
+    pub end: u64,
+}
+
+/// One line of a part's trim.txt: `WxH+X+Y`
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct Trim {
+    pub width: u32,
+    pub height: u32,
+    pub x: u32,
+    pub y: u32,
+}
+
+/// Normalizes a hex color to `#RRGGBB`/`#RGB` form
+pub fn validate_color(color: &str) -> Result<String> {
+    let color = color.trim_start_matches('#');
+
+    if color.len() != 6 && color.len() != 3 {
+        bail!("Invalid color format. Use #RRGGBB or #RGB");
+    }
+
+    if !color.chars().all(|c| c.is_ascii_hexdigit()) {
+        bail!("Invalid hex color code");
+    }
+
+    Ok(format!("#{}", color))
+}
+
+impl Header {
+    /// The same header drawn at `width`x`height`, with global format offsets scaled along
+    pub fn resized(&self, width: u32, height: u32) -> Header {
+        let scale = |value: u32, from: u32, to: u32| {
+            if from == 0 {
+                value
+            } else {
+                (value as f64 * to as f64 / from as f64).round() as u32
+            }
+        };
+        Header {
+            width,
+            height,
+            offset_x: scale(self.offset_x, self.width, width),
+            offset_y: scale(self.offset_y, self.height, height),
+            ..self.clone()
+        }
+    }
+
+    /// Bytes of one decoded frame, the player keeps frames as 32-bit pixels
+    pub fn frame_memory(&self) -> u64 {
+        self.width as u64 * self.height as u64 * 4
+    }
+
+    /// Most frames a part can hold within `budget` bytes once decoded
+    pub fn frames_within(&self, budget: u64) -> u64 {
+        budget / self.frame_memory().max(1)
+    }
+
+    /// Largest even size with the same aspect ratio where `frames` decoded frames fit in `budget` bytes
+    pub fn size_within(&self, frames: u64, budget: u64) -> (u32, u32) {
+        let needed = self.frame_memory() * frames.max(1);
+        let scale = (budget as f64 / needed as f64).sqrt().min(1.0);
+        let fit = |value: u32| (((value as f64 * scale / 2.0).floor() as u32) * 2).max(2);
+        (fit(self.width), fit(self.height))
+    }
+
+    fn parse(tokens: &[&str]) -> Result<Option<Self>> {
+        if tokens[0] == "g" && tokens.len() >= 6 {
+            // global format: g width height offsetx offsety fps
+            return Ok(Some(Header {
+                width: tokens[1].parse().context("Failed to parse width")?,
+                height: tokens[2].parse().context("Failed to parse height")?,
+                fps: tokens[5].parse().context("Failed to parse fps")?,
+                progress: None,
+                is_global_format: true,
+                offset_x: tokens[3].parse().context("Failed to parse offset_x")?,
+                offset_y: tokens[4].parse().context("Failed to parse offset_y")?,
+            }));
+        }
+
+        if tokens.len() >= 3 && tokens[0].parse::<u32>().is_ok() {
+            // original format: width height fps [progress]
+            return Ok(Some(Header {
+                width: tokens[0].parse().context("Failed to parse width")?,
+                height: tokens[1].parse().context("Failed to parse height")?,
+                fps: tokens[2].parse().context("Failed to parse fps")?,
+                progress: tokens.get(3).and_then(|t| t.parse().ok()),
+                is_global_format: false,
+                offset_x: 0,
+                offset_y: 0,
+            }));
+        }
+
+        Ok(None)
+    }
+}
+
+impl fmt::Display for Header {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_global_format {
+            write!(
+                f,
+                "g {} {} {} {} {}",
+                self.width, self.height, self.offset_x, self.offset_y, self.fps
+            )
+        } else {
+            write!(f, "{} {} {}", self.width, self.height, self.fps)?;
+            if let Some(progress) = self.progress {
+                write!(f, " {}", progress)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+impl PartKind {
+    pub fn from_char(c: char) -> Option<Self> {
+        match c {
+            'p' => Some(PartKind::Interruptible),
+            'c' => Some(PartKind::Complete),
+            'f' => Some(PartKind::Fade),
+            _ => None,
+        }
+    }
+
+    pub fn as_char(self) -> char {
+        match self {
+            PartKind::Interruptible => 'p',
+            PartKind::Complete => 'c',
+            PartKind::Fade => 'f',
+        }
+    }
+}
+
+impl Part {
+    pub fn new(kind: PartKind, count: u32, pause: u32, path: impl Into<String>) -> Self {
+        Part {
+            kind,
+            count,
+            pause,
+            path: path.into(),
+            fade_frames: None,
+            background: None,
+            clock: Vec::new(),
+        }
+    }
+
+    pub fn is_system(&self) -> bool {
+        self.path == SYSTEM_PART
+    }
+
+    fn parse(tokens: &[&str]) -> Result<Option<Self>> {
+        if tokens.len() < 4 || tokens[0].chars().count() != 1 {
+            return Ok(None);
+        }
+
+        let Some(kind) = tokens[0].chars().next().and_then(PartKind::from_char) else {
+            return Ok(None);
+        };
+
+        let mut part = Part::new(
+            kind,
+            tokens[1].parse().context("Failed to parse part count")?,
+            tokens[2].parse().context("Failed to parse part pause")?,
+            tokens[3],
+        );
+
+        let mut rest = tokens[4..].iter().peekable();
+
+        if kind == PartKind::Fade
+            && let Some(frames) = rest.peek().and_then(|t| t.parse().ok())
+        {
+            part.fade_frames = Some(frames);
+            rest.next();
+        }
+
+        if let Some(color) = rest.next_if(|t| t.starts_with('#')) {
+            part.background = Some(color.to_string());
+            // clock positions are only read after a background color
+            part.clock = rest.take(2).map(|t| t.to_string()).collect();
+        }
+
+        Ok(Some(part))
+    }
+}
+
+impl fmt::Display for Part {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} {} {} {}",
+            self.kind.as_char(),
+            self.count,
+            self.pause,
+            self.path
+        )?;
+        if self.kind == PartKind::Fade
+            && let Some(frames) = self.fade_frames
+        {
+            write!(f, " {}", frames)?;
+        }
+        if let Some(ref bg) = self.background {
+            write!(f, " {}", bg)?;
+            for pos in &self.clock {
+                write!(f, " {}", pos)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Desc {
+    pub fn parse(content: &str) -> Result<Self> {
+        let mut header = None;
+        let mut extra_lines = Vec::new();
+        let mut parts = Vec::new();
+
+        for line in content.lines() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() {
+                continue;
+            }
+            let position = header.is_some() as usize + parts.len();
+            if trimmed.starts_with('#') {
+                extra_lines.push(ExtraLine {
+                    position,
+                    text: trimmed.to_string(),
+                });
+                continue;
+            }
+
+            let tokens: Vec<&str> = trimmed.split_whitespace().collect();
+
+            if header.is_none() {
+                header = Header::parse(&tokens)?;
+                if header.is_none() {
+                    bail!("Unable to parse desc.txt header: {}", trimmed);
+                }
+            } else if let Some(part) = Part::parse(&tokens)? {
+                parts.push(part);
+            } else {
+                extra_lines.push(ExtraLine {
+                    position,
+                    text: trimmed.to_string(),
+                });
+            }
+        }
+
+        let Some(header) = header else {
+            bail!("Unable to parse desc.txt");
+        };
+
+        Ok(Desc {
+            header,
+            extra_lines,
+            parts,
+        })
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        Desc::parse(&content)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        fs::write(path, self.to_string())
+            .with_context(|| format!("Failed to write {}", path.display()))
+    }
+
+    /// Removes the part at `index`, lines after it stay after its predecessor
+    pub fn remove_part(&mut self, index: usize) -> Part {
+        for line in &mut self.extra_lines {
+            if line.position > index + 1 {
+                line.position -= 1;
+            }
+        }
+        self.parts.remove(index)
+    }
+
+    /// Inserts `part` before the part at `index`, lines before it stay before it
+    pub fn insert_part(&mut self, index: usize, part: Part) {
+        for line in &mut self.extra_lines {
+            if line.position > index + 1 {
+                line.position += 1;
+            }
+        }
+        self.parts.insert(index, part);
+    }
+
+    /// Lays the parts out the way the device plays them, every play lasting the
+    /// part's frames plus its pause
+    ///
+    /// `frames` holds the frame count of every part in desc order. Boot is taken to
+    /// complete after `loops` plays of the first part that loops until boot (count 0);
+    /// from then on only `c` parts play, once if they loop too.
+    pub fn timeline(&self, frames: &[u32], loops: u32) -> Timeline {
+        let mut time = 0;
+        let mut boot_part = None;
+        let mut parts = Vec::new();
+        for (idx, (part, &frames)) in self.parts.iter().zip(frames).enumerate() {
+            let plays = match (boot_part.is_some(), part.count) {
+                (true, _) if part.kind != PartKind::Complete => {
+                    parts.push(None);
+                    continue;
+                }
+                (true, 0) => 1,
+                (false, 0) => {
+                    boot_part = Some(idx);
+                    loops
+                }
+                (_, count) => count,
+            };
+            let start = time;
+            time += plays as u64 * (frames as u64 + part.pause as u64);
+            parts.push(Some(PartPlay {
+                start,
+                plays,
+                end: time,
+            }));
+        }
+        Timeline {
+            parts,
+            boot_part,
+            end: time,
+        }
+    }
+
+    /// Part directories in order of first use, `$SYSTEM` excluded
+    pub fn part_dirs(&self) -> Vec<&str> {
+        let mut dirs: Vec<&str> = Vec::new();
+        for part in &self.parts {
+            if !part.is_system() && !dirs.contains(&part.path.as_str()) {
+                dirs.push(&part.path);
+            }
+        }
+        dirs
+    }
+}
+
+impl fmt::Display for Desc {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let last = self.parts.len() + 1;
+        let extra_lines_at = |f: &mut fmt::Formatter<'_>, position: usize| -> fmt::Result {
+            for line in &self.extra_lines {
+                // lines past the last part (e.g. after parts were removed) go at the end
+                if line.position.min(last) == position {
+                    writeln!(f, "{}", line.text)?;
+                }
+            }
+            Ok(())
+        };
+
+        extra_lines_at(f, 0)?;
+        writeln!(f, "{}", self.header)?;
+        extra_lines_at(f, 1)?;
+        for (idx, part) in self.parts.iter().enumerate() {
+            writeln!(f, "{}", part)?;
+            extra_lines_at(f, idx + 2)?;
+        }
+        Ok(())
+    }
+}
+
+impl Trim {
+    pub fn parse(line: &str) -> Result<Self> {
+        let parse = || -> Option<Trim> {
+            let (size, pos) = line.trim().split_once('+')?;
+            let (width, height) = size.split_once('x')?;
+            let (x, y) = pos.split_once('+')?;
+            Some(Trim {
+                width: width.parse().ok()?,
+                height: height.parse().ok()?,
+                x: x.parse().ok()?,
+                y: y.parse().ok()?,
+            })
+        };
+        parse().with_context(|| format!("Invalid trim entry: {}", line.trim()))
+    }
+
+    /// Reads a trim.txt, one entry per frame
+    pub fn read_all(path: &Path) -> Result<Vec<Self>> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read {}", path.display()))?;
+        content
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .map(Trim::parse)
+            .collect()
+    }
+}
+
+impl fmt::Display for Trim {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}x{}+{}+{}", self.width, self.height, self.x, self.y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DESC: &str = "# stock animation\n\
+                        1080 2400 60\n\
+                        dynamic_colors part1 #102030 #405060 #708090 #a0b0c0\n\
+                        p 1 0 part0\n\
+                        # loops until boot completes\n\
+                        c 0 5 part1 #000000 c 64\n\
+                        f 1 0 part2 10\n\
+                        # trailing note\n";
+
+    #[test]
+    fn display_round_trips_extra_lines_in_place() {
+        let desc = Desc::parse(DESC).unwrap();
+        assert_eq!(desc.parts.len(), 3);
+        assert_eq!(desc.extra_lines.len(), 4);
+        assert_eq!(desc.to_string(), DESC);
+        assert_eq!(Desc::parse(&desc.to_string()).unwrap(), desc);
+    }
+
+    #[test]
+    fn removing_and_inserting_parts_keeps_lines_next_to_their_parts() {
+        let mut desc = Desc::parse(DESC).unwrap();
+        desc.remove_part(0);
+        assert_eq!(
+            desc.to_string(),
+            "# stock animation\n\
+             1080 2400 60\n\
+             dynamic_colors part1 #102030 #405060 #708090 #a0b0c0\n\
+             # loops until boot completes\n\
+             c 0 5 part1 #000000 c 64\n\
+             f 1 0 part2 10\n\
+             # trailing note\n"
+        );
+
+        desc.insert_part(1, Part::new(PartKind::Interruptible, 1, 0, "part3"));
+        assert_eq!(
+            desc.to_string(),
+            "# stock animation\n\
+             1080 2400 60\n\
+             dynamic_colors part1 #102030 #405060 #708090 #a0b0c0\n\
+             # loops until boot completes\n\
+             c 0 5 part1 #000000 c 64\n\
+             p 1 0 part3\n\
+             f 1 0 part2 10\n\
+             # trailing note\n"
+        );
+    }
+
+    #[test]
+    fn timeline_plays_only_complete_parts_after_boot() {
+        let desc = Desc::parse(
+            "720 1280 30\n\
+             p 1 0 intro\n\
+             p 2 5 logo\n\
+             p 0 0 loop\n\
+             p 1 0 skipped\n\
+             c 1 0 outro\n\
+             c 0 0 ending\n",
+        )
+        .unwrap();
+        let timeline = desc.timeline(&[10, 10, 20, 5, 5, 5], 2);
+
+        let starts: Vec<Option<(u64, u32)>> = timeline
+            .parts
+            .iter()
+            .map(|play| play.map(|play| (play.start, play.plays)))
+            .collect();
+        // every play of logo holds its last frame for 5 more frames
+        assert_eq!(
+            starts,
+            [
+                Some((0, 1)),
+                Some((10, 2)),
+                Some((40, 2)),
+                None,
+                Some((80, 1)),
+                Some((85, 1)),
+            ]
+        );
+        assert_eq!(timeline.boot_part, Some(2));
+        assert_eq!(timeline.parts[2].unwrap().end, 80);
+        assert_eq!(timeline.end, 90);
+    }
+
+    #[test]
+    fn timeline_without_an_endless_part_plays_everything() {
+        let desc = Desc::parse("720 1280 30\np 3 2 part0\nc 1 0 part1\n").unwrap();
+        let timeline = desc.timeline(&[4, 6], 5);
+        assert_eq!(timeline.boot_part, None);
+        assert_eq!(
+            timeline.parts,
+            [
+                Some(PartPlay {
+                    start: 0,
+                    plays: 3,
+                    end: 18
+                }),
+                Some(PartPlay {
+                    start: 18,
+                    plays: 1,
+                    end: 24
+                }),
+            ]
+        );
+        assert_eq!(timeline.end, 24);
+    }
+
+    #[test]
+    fn resized_headers_scale_their_offsets() {
+        let header = Desc::parse("g 1080 2400 40 120 60\np 1 0 part0\n").unwrap().header;
+        let resized = header.resized(540, 1200);
+        assert_eq!((resized.width, resized.height), (540, 1200));
+        assert_eq!((resized.offset_x, resized.offset_y), (20, 60));
+        assert!(resized.is_global_format);
+        assert_eq!(resized.fps, 60);
+    }
+}
+
@@ -0,0 +1,379 @@
+//! Scaling and encoding options shared by everything that writes frames,
+//! and splitting of frames piped out of FFmpeg.
+
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use std::io::{BufRead, Read};
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ImageFormat {
+    Jpg,
+    Png,
+}
+
+/// How a source picture is mapped onto the animation canvas
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum ScaleMode {
+    /// Scale to the exact size, ignoring aspect ratio
+    Stretch,
+    /// Keep aspect ratio and fit inside, padding the borders with the background color
+    Fit,
+    /// Keep aspect ratio and cover the whole canvas, cropping what overflows
+    Fill,
+    /// Keep the original size (only shrinking if too large), centered on the canvas
+    Pad,
+}
+
+/// How frames are made when the output frame rate differs from the source
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default, ValueEnum)]
+pub enum FpsMode {
+    /// Drop or repeat source frames
+    #[default]
+    Drop,
+    /// Mix neighbouring source frames by their distance in time
+    Blend,
+    /// Motion-compensated interpolation (videos only, slow)
+    Interpolate,
+}
+
+/// Encoder settings for output frames, the defaults leave every encoder untouched
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct FrameQuality {
+    /// JPEG quality (1-100), the encoder's default when `None`
+    pub jpeg: Option<u8>,
+}
+
+/// Maps source coordinates onto the canvas: `dst = src * scale + offset`
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Placement {
+    pub scale_x: f64,
+    pub scale_y: f64,
+    pub offset_x: f64,
+    pub offset_y: f64,
+}
+
+impl ImageFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            ImageFormat::Jpg => "jpg",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    /// FFmpeg encoder writing this format
+    pub fn codec(self) -> &'static str {
+        match self {
+            ImageFormat::Jpg => "mjpeg",
+            ImageFormat::Png => "png",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "jpg" | "jpeg" => Some(ImageFormat::Jpg),
+            "png" => Some(ImageFormat::Png),
+            _ => None,
+        }
+    }
+}
+
+impl ScaleMode {
+    /// FFmpeg filter chain producing a `width`x`height` picture
+    pub fn filter(self, width: u32, height: u32, pad_color: Option<&str>) -> String {
+        let color = ffmpeg_color(pad_color);
+        match self {
+            ScaleMode::Stretch => format!("scale={}:{}", width, height),
+            ScaleMode::Fit => format!(
+                "scale={w}:{h}:force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:color={c}",
+                w = width,
+                h = height,
+                c = color
+            ),
+            ScaleMode::Fill => format!(
+                "scale={w}:{h}:force_original_aspect_ratio=increase,crop={w}:{h}",
+                w = width,
+                h = height
+            ),
+            ScaleMode::Pad => format!(
+                "scale='min(iw,{w})':'min(ih,{h})':force_original_aspect_ratio=decrease,pad={w}:{h}:(ow-iw)/2:(oh-ih)/2:color={c}",
+                w = width,
+                h = height,
+                c = color
+            ),
+        }
+    }
+
+    /// Where a `src_width`x`src_height` picture lands on a `width`x`height` canvas
+    pub fn placement(self, src_width: u32, src_height: u32, width: u32, height: u32) -> Placement {
+        let sx = width as f64 / src_width.max(1) as f64;
+        let sy = height as f64 / src_height.max(1) as f64;
+
+        let uniform = match self {
+            ScaleMode::Stretch => {
+                return Placement {
+                    scale_x: sx,
+                    scale_y: sy,
+                    offset_x: 0.0,
+                    offset_y: 0.0,
+                };
+            }
+            ScaleMode::Fit => sx.min(sy),
+            ScaleMode::Fill => sx.max(sy),
+            ScaleMode::Pad => sx.min(sy).min(1.0),
+        };
+
+        Placement {
+            scale_x: uniform,
+            scale_y: uniform,
+            offset_x: (width as f64 - src_width as f64 * uniform) / 2.0,
+            offset_y: (height as f64 - src_height as f64 * uniform) / 2.0,
+        }
+    }
+}
+
+impl FpsMode {
+    /// FFmpeg filter producing constant `fps` frames per second, keeping the duration
+    pub fn filter(self, fps: u32) -> String {
+        match self {
+            FpsMode::Drop => format!("fps={}", fps),
+            FpsMode::Blend => format!("framerate=fps={}", fps),
+            FpsMode::Interpolate => format!("minterpolate=fps={}:mi_mode=mci", fps),
+        }
+    }
+}
+
+/// `#RRGGBB`/`#RGB` to the `0xRRGGBB` form FFmpeg expects, black by default
+pub fn ffmpeg_color(color: Option<&str>) -> String {
+    let hex = color.map(|c| c.trim_start_matches('#')).unwrap_or("000000");
+    if hex.len() == 3 {
+        let expanded: String = hex.chars().flat_map(|c| [c, c]).collect();
+        format!("0x{}", expanded)
+    } else {
+        format!("0x{}", hex)
+    }
+}
+
+/// `#RRGGBB`/`#RGB` to RGB components, black by default
+pub fn rgb_color(color: Option<&str>) -> [u8; 3] {
+    let hex = ffmpeg_color(color);
+    let value = u32::from_str_radix(hex.trim_start_matches("0x"), 16).unwrap_or(0);
+    [(value >> 16) as u8, (value >> 8) as u8, value as u8]
+}
+
+/// JPEG quality (1-100) to the FFmpeg `-q:v` scale (31-2)
+pub fn jpeg_qscale(quality: u8) -> u32 {
+    let quality = quality.clamp(1, 100) as u32;
+    2 + ((100 - quality) * 29 + 49) / 99
+}
+
+const PNG_SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+/// Reads the next picture from an FFmpeg `image2pipe` stream, `None` at the end
+///
+/// Pictures are concatenated without any framing, so the stream is split by
+/// walking the PNG chunks or JPEG markers up to the end of each image.
+pub fn read_piped_frame<R: BufRead>(reader: &mut R, format: ImageFormat) -> Result<Option<Vec<u8>>> {
+    if reader.fill_buf()?.is_empty() {
+        return Ok(None);
+    }
+
+    let mut frame = Vec::new();
+    match format {
+        ImageFormat::Png => read_png(reader, &mut frame)?,
+        ImageFormat::Jpg => read_jpeg(reader, &mut frame)?,
+    }
+    Ok(Some(frame))
+}
+
+fn take<R: Read>(reader: &mut R, frame: &mut Vec<u8>, len: usize) -> Result<()> {
+    let start = frame.len();
+    frame.resize(start + len, 0);
+    reader
+        .read_exact(&mut frame[start..])
+        .context("Truncated frame in FFmpeg output")
+}
+
+fn read_png<R: Read>(reader: &mut R, frame: &mut Vec<u8>) -> Result<()> {
+    take(reader, frame, PNG_SIGNATURE.len())?;
+    if frame[..] != PNG_SIGNATURE {
+        bail!("Unexpected data in FFmpeg PNG stream");
+    }
+
+    // chunks: length, type, data, crc
+    loop {
+        let start = frame.len();
+        take(reader, frame, 8)?;
+        let len = u32::from_be_bytes(frame[start..start + 4].try_into().unwrap()) as usize;
+        let is_end = &frame[start + 4..start + 8] == b"IEND";
+        take(reader, frame, len + 4)?;
+        if is_end {
+            return Ok(());
+        }
+    }
+}
+
+fn read_jpeg<R: BufRead>(reader: &mut R, frame: &mut Vec<u8>) -> Result<()> {
+    take(reader, frame, 2)?;
+    if frame[..] != [0xff, 0xd8] {
+        bail!("Unexpected data in FFmpeg JPEG stream");
+    }
+
+    let mut marker = read_jpeg_marker(reader, frame)?;
+    loop {
+        match marker {
+            // end of image
+            0xd9 => return Ok(()),
+            // markers without a payload
+            0x01 | 0xd0..=0xd7 => marker = read_jpeg_marker(reader, frame)?,
+            _ => {
+                let start = frame.len();
+                take(reader, frame, 2)?;
+                let len = u16::from_be_bytes([frame[start], frame[start + 1]]) as usize;
+                if len < 2 {
+                    bail!("Corrupt JPEG segment in FFmpeg output");
+                }
+                take(reader, frame, len - 2)?;
+
+                // start of scan is followed by entropy coded data up to the next marker
+                marker = if marker == 0xda {
+                    skip_jpeg_scan(reader, frame)?
+                } else {
+                    read_jpeg_marker(reader, frame)?
+                };
+            }
+        }
+    }
+}
+
+fn read_jpeg_marker<R: Read>(reader: &mut R, frame: &mut Vec<u8>) -> Result<u8> {
+    take(reader, frame, 1)?;
+    if frame[frame.len() - 1] != 0xff {
+        bail!("Corrupt JPEG marker in FFmpeg output");
+    }
+    // markers may be preceded by any number of 0xff fill bytes
+    loop {
+        take(reader, frame, 1)?;
+        let byte = frame[frame.len() - 1];
+        if byte != 0xff {
+            return Ok(byte);
+        }
+    }
+}
+
+fn skip_jpeg_scan<R: BufRead>(reader: &mut R, frame: &mut Vec<u8>) -> Result<u8> {
+    // inside scan data 0xff is only a marker when not followed by 0x00 or a restart code
+    let mut prev_ff = false;
+    loop {
+        let buf = reader.fill_buf()?;
+        if buf.is_empty() {
+            bail!("Truncated frame in FFmpeg output");
+        }
+
+        let mut found = None;
+        for (i, &byte) in buf.iter().enumerate() {
+            if prev_ff && byte != 0x00 && byte != 0xff && !(0xd0..=0xd7).contains(&byte) {
+                found = Some((i, byte));
+                break;
+            }
+            prev_ff = byte == 0xff;
+        }
+
+        match found {
+            Some((i, marker)) => {
+                frame.extend_from_slice(&buf[..=i]);
+                reader.consume(i + 1);
+                return Ok(marker);
+            }
+            None => {
+                let len = buf.len();
+                frame.extend_from_slice(buf);
+                reader.consume(len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufReader, Cursor};
+
+    fn encoded(format: image::ImageFormat, width: u32, height: u32, seed: u32) -> Vec<u8> {
+        let mut state = seed;
+        let image = image::RgbImage::from_fn(width, height, |_, _| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let [r, g, b, _] = state.to_be_bytes();
+            image::Rgb([r, g, b])
+        });
+        let mut data = Cursor::new(Vec::new());
+        image.write_to(&mut data, format).unwrap();
+        data.into_inner()
+    }
+
+    /// JPEG whose scan holds stuffed 0xff bytes, restart markers and fill bytes
+    fn jpeg_with_restarts() -> Vec<u8> {
+        let mut data = vec![0xff, 0xd8];
+        // APP0 and restart interval segments
+        data.extend([0xff, 0xe0, 0x00, 0x06, b'J', b'F', b'I', b'F']);
+        data.extend([0xff, 0xdd, 0x00, 0x04, 0x00, 0x01]);
+        // start of scan, then entropy coded data
+        data.extend([0xff, 0xda, 0x00, 0x04, 0x01, 0x00]);
+        data.extend([0x12, 0xff, 0x00, 0x34, 0xff, 0xd0, 0x56, 0xff, 0x00]);
+        data.extend([0xff, 0xd7, 0xff, 0xff, 0x00, 0x78]);
+        // fill bytes before the end of image
+        data.extend([0xff, 0xff, 0xd9]);
+        data
+    }
+
+    /// Splits `stream` into frames, reading it a few bytes at a time
+    fn split(stream: &[u8], format: ImageFormat) -> Result<Vec<Vec<u8>>> {
+        let mut reader = BufReader::with_capacity(3, stream);
+        let mut frames = Vec::new();
+        while let Some(frame) = read_piped_frame(&mut reader, format)? {
+            frames.push(frame);
+        }
+        Ok(frames)
+    }
+
+    #[test]
+    fn png_streams_split_into_frames() {
+        let frames = [
+            encoded(image::ImageFormat::Png, 4, 4, 1),
+            encoded(image::ImageFormat::Png, 7, 3, 2),
+            encoded(image::ImageFormat::Png, 1, 1, 3),
+        ];
+        assert_eq!(split(&frames.concat(), ImageFormat::Png).unwrap(), frames);
+    }
+
+    #[test]
+    fn jpeg_streams_split_at_the_end_of_image() {
+        let frames = [
+            encoded(image::ImageFormat::Jpeg, 64, 64, 1),
+            jpeg_with_restarts(),
+            encoded(image::ImageFormat::Jpeg, 9, 5, 2),
+        ];
+        // noise makes the encoder stuff 0xff bytes into the scan
+        assert!(frames[0].windows(2).any(|w| w == [0xff, 0x00]));
+        assert_eq!(split(&frames.concat(), ImageFormat::Jpg).unwrap(), frames);
+    }
+
+    #[test]
+    fn truncated_last_frames_are_errors() {
+        let png = encoded(image::ImageFormat::Png, 4, 4, 1);
+        let stream = [png.clone(), png[..png.len() - 5].to_vec()].concat();
+        assert!(split(&stream, ImageFormat::Png).is_err());
+
+        let jpeg = jpeg_with_restarts();
+        let stream = [jpeg.clone(), jpeg[..jpeg.len() - 3].to_vec()].concat();
+        assert!(split(&stream, ImageFormat::Jpg).is_err());
+        // cut inside the scan data
+        assert!(split(&jpeg[..26], ImageFormat::Jpg).is_err());
+    }
+
+    #[test]
+    fn empty_streams_have_no_frames() {
+        assert!(split(&[], ImageFormat::Png).unwrap().is_empty());
+        assert!(split(&[], ImageFormat::Jpg).unwrap().is_empty());
+        assert!(split(b"GIF89a", ImageFormat::Png).is_err());
+    }
+}
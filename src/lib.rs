@@ -0,0 +1,13 @@
+//! Shared building blocks for the bootanimation tools.
+
+pub mod analysis;
+pub mod animation;
+pub mod archive;
+pub mod desc;
+pub mod frames;
+pub mod jobs;
+pub mod media;
+pub mod memory;
+pub mod optimize;
+pub mod sequence;
+pub mod sheet;
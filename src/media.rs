@@ -0,0 +1,1035 @@
+//! Media backends: probing, decoding and encoding behind one trait.
+//!
+//! `FfmpegCli` drives the `ffmpeg`/`ffprobe` executables, `FakeBackend` produces
+//! deterministic output without any external tool.
+
+use crate::animation::encode_frame;
+use crate::frames::{FpsMode, FrameQuality, ImageFormat, ScaleMode, jpeg_qscale, read_piped_frame};
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use image::{Rgb, RgbImage};
+use std::env;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Properties of an input video
+#[derive(Debug, Clone, PartialEq)]
+pub struct MediaInfo {
+    pub width: u32,
+    pub height: u32,
+    /// Rounded frame rate
+    pub fps: u32,
+    /// Exact frame rate, the average one for variable frame rate videos
+    pub frame_rate: f64,
+    /// Frames are not evenly spaced, `frame_rate` is only their average
+    pub variable_frame_rate: bool,
+    pub duration: f64,
+    pub has_audio: bool,
+}
+
+/// Video frames to decode, scaled and encoded as bootanimation frames
+#[derive(Debug, Clone)]
+pub struct FrameRequest<'a> {
+    pub width: u32,
+    pub height: u32,
+    pub scale_mode: ScaleMode,
+    pub background: Option<&'a str>,
+    pub format: ImageFormat,
+    pub quality: FrameQuality,
+    /// Output frame rate, frames are decoded at the source rate when `None`
+    pub fps: Option<u32>,
+    /// How frames are converted to `fps`
+    pub fps_mode: FpsMode,
+    /// First output frame to decode, after any frame rate conversion
+    pub start: u32,
+    /// Number of frames to decode, all remaining when `None`
+    pub count: Option<u32>,
+    /// Rate of the output frames
+    pub frame_rate: f64,
+}
+
+/// Seconds decoded before a slice, rate conversion looks at the frames around its first one
+const SEEK_MARGIN: f64 = 1.0;
+
+impl FrameRequest<'_> {
+    /// Input position to seek to before decoding the slice
+    ///
+    /// Seeking stops every slice from decoding the video from its start.
+    fn seek(&self) -> f64 {
+        (self.start as f64 / self.frame_rate - SEEK_MARGIN).max(0.0)
+    }
+
+    /// FFmpeg filter chain converting the rate, cutting the slice and scaling
+    ///
+    /// Slices are cut half a frame around their output frame times after the rate
+    /// conversion, with timestamps kept from the start of the input across the
+    /// seek, so they hold exactly the frames a single run numbers the same.
+    fn filter(&self) -> String {
+        let mut filters = Vec::new();
+        if let Some(fps) = self.fps {
+            filters.push(self.fps_mode.filter(fps));
+        }
+        let frame_time = |frame: u32| (frame as f64 - 0.5) / self.frame_rate;
+        let mut trim = Vec::new();
+        if self.start > 0 {
+            trim.push(format!("start={}", frame_time(self.start)));
+        }
+        if let Some(count) = self.count {
+            trim.push(format!("end={}", frame_time(self.start + count)));
+        }
+        if !trim.is_empty() {
+            filters.push(format!("trim={}", trim.join(":")));
+        }
+        filters.push(self.scale_mode.filter(self.width, self.height, self.background));
+        filters.join(",")
+    }
+}
+
+/// Container and codecs of an encoded video
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum VideoFormat {
+    /// H.264 and AAC in MP4
+    Mp4,
+    /// VP9 and Opus in WebM
+    WebmVp9,
+    /// AV1 and Opus in WebM
+    WebmAv1,
+    /// Animated GIF with a palette generated from all frames
+    Gif,
+    /// Animated PNG
+    Apng,
+    /// Animated WebP
+    Webp,
+    /// Lossless FFV1 and FLAC in Matroska
+    Mkv,
+}
+
+impl VideoFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            VideoFormat::Mp4 => "mp4",
+            VideoFormat::WebmVp9 | VideoFormat::WebmAv1 => "webm",
+            VideoFormat::Gif => "gif",
+            VideoFormat::Apng => "apng",
+            VideoFormat::Webp => "webp",
+            VideoFormat::Mkv => "mkv",
+        }
+    }
+
+    /// Whether the container can hold a soundtrack
+    pub fn has_audio(self) -> bool {
+        matches!(
+            self,
+            VideoFormat::Mp4 | VideoFormat::WebmVp9 | VideoFormat::WebmAv1 | VideoFormat::Mkv
+        )
+    }
+
+    /// Whether the encoder takes a constant rate factor
+    pub fn has_crf(self) -> bool {
+        matches!(
+            self,
+            VideoFormat::Mp4 | VideoFormat::WebmVp9 | VideoFormat::WebmAv1
+        )
+    }
+
+    fn default_codec(self) -> Option<&'static str> {
+        match self {
+            VideoFormat::Mp4 => Some("libx264"),
+            VideoFormat::WebmVp9 => Some("libvpx-vp9"),
+            VideoFormat::WebmAv1 => Some("libaom-av1"),
+            VideoFormat::Gif => None,
+            VideoFormat::Apng => Some("apng"),
+            VideoFormat::Webp => Some("libwebp"),
+            VideoFormat::Mkv => Some("ffv1"),
+        }
+    }
+
+    /// Pixel format every player handles, FFV1 keeps the one of the frames
+    fn default_pix_fmt(self) -> Option<&'static str> {
+        match self {
+            VideoFormat::Mp4 | VideoFormat::WebmVp9 | VideoFormat::WebmAv1 => Some("yuv420p"),
+            _ => None,
+        }
+    }
+
+    fn default_crf(self) -> Option<u32> {
+        match self {
+            VideoFormat::WebmVp9 | VideoFormat::WebmAv1 => Some(32),
+            _ => None,
+        }
+    }
+
+    /// Remaining FFmpeg output options of the format
+    fn extra_args(self) -> &'static [&'static str] {
+        match self {
+            // constant quality rather than a bitrate target
+            VideoFormat::WebmVp9 => &["-b:v", "0"],
+            VideoFormat::WebmAv1 => &["-b:v", "0", "-cpu-used", "6", "-row-mt", "1"],
+            VideoFormat::Gif => &["-loop", "0"],
+            VideoFormat::Apng => &["-plays", "0", "-f", "apng"],
+            VideoFormat::Webp => &["-quality", "80", "-loop", "0"],
+            VideoFormat::Mkv => &["-level", "3"],
+            VideoFormat::Mp4 => &[],
+        }
+    }
+
+    fn audio_codec(self) -> &'static str {
+        match self {
+            VideoFormat::WebmVp9 | VideoFormat::WebmAv1 => "libopus",
+            VideoFormat::Mkv => "flac",
+            _ => "aac",
+        }
+    }
+}
+
+/// Encoder settings overriding the defaults of a `VideoFormat`
+#[derive(Debug, Clone, Default)]
+pub struct EncoderOptions {
+    pub codec: Option<String>,
+    pub crf: Option<u32>,
+    pub preset: Option<String>,
+    pub pix_fmt: Option<String>,
+    /// Passed to FFmpeg as they are, right before the output file
+    pub extra_args: Vec<String>,
+}
+
+/// Video to encode from numbered frames (`00001.png`, `00002.png`...) in `frames_dir`
+#[derive(Debug, Clone)]
+pub struct EncodeRequest<'a> {
+    pub frames_dir: &'a Path,
+    pub extension: &'a str,
+    pub start_frame: u32,
+    /// Number of frames to encode, all remaining when `None`
+    pub frame_count: Option<u32>,
+    pub fps: u32,
+    pub width: u32,
+    pub height: u32,
+    pub format: VideoFormat,
+    pub encoder: &'a EncoderOptions,
+    pub output: &'a Path,
+}
+
+/// Container and codec of an exported soundtrack
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Mp3,
+}
+
+impl AudioFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "wav",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Mp3 => "mp3",
+        }
+    }
+
+    pub fn from_extension(ext: &str) -> Option<Self> {
+        match ext.to_lowercase().as_str() {
+            "wav" => Some(AudioFormat::Wav),
+            "flac" => Some(AudioFormat::Flac),
+            "mp3" => Some(AudioFormat::Mp3),
+            _ => None,
+        }
+    }
+
+    fn codec(self) -> &'static str {
+        match self {
+            AudioFormat::Wav => "pcm_s16le",
+            AudioFormat::Flac => "flac",
+            AudioFormat::Mp3 => "libmp3lame",
+        }
+    }
+}
+
+/// Sound starting `start` seconds into a video
+#[derive(Debug, Clone)]
+pub struct AudioClip<'a> {
+    pub path: &'a Path,
+    pub start: f64,
+}
+
+pub trait MediaBackend: Sync {
+    fn probe(&self, input: &Path) -> Result<MediaInfo>;
+
+    /// Decodes frames in order, passing each encoded frame to `sink`, returns the frame count
+    fn decode_frames(
+        &self,
+        input: &Path,
+        request: &FrameRequest,
+        sink: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<u32>;
+
+    /// Runs the video filter `filter` over still frames, writing them to
+    /// `output_dir` as `000001.<ext>` onwards in `format`
+    fn filter_frames(
+        &self,
+        frames: &[PathBuf],
+        filter: &str,
+        format: ImageFormat,
+        quality: FrameQuality,
+        output_dir: &Path,
+        work_dir: &Path,
+    ) -> Result<()>;
+
+    /// Writes `duration` seconds of audio from `start` as 44.1 kHz stereo 16-bit WAV
+    fn decode_audio(&self, input: &Path, start: f64, duration: f64, output: &Path) -> Result<()>;
+
+    fn encode_video(&self, request: &EncodeRequest) -> Result<()>;
+
+    /// Adds a soundtrack mixed from `clips` to `video`, copying the video stream
+    ///
+    /// Every clip plays to its end from its start, over later clips and past the end
+    /// of the video if it has to; silence fills the rest of at least `duration` seconds.
+    fn add_audio(
+        &self,
+        video: &Path,
+        clips: &[AudioClip],
+        duration: f64,
+        format: VideoFormat,
+        output: &Path,
+    ) -> Result<()>;
+
+    /// Writes the soundtrack `add_audio` would add, without a video
+    fn mix_audio(
+        &self,
+        clips: &[AudioClip],
+        duration: f64,
+        format: AudioFormat,
+        output: &Path,
+    ) -> Result<()>;
+}
+
+/// The `ffmpeg` and `ffprobe` executables, from `FFMPEG_PATH`/`FFPROBE_PATH` or `PATH`
+pub struct FfmpegCli {
+    pub ffmpeg: PathBuf,
+    pub ffprobe: PathBuf,
+}
+
+impl FfmpegCli {
+    pub fn from_env() -> Self {
+        FfmpegCli {
+            ffmpeg: env::var_os("FFMPEG_PATH").map_or_else(|| "ffmpeg".into(), PathBuf::from),
+            ffprobe: env::var_os("FFPROBE_PATH").map_or_else(|| "ffprobe".into(), PathBuf::from),
+        }
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(&self.ffmpeg);
+        command.arg("-hide_banner");
+        command
+    }
+
+    fn probe_entry(&self, input: &Path, stream: Option<&str>, entry: &str) -> Result<String> {
+        let mut command = Command::new(&self.ffprobe);
+        command.args(["-hide_banner", "-v", "error"]);
+        if let Some(stream) = stream {
+            command.args(["-select_streams", stream]);
+        }
+        let output = command
+            .args(["-show_entries", entry, "-of", "csv=p=0"])
+            .arg(input)
+            .output()
+            .with_context(|| format!("Failed to run ffprobe for {}", entry))?;
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+}
+
+impl MediaBackend for FfmpegCli {
+    fn probe(&self, input: &Path) -> Result<MediaInfo> {
+        let width: u32 = self
+            .probe_entry(input, Some("v:0"), "stream=width")?
+            .parse()
+            .context("Failed to parse width")?;
+
+        let height: u32 = self
+            .probe_entry(input, Some("v:0"), "stream=height")?
+            .parse()
+            .context("Failed to parse height")?;
+
+        let base_rate = parse_rate(&self.probe_entry(input, Some("v:0"), "stream=r_frame_rate")?)?;
+        // phone recordings report their highest rate, the average gives them away
+        let average_rate = self
+            .probe_entry(input, Some("v:0"), "stream=avg_frame_rate")
+            .and_then(|rate| parse_rate(&rate))
+            .unwrap_or(base_rate);
+        let variable_frame_rate = average_rate > 0.0 && (average_rate - base_rate).abs() > 0.01;
+        let frame_rate = if variable_frame_rate { average_rate } else { base_rate };
+
+        let duration: f64 = self
+            .probe_entry(input, None, "format=duration")?
+            .parse()
+            .context("Failed to parse duration")?;
+
+        let has_audio = !self
+            .probe_entry(input, Some("a"), "stream=codec_type")?
+            .is_empty();
+
+        Ok(MediaInfo {
+            width,
+            height,
+            fps: frame_rate.round() as u32,
+            frame_rate,
+            variable_frame_rate,
+            duration,
+            has_audio,
+        })
+    }
+
+    fn decode_frames(
+        &self,
+        input: &Path,
+        request: &FrameRequest,
+        sink: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<u32> {
+        let mut command = self.command();
+        command.arg("-nostdin");
+
+        // slices run side by side, keep their output short
+        if request.count.is_some() {
+            command.args(["-v", "error"]);
+        }
+
+        if request.count.is_some() {
+            // keep timestamps from the start of the input so the trim matches a single run
+            command.args(["-ss", &request.seek().to_string(), "-copyts", "-start_at_zero"]);
+        }
+        command.arg("-i").arg(input);
+
+        let filter = request.filter();
+        if let Some(count) = request.count {
+            // stop decoding once the slice is complete
+            command.args(["-frames:v", &count.to_string()]);
+        }
+        command.args(["-vf", &filter, "-f", "image2pipe", "-c:v", request.format.codec()]);
+        if let (ImageFormat::Jpg, Some(quality)) = (request.format, request.quality.jpeg) {
+            command.args(["-q:v", &jpeg_qscale(quality).to_string()]);
+        }
+
+        let mut child = command
+            .arg("-")
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to extract frames")?;
+
+        let mut reader = BufReader::with_capacity(1 << 20, child.stdout.take().unwrap());
+        let mut read_frames = || -> Result<u32> {
+            let mut count = 0;
+            while let Some(frame) = read_piped_frame(&mut reader, request.format)? {
+                sink(&frame)?;
+                count += 1;
+            }
+            Ok(count)
+        };
+
+        let count = match read_frames() {
+            Ok(count) => count,
+            Err(e) => {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(e);
+            }
+        };
+
+        if !child.wait()?.success() {
+            bail!("FFmpeg failed to extract frames");
+        }
+
+        Ok(count)
+    }
+
+    fn filter_frames(
+        &self,
+        frames: &[PathBuf],
+        filter: &str,
+        format: ImageFormat,
+        quality: FrameQuality,
+        output_dir: &Path,
+        work_dir: &Path,
+    ) -> Result<()> {
+        let Some(first) = frames.first() else {
+            return Ok(());
+        };
+
+        // FFmpeg reads still frames as one numbered sequence
+        let seq_dir = work_dir.join("sequence");
+        if seq_dir.exists() {
+            fs::remove_dir_all(&seq_dir)?;
+        }
+        fs::create_dir_all(&seq_dir)?;
+        let src_ext = first
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        for (idx, frame) in frames.iter().enumerate() {
+            fs::copy(frame, seq_dir.join(format!("{:06}.{}", idx + 1, src_ext)))?;
+        }
+
+        let mut command = self.command();
+        command
+            .args(["-nostdin", "-y", "-v", "error", "-i"])
+            .arg(seq_dir.join(format!("%06d.{}", src_ext)))
+            .args(["-vf", filter]);
+        if let (ImageFormat::Jpg, Some(q)) = (format, quality.jpeg) {
+            command.args(["-q:v", &jpeg_qscale(q).to_string()]);
+        }
+        let status = command
+            .arg(output_dir.join(format!("%06d.{}", format.extension())))
+            .status()
+            .context("Failed to run FFmpeg")?;
+
+        if !status.success() {
+            bail!("FFmpeg failed to convert frames");
+        }
+
+        Ok(())
+    }
+
+    fn decode_audio(&self, input: &Path, start: f64, duration: f64, output: &Path) -> Result<()> {
+        let status = self
+            .command()
+            .args(["-nostdin", "-y", "-i"])
+            .arg(input)
+            .args(["-ss", &start.to_string(), "-t", &duration.to_string()])
+            .args(["-vn", "-acodec", "pcm_s16le", "-ar", "44100", "-ac", "2"])
+            .arg(output)
+            .status()
+            .context("Failed to extract audio")?;
+
+        if !status.success() {
+            bail!("FFmpeg failed to extract audio");
+        }
+
+        Ok(())
+    }
+
+    fn encode_video(&self, request: &EncodeRequest) -> Result<()> {
+        let pattern = request
+            .frames_dir
+            .join(format!("%05d.{}", request.extension));
+
+        let mut command = self.command();
+        command
+            .args(["-nostdin", "-y"])
+            .args(["-start_number", &request.start_frame.to_string()])
+            .args(["-framerate", &request.fps.to_string()])
+            .arg("-i")
+            .arg(pattern);
+
+        if let Some(count) = request.frame_count {
+            command.args(["-frames:v", &count.to_string()]);
+        }
+
+        let format = request.format;
+        let encoder = request.encoder;
+        if let Some(codec) = encoder.codec.as_deref().or(format.default_codec()) {
+            command.args(["-c:v", codec]);
+        }
+        if let Some(pix_fmt) = encoder.pix_fmt.as_deref().or(format.default_pix_fmt()) {
+            command.args(["-pix_fmt", pix_fmt]);
+        }
+        if let Some(crf) = encoder.crf.or(format.default_crf()) {
+            command.args(["-crf", &crf.to_string()]);
+        }
+        if let Some(preset) = &encoder.preset {
+            command.args(["-preset", preset]);
+        }
+        command.args(format.extra_args());
+        if request.format == VideoFormat::Gif {
+            // one palette for the whole animation, used without frame to frame flicker
+            command.args([
+                "-vf",
+                &format!(
+                    "scale={}:{}:flags=lanczos,split[a][b];[a]palettegen=stats_mode=diff[p];[b][p]paletteuse=dither=sierra2_4a",
+                    request.width, request.height
+                ),
+            ]);
+        } else {
+            command.args(["-s", &format!("{}x{}", request.width, request.height)]);
+        }
+        command.args(&encoder.extra_args);
+
+        let status = command
+            .arg(request.output)
+            .status()
+            .context("Failed to run FFmpeg")?;
+
+        if !status.success() {
+            bail!("FFmpeg failed to generate video");
+        }
+
+        Ok(())
+    }
+
+    fn add_audio(
+        &self,
+        video: &Path,
+        clips: &[AudioClip],
+        duration: f64,
+        format: VideoFormat,
+        output: &Path,
+    ) -> Result<()> {
+        if clips.is_empty() {
+            bail!("No audio to add");
+        }
+
+        let mut command = self.command();
+        command.args(["-nostdin", "-y", "-i"]).arg(video);
+        for clip in clips {
+            command.arg("-i").arg(clip.path);
+        }
+
+        let status = command
+            .args(["-filter_complex", &mix_filter(clips, 1, duration)])
+            .args(["-map", "0:v", "-map", "[audio]"])
+            .args(["-c:v", "copy", "-c:a", format.audio_codec()])
+            .arg(output)
+            .status()
+            .context("Failed to run FFmpeg")?;
+
+        if !status.success() {
+            bail!("FFmpeg failed to add audio");
+        }
+
+        Ok(())
+    }
+
+    fn mix_audio(
+        &self,
+        clips: &[AudioClip],
+        duration: f64,
+        format: AudioFormat,
+        output: &Path,
+    ) -> Result<()> {
+        if clips.is_empty() {
+            bail!("No audio to mix");
+        }
+
+        let mut command = self.command();
+        command.args(["-nostdin", "-y"]);
+        for clip in clips {
+            command.arg("-i").arg(clip.path);
+        }
+
+        let status = command
+            .args(["-filter_complex", &mix_filter(clips, 0, duration)])
+            .args(["-map", "[audio]", "-c:a", format.codec()])
+            .arg(output)
+            .status()
+            .context("Failed to run FFmpeg")?;
+
+        if !status.success() {
+            bail!("FFmpeg failed to mix audio");
+        }
+
+        Ok(())
+    }
+}
+
+/// Deterministic backend for tests on machines without FFmpeg
+///
+/// Frames are solid colors derived from their index, audio is silence and
+/// "videos" are text files describing what would have been encoded.
+pub struct FakeBackend {
+    pub info: MediaInfo,
+    /// Frames in the fake input at `info.frame_rate`
+    pub frame_count: u32,
+}
+
+impl FakeBackend {
+    pub fn new(width: u32, height: u32, fps: u32, frame_count: u32, has_audio: bool) -> Self {
+        FakeBackend {
+            info: MediaInfo {
+                width,
+                height,
+                fps,
+                frame_rate: fps as f64,
+                variable_frame_rate: false,
+                duration: frame_count as f64 / fps.max(1) as f64,
+                has_audio,
+            },
+            frame_count,
+        }
+    }
+
+    /// Color of frame `idx`
+    pub fn frame_color(idx: u32) -> [u8; 3] {
+        [
+            (idx * 37 % 256) as u8,
+            (idx * 91 % 256) as u8,
+            (idx * 13 % 256) as u8,
+        ]
+    }
+}
+
+impl MediaBackend for FakeBackend {
+    fn probe(&self, _input: &Path) -> Result<MediaInfo> {
+        Ok(self.info.clone())
+    }
+
+    fn decode_frames(
+        &self,
+        _input: &Path,
+        request: &FrameRequest,
+        sink: &mut dyn FnMut(&[u8]) -> Result<()>,
+    ) -> Result<u32> {
+        // output frame `idx` shows the source frame playing at its time
+        let source_frame = |idx: u32| match request.fps {
+            Some(fps) => (idx as f64 * self.info.frame_rate / fps.max(1) as f64) as u32,
+            None => idx,
+        };
+        let total = match request.fps {
+            Some(fps) => (self.info.duration * fps as f64).round() as u32,
+            None => self.frame_count,
+        };
+        let end = match request.count {
+            Some(count) => (request.start + count).min(total),
+            None => total,
+        };
+
+        // like FFmpeg, frames before the seek point are never decoded
+        let first_decoded = (request.seek() * self.info.frame_rate).ceil() as u32;
+        for idx in request.start..end {
+            if source_frame(idx) < first_decoded {
+                bail!("Frame {} is before the seek point", idx);
+            }
+            let color = Self::frame_color(source_frame(idx));
+            let image = RgbImage::from_pixel(request.width, request.height, Rgb(color));
+            sink(&encode_frame(&image, request.format, request.quality)?)?;
+        }
+
+        Ok(end.saturating_sub(request.start))
+    }
+
+    fn filter_frames(
+        &self,
+        frames: &[PathBuf],
+        _filter: &str,
+        format: ImageFormat,
+        quality: FrameQuality,
+        output_dir: &Path,
+        _work_dir: &Path,
+    ) -> Result<()> {
+        // frames keep their size, only the format changes
+        for (idx, frame) in frames.iter().enumerate() {
+            let image = image::open(frame)
+                .with_context(|| format!("Failed to decode {}", frame.display()))?
+                .into_rgb8();
+            fs::write(
+                output_dir.join(format!("{:06}.{}", idx + 1, format.extension())),
+                encode_frame(&image, format, quality)?,
+            )?;
+        }
+        Ok(())
+    }
+
+    fn decode_audio(&self, _input: &Path, _start: f64, duration: f64, output: &Path) -> Result<()> {
+        if !self.info.has_audio {
+            bail!("Fake input has no audio");
+        }
+        write_silent_wav(output, duration)
+    }
+
+    fn encode_video(&self, request: &EncodeRequest) -> Result<()> {
+        let available = (request.start_frame..)
+            .take_while(|n| {
+                request
+                    .frames_dir
+                    .join(format!("{:05}.{}", n, request.extension))
+                    .exists()
+            })
+            .count() as u32;
+        let frames = request.frame_count.map_or(available, |c| c.min(available));
+
+        fs::write(
+            request.output,
+            format!(
+                "frames {}+{} fps {} size {}x{} format {:?}\n",
+                request.start_frame,
+                frames,
+                request.fps,
+                request.width,
+                request.height,
+                request.format
+            ),
+        )?;
+        Ok(())
+    }
+
+    fn add_audio(
+        &self,
+        video: &Path,
+        clips: &[AudioClip],
+        duration: f64,
+        _format: VideoFormat,
+        output: &Path,
+    ) -> Result<()> {
+        let mut content = fs::read(video)?;
+        for clip in clips {
+            let name = clip.path.file_name().unwrap_or_default().to_string_lossy();
+            content.extend(format!("audio {} at {:.3}\n", name, clip.start).into_bytes());
+        }
+        content.extend(format!("audio padded to {:.3}\n", duration).into_bytes());
+        fs::write(output, content)?;
+        Ok(())
+    }
+
+    fn mix_audio(
+        &self,
+        clips: &[AudioClip],
+        duration: f64,
+        format: AudioFormat,
+        output: &Path,
+    ) -> Result<()> {
+        let mut content = format!("{:?}\n", format).into_bytes();
+        for clip in clips {
+            let name = clip.path.file_name().unwrap_or_default().to_string_lossy();
+            content.extend(format!("audio {} at {:.3}\n", name, clip.start).into_bytes());
+        }
+        content.extend(format!("audio padded to {:.3}\n", duration).into_bytes());
+        fs::write(output, content)?;
+        Ok(())
+    }
+}
+
+/// FFmpeg filter mixing `clips`, read from inputs `first_input` on, into `[audio]`
+///
+/// Clips are brought to the same rate and layout and delayed to their start.
+fn mix_filter(clips: &[AudioClip], first_input: usize, duration: f64) -> String {
+    let mut filter = String::new();
+    for (idx, clip) in clips.iter().enumerate() {
+        filter += &format!(
+            "[{}:a]aresample=48000,aformat=channel_layouts=stereo,adelay={}:all=1[a{}];",
+            first_input + idx,
+            (clip.start * 1000.0).round() as u64,
+            idx
+        );
+    }
+    let inputs: String = (0..clips.len()).map(|idx| format!("[a{}]", idx)).collect();
+    filter += &format!(
+        "{}amix=inputs={}:duration=longest:normalize=0,apad=whole_dur={:.3}[audio]",
+        inputs,
+        clips.len(),
+        duration
+    );
+    filter
+}
+
+/// ffprobe rate (`30000/1001` or `25`) to frames per second, 0 when unknown
+fn parse_rate(rate: &str) -> Result<f64> {
+    Ok(match rate.split_once('/') {
+        Some((numerator, denominator)) => {
+            let numerator: f64 = numerator.parse().context("Failed to parse fps numerator")?;
+            let denominator: f64 = denominator
+                .parse()
+                .context("Failed to parse fps denominator")?;
+            if denominator == 0.0 { 0.0 } else { numerator / denominator }
+        }
+        None => rate.parse().context("Failed to parse fps")?,
+    })
+}
+
+/// Writes `duration` seconds of 44.1 kHz stereo 16-bit silence
+pub fn write_silent_wav(path: &Path, duration: f64) -> Result<()> {
+    let samples = (duration.max(0.0) * 44100.0).round() as u32;
+    let data_len = samples * 4;
+
+    let mut out = BufWriter::new(File::create(path)?);
+    out.write_all(b"RIFF")?;
+    out.write_all(&(36 + data_len).to_le_bytes())?;
+    out.write_all(b"WAVEfmt ")?;
+    out.write_all(&16u32.to_le_bytes())?;
+    // PCM, 2 channels, 44100 Hz, byte rate, block align, 16 bits
+    out.write_all(&1u16.to_le_bytes())?;
+    out.write_all(&2u16.to_le_bytes())?;
+    out.write_all(&44100u32.to_le_bytes())?;
+    out.write_all(&(44100u32 * 4).to_le_bytes())?;
+    out.write_all(&4u16.to_le_bytes())?;
+    out.write_all(&16u16.to_le_bytes())?;
+    out.write_all(b"data")?;
+    out.write_all(&data_len.to_le_bytes())?;
+    out.write_all(&vec![0u8; data_len as usize])?;
+    out.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(fps: Option<u32>) -> FrameRequest<'static> {
+        FrameRequest {
+            width: 64,
+            height: 48,
+            scale_mode: ScaleMode::Stretch,
+            background: None,
+            format: ImageFormat::Png,
+            quality: FrameQuality::default(),
+            fps,
+            fps_mode: FpsMode::Drop,
+            start: 0,
+            count: None,
+            frame_rate: fps.unwrap_or(25) as f64,
+        }
+    }
+
+    #[test]
+    fn slices_seek_ahead_and_are_cut_by_output_frame_time() {
+        assert_eq!(request(None).filter(), "scale=64:48");
+        assert_eq!(request(Some(30)).filter(), "fps=30,scale=64:48");
+
+        // frames 45 to 64 at 30 fps are the ones timed from 1.5 to 2.15 s
+        let slice = FrameRequest {
+            start: 45,
+            count: Some(20),
+            ..request(Some(30))
+        };
+        assert_eq!(slice.seek(), 0.5);
+        assert_eq!(
+            slice.filter(),
+            format!("fps=30,trim=start={}:end={},scale=64:48", 44.5 / 30.0, 64.5 / 30.0)
+        );
+
+        let first = FrameRequest {
+            count: Some(20),
+            ..request(Some(30))
+        };
+        assert_eq!(first.seek(), 0.0);
+        assert_eq!(
+            first.filter(),
+            format!("fps=30,trim=end={},scale=64:48", 19.5 / 30.0)
+        );
+    }
+
+    fn decoded(backend: &FakeBackend, request: &FrameRequest) -> Vec<[u8; 3]> {
+        let mut colors = Vec::new();
+        let count = backend
+            .decode_frames(Path::new("in.mp4"), request, &mut |frame| {
+                let image = image::load_from_memory(frame)?.into_rgb8();
+                assert_eq!(image.dimensions(), (64, 48));
+                colors.push(image.get_pixel(0, 0).0);
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(count as usize, colors.len());
+        colors
+    }
+
+    #[test]
+    fn fake_probe_describes_the_fake_input() {
+        let info = FakeBackend::new(320, 240, 25, 50, true)
+            .probe(Path::new("in.mp4"))
+            .unwrap();
+        assert_eq!((info.width, info.height, info.fps), (320, 240, 25));
+        assert_eq!(info.duration, 2.0);
+        assert!(info.has_audio && !info.variable_frame_rate);
+    }
+
+    #[test]
+    fn fake_decoding_converts_the_rate_and_cuts_slices() {
+        let backend = FakeBackend::new(320, 240, 25, 10, false);
+        let colors = |sources: &[u32]| -> Vec<[u8; 3]> {
+            sources.iter().map(|&i| FakeBackend::frame_color(i)).collect()
+        };
+
+        assert_eq!(decoded(&backend, &request(None)), colors(&[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]));
+        // 0.4 s at 30 fps, every output frame shows the source frame playing at its time
+        assert_eq!(
+            decoded(&backend, &request(Some(30))),
+            colors(&[0, 0, 1, 2, 3, 4, 5, 5, 6, 7, 8, 9])
+        );
+
+        let slice = FrameRequest {
+            start: 8,
+            count: Some(6),
+            ..request(Some(30))
+        };
+        assert_eq!(decoded(&backend, &slice), colors(&[6, 7, 8, 9]));
+
+        // a slice seconds into the video is decoded from just before it
+        let backend = FakeBackend::new(320, 240, 25, 100, false);
+        let slice = FrameRequest {
+            start: 90,
+            count: Some(3),
+            ..request(Some(30))
+        };
+        assert_eq!(slice.seek(), 2.0);
+        assert_eq!(decoded(&backend, &slice), colors(&[75, 75, 76]));
+    }
+
+    #[test]
+    fn fake_audio_has_the_requested_duration() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let output = temp.path().join("audio.wav");
+        FakeBackend::new(320, 240, 25, 50, true)
+            .decode_audio(Path::new("in.mp4"), 0.5, 1.25, &output)
+            .unwrap();
+        // 44 byte header, then 44100 stereo 16-bit samples per second
+        assert_eq!(fs::metadata(&output).unwrap().len(), 44 + 55125 * 4);
+
+        let silent = FakeBackend::new(320, 240, 25, 50, false);
+        assert!(silent.decode_audio(Path::new("in.mp4"), 0.0, 1.0, &output).is_err());
+    }
+
+    #[test]
+    fn fake_encoding_and_audio_describe_their_input() {
+        let temp = tempfile::TempDir::new().unwrap();
+        let frames_dir = temp.path().join("frames");
+        fs::create_dir_all(&frames_dir).unwrap();
+        for idx in 1..=7 {
+            fs::write(frames_dir.join(format!("{:05}.png", idx)), "frame").unwrap();
+        }
+
+        let backend = FakeBackend::new(320, 240, 25, 50, true);
+        let encoder = EncoderOptions::default();
+        let encode = |start_frame: u32, frame_count: Option<u32>, name: &str| -> PathBuf {
+            let output = temp.path().join(name);
+            backend
+                .encode_video(&EncodeRequest {
+                    frames_dir: &frames_dir,
+                    extension: "png",
+                    start_frame,
+                    frame_count,
+                    fps: 10,
+                    width: 32,
+                    height: 24,
+                    format: VideoFormat::Mp4,
+                    encoder: &encoder,
+                    output: &output,
+                })
+                .unwrap();
+            output
+        };
+
+        let first = encode(1, Some(3), "first.mp4");
+        let rest = encode(4, None, "rest.mp4");
+        assert_eq!(
+            fs::read_to_string(&rest).unwrap(),
+            "frames 4+4 fps 10 size 32x24 format Mp4\n"
+        );
+
+        let clip = temp.path().join("audio.wav");
+        let with_audio = temp.path().join("with_audio.mp4");
+        backend
+            .add_audio(
+                &first,
+                &[AudioClip { path: &clip, start: 0.3 }],
+                0.7,
+                VideoFormat::Mp4,
+                &with_audio,
+            )
+            .unwrap();
+        assert_eq!(
+            fs::read_to_string(&with_audio).unwrap(),
+            "frames 1+3 fps 10 size 32x24 format Mp4\n\
+             audio audio.wav at 0.300\n\
+             audio padded to 0.700\n"
+        );
+    }
+}
+
@@ -0,0 +1,210 @@
+//! Decoded memory of bootanimation parts, checked against warning and failure limits.
+
+use crate::desc::Header;
+
+/// Part memory limits in megabytes, `None` never triggers
+#[derive(Debug, Copy, Clone, PartialEq, Default)]
+pub struct MemoryLimits {
+    pub warn: Option<f64>,
+    pub fail: Option<f64>,
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum Severity {
+    /// Over the warning limit, low-RAM devices may not cope
+    Warning,
+    /// Over the failure limit
+    Failure,
+}
+
+/// A part needing more decoded memory than one of the limits
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryIssue {
+    pub part: String,
+    pub frames: u32,
+    /// Decoded memory of the part
+    pub bytes: u64,
+    pub severity: Severity,
+    /// The limit exceeded, in megabytes
+    pub limit: f64,
+    /// Most frames a part can hold within the limit, 0 when not even one fits
+    pub max_frames: u64,
+    /// Largest even size with the same aspect ratio where the part fits the limit
+    pub max_size: (u32, u32),
+}
+
+impl MemoryIssue {
+    /// What is over which limit, without advice on fixing it
+    pub fn describe(&self) -> String {
+        match self.severity {
+            Severity::Warning => format!(
+                "{} needs {} of decoded frames, low-RAM devices may run out of memory above {} MB",
+                self.part,
+                megabytes(self.bytes),
+                self.limit
+            ),
+            Severity::Failure => format!(
+                "{} needs {} of decoded frames, over the {} MB limit",
+                self.part,
+                megabytes(self.bytes),
+                self.limit
+            ),
+        }
+    }
+}
+
+/// Bytes the player needs to hold `frames` decoded frames
+pub fn part_memory(header: &Header, frames: u32) -> u64 {
+    header.frame_memory() * frames as u64
+}
+
+fn limit_bytes(megabytes: f64) -> u64 {
+    (megabytes * 1024.0 * 1024.0) as u64
+}
+
+/// Parts over a limit, in order, each reported once for the strictest limit it exceeds
+pub fn check_parts(
+    header: &Header,
+    parts: &[(String, u32)],
+    limits: MemoryLimits,
+) -> Vec<MemoryIssue> {
+    parts
+        .iter()
+        .filter_map(|(part, frames)| {
+            let bytes = part_memory(header, *frames);
+            let over = |limit: Option<f64>| limit.filter(|&mb| bytes > limit_bytes(mb));
+            let (severity, limit) = match (over(limits.fail), over(limits.warn)) {
+                (Some(limit), _) => (Severity::Failure, limit),
+                (None, Some(limit)) => (Severity::Warning, limit),
+                (None, None) => return None,
+            };
+            Some(MemoryIssue {
+                part: part.clone(),
+                frames: *frames,
+                bytes,
+                severity,
+                limit,
+                max_frames: header.frames_within(limit_bytes(limit)),
+                max_size: header.size_within(*frames as u64, limit_bytes(limit)),
+            })
+        })
+        .collect()
+}
+
+/// `bytes` in KB below a megabyte, in MB otherwise
+pub fn megabytes(bytes: u64) -> String {
+    if bytes < 1024 * 1024 {
+        format!("{:.1} KB", bytes as f64 / 1024.0)
+    } else {
+        format!("{:.2} MB", bytes as f64 / (1024.0 * 1024.0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 512x512 frames take exactly 1 MB decoded
+    fn header() -> Header {
+        Header {
+            width: 512,
+            height: 512,
+            fps: 30,
+            progress: None,
+            is_global_format: false,
+            offset_x: 0,
+            offset_y: 0,
+        }
+    }
+
+    fn parts(frames: &[u32]) -> Vec<(String, u32)> {
+        frames
+            .iter()
+            .enumerate()
+            .map(|(idx, &frames)| (format!("part{}", idx), frames))
+            .collect()
+    }
+
+    fn severities(issues: &[MemoryIssue]) -> Vec<(&str, Severity)> {
+        issues
+            .iter()
+            .map(|issue| (issue.part.as_str(), issue.severity))
+            .collect()
+    }
+
+    #[test]
+    fn parts_at_a_limit_are_within_it() {
+        let limits = MemoryLimits {
+            warn: Some(10.0),
+            fail: Some(20.0),
+        };
+        let issues = check_parts(&header(), &parts(&[10, 11, 20, 21]), limits);
+        assert_eq!(
+            severities(&issues),
+            [
+                ("part1", Severity::Warning),
+                ("part2", Severity::Warning),
+                ("part3", Severity::Failure)
+            ]
+        );
+        assert_eq!((issues[0].limit, issues[2].limit), (10.0, 20.0));
+    }
+
+    #[test]
+    fn limits_can_be_left_out() {
+        let frames = parts(&[5, 50]);
+        assert!(check_parts(&header(), &frames, MemoryLimits::default()).is_empty());
+
+        let warn_only = MemoryLimits {
+            warn: Some(8.0),
+            fail: None,
+        };
+        let issues = check_parts(&header(), &frames, warn_only);
+        assert_eq!(severities(&issues), [("part1", Severity::Warning)]);
+
+        // a failure limit under the warning one still fails
+        let fail_only = MemoryLimits {
+            warn: None,
+            fail: Some(4.0),
+        };
+        let issues = check_parts(&header(), &frames, fail_only);
+        assert_eq!(
+            severities(&issues),
+            [("part0", Severity::Failure), ("part1", Severity::Failure)]
+        );
+    }
+
+    #[test]
+    fn issues_tell_what_would_fit() {
+        let limits = MemoryLimits {
+            warn: Some(10.0),
+            fail: None,
+        };
+        let issue = &check_parts(&header(), &parts(&[40]), limits)[0];
+        assert_eq!(issue.bytes, 40 * 1024 * 1024);
+        assert_eq!(issue.max_frames, 10);
+        assert_eq!(issue.max_size, header().size_within(40, 10 * 1024 * 1024));
+        assert_eq!(
+            issue.describe(),
+            "part0 needs 40.00 MB of decoded frames, low-RAM devices may run out of memory above 10 MB"
+        );
+
+        let limits = MemoryLimits {
+            warn: None,
+            fail: Some(0.5),
+        };
+        let issue = &check_parts(&header(), &parts(&[1]), limits)[0];
+        assert_eq!(issue.max_frames, 0);
+        assert_eq!(
+            issue.describe(),
+            "part0 needs 1.00 MB of decoded frames, over the 0.5 MB limit"
+        );
+    }
+
+    #[test]
+    fn small_sizes_are_shown_in_kilobytes() {
+        assert_eq!(megabytes(512 * 1024), "512.0 KB");
+        assert_eq!(megabytes(1024 * 1024), "1.00 MB");
+        assert_eq!(megabytes(3 * 1024 * 1024 / 2), "1.50 MB");
+    }
+}
@@ -0,0 +1,199 @@
+//! Shrinking PNG frames: lossless recompression, fewer bits per channel and palette
+//! quantization.
+
+use anyhow::{Context, Result};
+use color_quant::NeuQuant;
+use image::{RgbaImage, imageops};
+
+/// NeuQuant sampling factor, 1 is slowest and best, 30 fastest
+const QUANT_SAMPLE_FACTOR: i32 = 10;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+pub struct PngOptions {
+    /// Quantize to a palette of at most this many colors (2-256)
+    pub palette: Option<u16>,
+    /// Spread the quantization error with Floyd-Steinberg dithering
+    pub dither: bool,
+    /// Keep only the top bits of every color channel (1-7), before any quantization
+    pub bits: Option<u8>,
+}
+
+/// Re-encodes a PNG as small as possible, keeping the original if it is already smaller
+///
+/// Without a palette or fewer bits the pixels are unchanged: the alpha channel is
+/// dropped when every pixel is opaque, gray pictures are stored as grayscale and
+/// the strongest deflate settings are used.
+pub fn optimize_png(data: &[u8], options: PngOptions) -> Result<Vec<u8>> {
+    let mut image = image::load_from_memory_with_format(data, image::ImageFormat::Png)
+        .context("Failed to decode PNG frame")?
+        .into_rgba8();
+    if let Some(bits) = options.bits {
+        posterize(&mut image, bits);
+    }
+    let opaque = image.pixels().all(|p| p[3] == 255);
+
+    let optimized = match options.palette {
+        Some(colors) => encode_palette(image, colors, options.dither, opaque)?,
+        None => encode_lossless(&image, opaque)?,
+    };
+
+    Ok(if optimized.len() < data.len() {
+        optimized
+    } else {
+        data.to_vec()
+    })
+}
+
+/// Keeps the top `bits` of every color channel, spread back over the full range
+fn posterize(image: &mut RgbaImage, bits: u8) {
+    let bits = bits.clamp(1, 8) as u32;
+    let levels = (1 << bits) - 1;
+    for pixel in image.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            let level = *value as u32 >> (8 - bits);
+            *value = (level * 255 / levels) as u8;
+        }
+    }
+}
+
+fn encode_lossless(image: &RgbaImage, opaque: bool) -> Result<Vec<u8>> {
+    let gray = image.pixels().all(|p| p[0] == p[1] && p[1] == p[2]);
+
+    let (color, channels): (png::ColorType, &[usize]) = match (gray, opaque) {
+        (true, true) => (png::ColorType::Grayscale, &[0]),
+        (true, false) => (png::ColorType::GrayscaleAlpha, &[0, 3]),
+        (false, true) => (png::ColorType::Rgb, &[0, 1, 2]),
+        (false, false) => (png::ColorType::Rgba, &[0, 1, 2, 3]),
+    };
+    let pixels: Vec<u8> = image
+        .pixels()
+        .flat_map(|p| channels.iter().map(|&c| p[c]))
+        .collect();
+
+    encode(image.width(), image.height(), color, &pixels, |_| {})
+}
+
+fn encode_palette(mut image: RgbaImage, colors: u16, dither: bool, opaque: bool) -> Result<Vec<u8>> {
+    let quant = NeuQuant::new(QUANT_SAMPLE_FACTOR, colors.clamp(2, 256) as usize, &image);
+    if dither {
+        imageops::dither(&mut image, &quant);
+    }
+    let indices = imageops::index_colors(&image, &quant).into_raw();
+
+    let palette_rgba = quant.color_map_rgba();
+    let palette: Vec<u8> = palette_rgba
+        .chunks_exact(4)
+        .flat_map(|c| [c[0], c[1], c[2]])
+        .collect();
+    let alphas: Vec<u8> = palette_rgba.chunks_exact(4).map(|c| c[3]).collect();
+
+    encode(
+        image.width(),
+        image.height(),
+        png::ColorType::Indexed,
+        &indices,
+        |encoder| {
+            encoder.set_palette(palette);
+            if !opaque {
+                encoder.set_trns(alphas);
+            }
+        },
+    )
+}
+
+fn encode(
+    width: u32,
+    height: u32,
+    color: png::ColorType,
+    pixels: &[u8],
+    configure: impl FnOnce(&mut png::Encoder<&mut Vec<u8>>),
+) -> Result<Vec<u8>> {
+    let mut encoded = Vec::new();
+    let mut encoder = png::Encoder::new(&mut encoded, width, height);
+    encoder.set_color(color);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(png::Compression::High);
+    configure(&mut encoder);
+
+    let mut writer = encoder.write_header().context("Failed to encode PNG frame")?;
+    writer
+        .write_image_data(pixels)
+        .context("Failed to encode PNG frame")?;
+    writer.finish().context("Failed to encode PNG frame")?;
+
+    Ok(encoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgba};
+    use std::collections::HashSet;
+    use std::io::Cursor;
+
+    /// Smooth gradient with many colors, `alpha` everywhere
+    fn gradient(alpha: u8) -> RgbaImage {
+        RgbaImage::from_fn(64, 32, |x, y| {
+            Rgba([(x * 4) as u8, (y * 8) as u8, ((x + y) * 3) as u8, alpha])
+        })
+    }
+
+    fn png(image: &RgbaImage) -> Vec<u8> {
+        let mut data = Vec::new();
+        image.write_to(&mut Cursor::new(&mut data), ImageFormat::Png).unwrap();
+        data
+    }
+
+    fn decoded(data: &[u8]) -> RgbaImage {
+        image::load_from_memory_with_format(data, ImageFormat::Png)
+            .unwrap()
+            .into_rgba8()
+    }
+
+    fn colors(image: &RgbaImage) -> usize {
+        image.pixels().map(|p| p.0).collect::<HashSet<_>>().len()
+    }
+
+    #[test]
+    fn lossless_mode_keeps_every_pixel() {
+        let gray = RgbaImage::from_fn(16, 16, |x, y| {
+            let value = (x * y) as u8;
+            Rgba([value, value, value, 255])
+        });
+        for image in [gradient(255), gradient(128), gray] {
+            let optimized = optimize_png(&png(&image), PngOptions::default()).unwrap();
+            assert_eq!(decoded(&optimized), image);
+        }
+    }
+
+    #[test]
+    fn palette_mode_respects_the_color_limit() {
+        let image = gradient(255);
+        assert!(colors(&image) > 256);
+
+        for (limit, dither) in [(256, true), (16, true), (16, false), (2, false)] {
+            let options = PngOptions {
+                palette: Some(limit),
+                dither,
+                bits: None,
+            };
+            let optimized = decoded(&optimize_png(&png(&image), options).unwrap());
+            assert_eq!(optimized.dimensions(), image.dimensions());
+            assert!(colors(&optimized) <= limit as usize);
+        }
+    }
+
+    #[test]
+    fn fewer_bits_leave_fewer_levels_per_channel() {
+        let options = PngOptions {
+            bits: Some(3),
+            ..PngOptions::default()
+        };
+        let optimized = decoded(&optimize_png(&png(&gradient(200)), options).unwrap());
+        for channel in 0..4 {
+            let levels: HashSet<u8> = optimized.pixels().map(|p| p[channel]).collect();
+            assert!(levels.len() <= 8, "channel {} has {} levels", channel, levels.len());
+        }
+        assert!(optimized.pixels().all(|p| p[3] == 200));
+    }
+}
@@ -0,0 +1,100 @@
+//! Numbered image sequences given as a directory or a file name pattern.
+
+use crate::archive::sort_frames_naturally;
+use anyhow::{Context, Result, bail};
+use image::RgbaImage;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Extensions accepted in image sequences
+pub const SEQUENCE_EXTENSIONS: [&str; 4] = ["png", "jpg", "jpeg", "webp"];
+
+/// Frames of an image sequence in natural order, `None` if `input` is a plain file
+///
+/// `input` is either a directory, in which case every image in it is used, or a
+/// path whose file name contains `*`/`?` wildcards, e.g. `renders/frame_*.png`.
+pub fn find_sequence(input: &Path) -> Result<Option<Vec<PathBuf>>> {
+    let (dir, pattern) = if input.is_dir() {
+        (input.to_path_buf(), None)
+    } else {
+        let name = input
+            .file_name()
+            .map(|n| n.to_string_lossy().to_string())
+            .unwrap_or_default();
+        if !name.contains(['*', '?']) {
+            return Ok(None);
+        }
+        let dir = match input.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        (dir, Some(name))
+    };
+
+    let mut frames: Vec<PathBuf> = fs::read_dir(&dir)
+        .with_context(|| format!("Failed to read {}", dir.display()))?
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| path.is_file() && is_sequence_image(path))
+        .filter(|path| match pattern {
+            Some(ref pattern) => {
+                let name = path.file_name().unwrap().to_string_lossy();
+                wildcard_match(pattern, &name)
+            }
+            None => true,
+        })
+        .collect();
+
+    if frames.is_empty() {
+        bail!("No images found for {}", input.display());
+    }
+
+    sort_frames_naturally(&mut frames);
+    Ok(Some(frames))
+}
+
+fn is_sequence_image(path: &Path) -> bool {
+    path.extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .is_some_and(|ext| SEQUENCE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+/// Matches `*` (any run of characters) and `?` (one character)
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // position of the last `*` and where it started matching
+    let mut star: Option<(usize, usize)> = None;
+
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            star = Some((p, n));
+            p += 1;
+        } else if let Some((star_p, star_n)) = star {
+            // let the last `*` swallow one more character
+            p = star_p + 1;
+            n = star_n + 1;
+            star = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// Size of a frame without decoding it
+pub fn frame_size(path: &Path) -> Result<(u32, u32)> {
+    image::image_dimensions(path).with_context(|| format!("Failed to read {}", path.display()))
+}
+
+pub fn decode_frame(path: &Path) -> Result<RgbaImage> {
+    Ok(image::open(path)
+        .with_context(|| format!("Failed to decode {}", path.display()))?
+        .into_rgba8())
+}
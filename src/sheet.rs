@@ -0,0 +1,401 @@
+//! Contact sheets and thumbnails rendered from bootanimation frames.
+
+use crate::jobs::run_parallel;
+use crate::sequence::decode_frame;
+use anyhow::{Result, bail};
+use image::imageops::{self, FilterType};
+use image::{Rgb, RgbImage, RgbaImage};
+use std::path::{Path, PathBuf};
+
+/// Space between tiles and around the sheet
+const MARGIN: u32 = 8;
+/// Labels are drawn with the built-in 5x7 font scaled by this factor
+const TEXT_SCALE: u32 = 2;
+/// Frames sampled when looking for a representative frame
+const CANDIDATES: usize = 9;
+
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+const LABEL_BACKGROUND: Rgb<u8> = Rgb([0, 0, 0]);
+const TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+
+/// One row of a contact sheet: a label above evenly spaced frames
+pub struct SheetRow {
+    pub label: String,
+    pub frames: Vec<PathBuf>,
+}
+
+/// Indexes of `samples` frames spread evenly over `count`, first and last included
+pub fn evenly_spaced(count: usize, samples: usize) -> Vec<usize> {
+    if count <= samples {
+        return (0..count).collect();
+    }
+    match samples {
+        0 => Vec::new(),
+        1 => vec![count / 2],
+        _ => (0..samples)
+            .map(|i| (i * (count - 1) + (samples - 1) / 2) / (samples - 1))
+            .collect(),
+    }
+}
+
+/// Grid with one row per part, each tile labelled with its frame number
+///
+/// Tiles are `tile_width` wide with the `width`:`height` aspect ratio of the
+/// animation, frames of another size are fitted inside them.
+pub fn contact_sheet(
+    rows: &[SheetRow],
+    columns: u32,
+    tile_width: u32,
+    (width, height): (u32, u32),
+    jobs: usize,
+) -> Result<RgbImage> {
+    if columns == 0 || tile_width == 0 {
+        bail!("Contact sheet needs at least one column of non-empty tiles");
+    }
+    let tile_height = ((tile_width as u64 * height as u64 / width.max(1) as u64) as u32).max(1);
+    let header_height = glyph_height() + 2 * MARGIN;
+    let sheet_width = MARGIN + columns * (tile_width + MARGIN);
+    let sheet_height = MARGIN + rows.len() as u32 * (header_height + tile_height + MARGIN);
+
+    let tiles: Vec<(usize, usize)> = rows
+        .iter()
+        .enumerate()
+        .flat_map(|(row, part)| {
+            evenly_spaced(part.frames.len(), columns as usize)
+                .into_iter()
+                .map(move |idx| (row, idx))
+        })
+        .collect();
+    let rendered = run_parallel(&tiles, jobs, |_, &(row, idx)| {
+        render_tile(&rows[row].frames[idx], idx, tile_width, tile_height)
+    });
+
+    let mut sheet = RgbImage::from_pixel(sheet_width, sheet_height, BACKGROUND);
+    let mut rendered = rendered.into_iter();
+    let mut y = MARGIN;
+    for (row, part) in rows.iter().enumerate() {
+        draw_text(
+            &mut sheet,
+            &part.label,
+            MARGIN,
+            y + MARGIN,
+            sheet_width - 2 * MARGIN,
+        );
+        y += header_height;
+
+        let mut x = MARGIN;
+        for _ in tiles.iter().filter(|&&(r, _)| r == row) {
+            let tile = rendered.next().unwrap()?;
+            imageops::replace(&mut sheet, &tile, x as i64, y as i64);
+            x += tile_width + MARGIN;
+        }
+        y += tile_height + MARGIN;
+    }
+
+    Ok(sheet)
+}
+
+/// Frame showing the most detail among a few evenly spaced ones, skipping blank frames
+pub fn representative_frame(frames: &[PathBuf], jobs: usize) -> Result<usize> {
+    let candidates = evenly_spaced(frames.len(), CANDIDATES);
+    if candidates.is_empty() {
+        bail!("No frames to pick a thumbnail from");
+    }
+
+    let scores = run_parallel(&candidates, jobs, |_, &idx| -> Result<f64> {
+        let frame = flatten(&decode_frame(&frames[idx])?);
+        Ok(luma_deviation(&imageops::thumbnail(&frame, 64, 64)))
+    });
+
+    let mut best = (candidates[0], f64::NEG_INFINITY);
+    for (&idx, score) in candidates.iter().zip(scores) {
+        let score = score?;
+        if score > best.1 {
+            best = (idx, score);
+        }
+    }
+    Ok(best.0)
+}
+
+/// Frame scaled to fit within `size`x`size`, keeping its aspect ratio
+pub fn thumbnail(frame: &Path, size: u32) -> Result<RgbImage> {
+    let frame = flatten(&decode_frame(frame)?);
+    let (width, height) = fit(frame.dimensions(), (size, size));
+    Ok(imageops::resize(
+        &frame,
+        width,
+        height,
+        FilterType::Lanczos3,
+    ))
+}
+
+fn render_tile(frame: &Path, idx: usize, width: u32, height: u32) -> Result<RgbImage> {
+    let frame = flatten(&decode_frame(frame)?);
+    let (fit_width, fit_height) = fit(frame.dimensions(), (width, height));
+    let scaled = imageops::resize(&frame, fit_width, fit_height, FilterType::Triangle);
+
+    let mut tile = RgbImage::from_pixel(width, height, LABEL_BACKGROUND);
+    imageops::replace(
+        &mut tile,
+        &scaled,
+        ((width - fit_width) / 2) as i64,
+        ((height - fit_height) / 2) as i64,
+    );
+
+    let label = format!("#{}", idx + 1);
+    let label_width = (text_width(&label) + 4).min(width);
+    let label_height = (glyph_height() + 4).min(height);
+    for y in height - label_height..height {
+        for x in 0..label_width {
+            tile.put_pixel(x, y, LABEL_BACKGROUND);
+        }
+    }
+    draw_text(&mut tile, &label, 2, height - label_height + 2, width);
+    Ok(tile)
+}
+
+/// Frame composited over black, as shown on the device
+fn flatten(frame: &RgbaImage) -> RgbImage {
+    RgbImage::from_fn(frame.width(), frame.height(), |x, y| {
+        let [r, g, b, a] = frame.get_pixel(x, y).0;
+        let scale = |c: u8| (c as u32 * a as u32 / 255) as u8;
+        Rgb([scale(r), scale(g), scale(b)])
+    })
+}
+
+/// Largest size within `max_width`x`max_height` with the aspect ratio of `width`x`height`
+fn fit((width, height): (u32, u32), (max_width, max_height): (u32, u32)) -> (u32, u32) {
+    let ratio = (max_width as f64 / width as f64).min(max_height as f64 / height as f64);
+    (
+        ((width as f64 * ratio).round() as u32).clamp(1, max_width),
+        ((height as f64 * ratio).round() as u32).clamp(1, max_height),
+    )
+}
+
+/// Standard deviation of the luma, low for blank and nearly blank frames
+fn luma_deviation(image: &RgbImage) -> f64 {
+    let luma: Vec<f64> = image
+        .pixels()
+        .map(|p| 0.299 * p[0] as f64 + 0.587 * p[1] as f64 + 0.114 * p[2] as f64)
+        .collect();
+    let n = luma.len().max(1) as f64;
+    let mean = luma.iter().sum::<f64>() / n;
+    (luma.iter().map(|l| (l - mean).powi(2)).sum::<f64>() / n).sqrt()
+}
+
+fn glyph_height() -> u32 {
+    7 * TEXT_SCALE
+}
+
+fn text_width(text: &str) -> u32 {
+    (text.chars().count() as u32 * 6).saturating_sub(1) * TEXT_SCALE
+}
+
+/// Draws `text` with its top left corner at `x`,`y`, cut off after `max_width` pixels
+fn draw_text(image: &mut RgbImage, text: &str, x: u32, y: u32, max_width: u32) {
+    let advance = 6 * TEXT_SCALE;
+    let fitting = (max_width + TEXT_SCALE) / advance;
+    for (i, c) in text.chars().take(fitting as usize).enumerate() {
+        let left = x + i as u32 * advance;
+        for (row, bits) in glyph(c).iter().enumerate() {
+            for col in 0..5 {
+                if bits & (0x10 >> col) == 0 {
+                    continue;
+                }
+                for dy in 0..TEXT_SCALE {
+                    for dx in 0..TEXT_SCALE {
+                        let (px, py) = (
+                            left + col * TEXT_SCALE + dx,
+                            y + row as u32 * TEXT_SCALE + dy,
+                        );
+                        if px < image.width() && py < image.height() {
+                            image.put_pixel(px, py, TEXT);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Rows of a 5x7 glyph, most significant of the low five bits leftmost
+///
+/// Letters are drawn in upper case, characters without a glyph as `?`.
+fn glyph(c: char) -> [u8; 7] {
+    match c.to_ascii_uppercase() {
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        'A' => [0x0E, 0x11, 0x11, 0x11, 0x1F, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x11, 0x0A, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '#' => [0x0A, 0x0A, 0x1F, 0x0A, 0x1F, 0x0A, 0x0A],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '+' => [0x00, 0x04, 0x04, 0x1F, 0x04, 0x04, 0x00],
+        '_' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x1F],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    /// Writes `images` as numbered PNG frames
+    fn frames(dir: &Path, images: &[RgbImage]) -> Vec<PathBuf> {
+        images
+            .iter()
+            .enumerate()
+            .map(|(idx, image)| {
+                let path = dir.join(format!("{:03}.png", idx));
+                image.save(&path).unwrap();
+                path
+            })
+            .collect()
+    }
+
+    fn solid(width: u32, height: u32, color: [u8; 3]) -> RgbImage {
+        RgbImage::from_pixel(width, height, Rgb(color))
+    }
+
+    #[test]
+    fn samples_are_spread_evenly_with_both_ends() {
+        assert_eq!(evenly_spaced(3, 5), [0, 1, 2]);
+        assert_eq!(evenly_spaced(10, 0), [] as [usize; 0]);
+        assert_eq!(evenly_spaced(10, 1), [5]);
+        assert_eq!(evenly_spaced(10, 2), [0, 9]);
+        assert_eq!(evenly_spaced(10, 4), [0, 3, 6, 9]);
+        assert_eq!(evenly_spaced(100, 3), [0, 50, 99]);
+    }
+
+    #[test]
+    fn representative_frames_skip_blank_ones() {
+        let temp = TempDir::new().unwrap();
+        let checker = RgbImage::from_fn(16, 16, |x, y| {
+            if (x / 4 + y / 4) % 2 == 0 { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) }
+        });
+        let mut images = vec![solid(16, 16, [0, 0, 0]); 20];
+        images[19] = solid(16, 16, [255, 255, 255]);
+        // only a few evenly spaced frames are looked at
+        let middle = evenly_spaced(20, CANDIDATES)[4];
+        images[middle] = checker;
+
+        let paths = frames(temp.path(), &images);
+        assert_eq!(representative_frame(&paths, 2).unwrap(), middle);
+        assert!(representative_frame(&[], 1).is_err());
+    }
+
+    #[test]
+    fn contact_sheets_have_a_labelled_row_per_part() {
+        let temp = TempDir::new().unwrap();
+        let short_dir = temp.path().join("short");
+        let long_dir = temp.path().join("long");
+        std::fs::create_dir_all(&short_dir).unwrap();
+        std::fs::create_dir_all(&long_dir).unwrap();
+        let rows = [
+            SheetRow {
+                label: "part0".to_string(),
+                frames: frames(&short_dir, &vec![solid(80, 40, [200, 0, 0]); 3]),
+            },
+            SheetRow {
+                label: "part1".to_string(),
+                // square frames are fitted into the wide tiles
+                frames: frames(&long_dir, &vec![solid(40, 40, [0, 0, 200]); 10]),
+            },
+        ];
+
+        let sheet = contact_sheet(&rows, 4, 40, (80, 40), 2).unwrap();
+        let (tile_width, tile_height, header_height) = (40, 20, glyph_height() + 2 * MARGIN);
+        assert_eq!(
+            sheet.dimensions(),
+            (
+                MARGIN + 4 * (tile_width + MARGIN),
+                MARGIN + 2 * (header_height + tile_height + MARGIN)
+            )
+        );
+
+        let tile = |row: u32, column: u32| {
+            let x = MARGIN + column * (tile_width + MARGIN);
+            let y = MARGIN + row * (header_height + tile_height + MARGIN) + header_height;
+            (x, y)
+        };
+        // top row of each tile, above the frame number
+        let pixel = |(x, y): (u32, u32), dx: u32| sheet.get_pixel(x + dx, y).0;
+        assert_eq!(pixel(tile(0, 2), 36), [200, 0, 0]);
+        // the short part fills three tiles, the rest of its row stays empty
+        assert_eq!(pixel(tile(0, 3), 36), BACKGROUND.0);
+        // the square frame is centered with bars on both sides
+        assert_eq!(pixel(tile(1, 3), 36), LABEL_BACKGROUND.0);
+        assert_eq!(pixel(tile(1, 3), 28), [0, 0, 200]);
+
+        // labels are drawn above their row
+        let label_pixels = |row: u32| {
+            let y = MARGIN + row * (header_height + tile_height + MARGIN) + MARGIN;
+            (y..y + glyph_height())
+                .flat_map(|y| (MARGIN..sheet.width()).map(move |x| (x, y)))
+                .filter(|&(x, y)| *sheet.get_pixel(x, y) == TEXT)
+                .count()
+        };
+        assert!(label_pixels(0) > 0 && label_pixels(1) > 0);
+
+        assert!(contact_sheet(&rows, 0, 40, (80, 40), 1).is_err());
+    }
+
+    #[test]
+    fn text_is_drawn_with_the_scaled_glyphs() {
+        let lit = |image: &RgbImage| image.pixels().filter(|&&p| p == TEXT).count() as u32;
+        let bits = |c: char| glyph(c).iter().map(|row| row.count_ones()).sum::<u32>();
+
+        let mut image = solid(100, 20, [0, 0, 0]);
+        draw_text(&mut image, "#1", 0, 0, 100);
+        assert_eq!(lit(&image), (bits('#') + bits('1')) * TEXT_SCALE * TEXT_SCALE);
+        assert_eq!(text_width("#1"), 11 * TEXT_SCALE);
+
+        // lower case letters use the upper case glyphs, unknown characters a question mark
+        assert_eq!(glyph('a'), glyph('A'));
+        assert_eq!(glyph('~'), glyph('?'));
+
+        // text past the width is cut at whole characters
+        let mut image = solid(100, 20, [0, 0, 0]);
+        draw_text(&mut image, "11111", 0, 0, text_width("11"));
+        assert_eq!(lit(&image), 2 * bits('1') * TEXT_SCALE * TEXT_SCALE);
+    }
+}
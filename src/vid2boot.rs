@@ -1,18 +1,30 @@
 use anyhow::{Context, Result, bail};
+use bootanimation_tools::analysis::{SceneDetector, StaticRun, best_match, decode_encoded, thumbnail};
+use bootanimation_tools::animation::{Animation, blend, encode_frame, render_frame};
+use bootanimation_tools::archive::{
+    BootanimationWriter, create_bootanimation_zip, extract_zip, list_frames, read_part_frames,
+};
+use bootanimation_tools::desc::{Desc, Header, Part, PartKind, Trim, validate_color};
+use bootanimation_tools::frames::{FpsMode, FrameQuality, ImageFormat, ScaleMode};
+use bootanimation_tools::jobs::{run_parallel, worker_count};
+use bootanimation_tools::media::{FfmpegCli, FrameRequest, MediaBackend, MediaInfo};
+use bootanimation_tools::memory::{
+    MemoryIssue, MemoryLimits, Severity, check_parts, megabytes, part_memory,
+};
+use bootanimation_tools::optimize::{PngOptions, optimize_png};
+use bootanimation_tools::sequence::{decode_frame, find_sequence, frame_size};
 use clap::{Parser, ValueEnum};
-use std::fs::{self, File};
-use std::io::{BufWriter, Write};
+use image::imageops;
+use std::fs;
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::Mutex;
 use tempfile::TempDir;
-use zip::{CompressionMethod, ZipArchive};
-use zip::write::FileOptions;
 
 #[derive(Parser)]
 #[command(name = "vid2boot")]
 #[command(about = "Convert videos to Android bootanimation", long_about = None)]
 struct Cli {
-    /// Input video file path
+    /// Input video file path, GIF/APNG/WebP file, or image sequence directory or pattern (e.g. 'frames/*.png')
     #[arg(short, long)]
     input: PathBuf,
 
@@ -24,6 +36,19 @@ struct Cli {
     #[arg(short, long)]
     config_from: Option<PathBuf>,
 
+    /// Read target resolution from a device dump (build.prop, `wm size` or
+    /// `dumpsys display` output); can be given multiple times
+    #[arg(short = 'd', long)]
+    device_info: Vec<PathBuf>,
+
+    /// Re-skin an existing bootanimation.zip, keeping its parts and desc.txt attributes
+    #[arg(short, long, conflicts_with_all = ["config_from", "background"])]
+    template: Option<PathBuf>,
+
+    /// How video frames are spread over the template parts
+    #[arg(long, value_enum, default_value = "proportion")]
+    template_mapping: TemplateMapping,
+
     /// Output width (optional, uses video width if not specified)
     #[arg(short = 'W', long)]
     width: Option<u32>,
@@ -32,10 +57,14 @@ struct Cli {
     #[arg(short = 'H', long)]
     height: Option<u32>,
 
-    /// Frame rate (optional, uses video fps if not specified)
+    /// Frame rate (optional, uses video fps if not specified, required for image sequences)
     #[arg(short, long)]
     fps: Option<f64>,
 
+    /// How frames are converted when the output frame rate differs from the input
+    #[arg(long, value_enum, default_value = "drop")]
+    fps_mode: FpsMode,
+
     /// Animation loop behavior
     #[arg(short, long, value_enum, default_value = "stop-on-boot")]
     loop_mode: LoopMode,
@@ -44,6 +73,10 @@ struct Cli {
     #[arg(short, long)]
     background: Option<String>,
 
+    /// How the video is fitted to the output resolution
+    #[arg(long, value_enum, default_value = "stretch")]
+    scale_mode: ScaleMode,
+
     /// Include audio (creates audio.wav in each part)
     #[arg(long)]
     with_audio: bool,
@@ -55,6 +88,79 @@ struct Cli {
     /// Image format for frames
     #[arg(long, value_enum, default_value = "jpg")]
     format: ImageFormat,
+
+    /// JPEG quality (1-100, uses the encoder default if not specified)
+    #[arg(short, long, value_parser = clap::value_parser!(u8).range(1..=100))]
+    quality: Option<u8>,
+
+    /// Recompress PNG frames in-process, dropping unused alpha and color channels
+    #[arg(long)]
+    optimize_png: bool,
+
+    /// Quantize PNG frames to a palette of at most this many colors (implies --optimize-png)
+    #[arg(long, value_parser = clap::value_parser!(u16).range(2..=256))]
+    png_colors: Option<u16>,
+
+    /// Quantize without dithering
+    #[arg(long, requires = "png_colors")]
+    no_dither: bool,
+
+    /// Keep only the most seamless loop, with its start in the first and its end in
+    /// the last SECONDS of the video
+    #[arg(long, value_name = "SECONDS", conflicts_with = "template")]
+    find_loop: Option<f64>,
+
+    /// Crossfade this many frames at the loop seam
+    #[arg(long, default_value = "0", requires = "find_loop")]
+    loop_crossfade: u32,
+
+    /// Start a new part at every scene cut, still splitting scenes longer than --max-frames
+    #[arg(long, conflicts_with = "template")]
+    split_at_scenes: bool,
+
+    /// Mean pixel difference (0-255) between consecutive frames that counts as a scene cut
+    #[arg(long, default_value = "30", requires = "split_at_scenes")]
+    scene_threshold: f64,
+
+    /// Replace identical frames at the end of each part with desc.txt pause frames
+    #[arg(long)]
+    collapse_static: bool,
+
+    /// Mean pixel difference (0-255) still treated as identical by --collapse-static
+    #[arg(long, default_value = "0", requires = "collapse_static")]
+    static_threshold: f64,
+
+    /// Number of parts extracted in parallel (0 = one per CPU core)
+    #[arg(short, long, default_value = "1")]
+    jobs: usize,
+
+    /// Largest allowed bootanimation.zip in megabytes, settings are lowered until it fits
+    #[arg(long)]
+    max_size: Option<f64>,
+
+    /// Order in which --max-size lowers settings
+    #[arg(long, value_enum, value_delimiter = ',', default_value = "quality,fps,resolution")]
+    size_priority: Vec<SizeSetting>,
+
+    /// Most frames the whole animation may have, the frame rate is lowered to fit
+    #[arg(long, conflicts_with = "template")]
+    max_total_frames: Option<u32>,
+
+    /// Most decoded memory in megabytes one part may need (4 bytes per pixel)
+    #[arg(long, conflicts_with = "template")]
+    max_part_memory: Option<f64>,
+
+    /// What --max-part-memory lowers to fit
+    #[arg(long, value_enum, default_value = "fps", requires = "max_part_memory")]
+    part_memory_fit: MemoryFit,
+
+    /// Warn when a part needs more decoded memory than this many megabytes (0 = never)
+    #[arg(long, default_value = "256")]
+    warn_part_memory: f64,
+
+    /// Fail when a part needs more decoded memory than this many megabytes
+    #[arg(long)]
+    fail_part_memory: Option<f64>,
 }
 
 #[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
@@ -68,528 +174,1750 @@ enum LoopMode {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, ValueEnum)]
-enum ImageFormat {
-    Jpg,
-    Png,
+enum TemplateMapping {
+    /// Split the video in the same proportions as the template parts
+    Proportion,
+    /// Give every part as many frames as it lasts in the template
+    Duration,
 }
 
-struct VideoProperties {
-    width: u32,
-    height: u32,
-    fps: u32,
-    duration: f64,
-    has_audio: bool,
+/// Settings `--max-size` may lower
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum SizeSetting {
+    /// JPEG quality, or PNG compression, color depth and palette size
+    Quality,
+    Fps,
+    Resolution,
 }
 
-struct BootAnimConfig {
-    width: u32,
-    height: u32,
-    fps: u32,
-    is_global_format: bool,
-    offset_x: u32,
-    offset_y: u32,
-}
-
-fn get_ffmpeg_path() -> String {
-    std::env::var("FFMPEG_PATH").unwrap_or_else(|_| "ffmpeg".to_string())
-}
-
-fn get_ffprobe_path() -> String {
-    std::env::var("FFPROBE_PATH").unwrap_or_else(|_| "ffprobe".to_string())
-}
-
-fn get_video_properties(video_path: &Path) -> Result<VideoProperties> {
-    let ffprobe = get_ffprobe_path();
-
-    // get width
-    let width_output = Command::new(&ffprobe)
-        .args([
-            "-hide_banner",
-            "-v",
-            "error",
-            "-select_streams",
-            "v:0",
-            "-show_entries",
-            "stream=width",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(video_path)
-        .output()
-        .context("Failed to get video width")?;
-
-    let width: u32 = String::from_utf8_lossy(&width_output.stdout)
-        .trim()
-        .parse()
-        .context("Failed to parse width")?;
-
-    // get height
-    let height_output = Command::new(&ffprobe)
-        .args([
-            "-hide_banner",
-            "-v",
-            "error",
-            "-select_streams",
-            "v:0",
-            "-show_entries",
-            "stream=height",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(video_path)
-        .output()
-        .context("Failed to get video height")?;
-
-    let height: u32 = String::from_utf8_lossy(&height_output.stdout)
-        .trim()
-        .parse()
-        .context("Failed to parse height")?;
-
-    // get frame rate
-    let fps_output = Command::new(&ffprobe)
-        .args([
-            "-hide_banner",
-            "-v",
-            "error",
-            "-select_streams",
-            "v:0",
-            "-show_entries",
-            "stream=r_frame_rate",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(video_path)
-        .output()
-        .context("Failed to get frame rate")?;
-
-    let fps_str = String::from_utf8_lossy(&fps_output.stdout)
-        .trim()
-        .to_string();
-    let fps = if fps_str.contains('/') {
-        let parts: Vec<&str> = fps_str.split('/').collect();
-        let numerator: f64 = parts[0].parse().context("Failed to parse fps numerator")?;
-        let denominator: f64 = parts[1]
-            .parse()
-            .context("Failed to parse fps denominator")?;
-        (numerator / denominator).round() as u32
-    } else {
-        fps_str.parse().context("Failed to parse fps")?
-    };
+/// Settings `--max-part-memory` may lower
+#[derive(Debug, Copy, Clone, PartialEq, Eq, ValueEnum)]
+enum MemoryFit {
+    /// Highest frame rate that keeps the whole animation in one small enough part
+    Fps,
+    /// Largest resolution, keeping the aspect ratio
+    Resolution,
+}
 
-    // get duration
-    let duration_output = Command::new(&ffprobe)
-        .args([
-            "-hide_banner",
-            "-v",
-            "error",
-            "-show_entries",
-            "format=duration",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(video_path)
-        .output()
-        .context("Failed to get video duration")?;
-
-    let duration: f64 = String::from_utf8_lossy(&duration_output.stdout)
-        .trim()
-        .parse()
-        .context("Failed to parse duration")?;
-
-    // check for audio
-    let audio_output = Command::new(&ffprobe)
-        .args([
-            "-hide_banner",
-            "-v",
-            "error",
-            "-show_entries",
-            "stream=codec_type",
-            "-select_streams",
-            "a",
-            "-of",
-            "csv=p=0",
-        ])
-        .arg(video_path)
-        .output()
-        .context("Failed to check for audio")?;
-
-    let has_audio = !String::from_utf8_lossy(&audio_output.stdout)
-        .trim()
-        .is_empty();
-
-    Ok(VideoProperties {
-        width,
-        height,
-        fps,
-        duration,
-        has_audio,
-    })
+/// Frame settings `--max-size` lowers together
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+struct FrameSettings {
+    quality: FrameQuality,
+    /// In-process PNG pass, also dropping color bits or quantizing on the smaller PNG steps
+    png: Option<PngOptions>,
 }
 
-fn read_config_from_bootanimation(zip_path: &Path) -> Result<BootAnimConfig> {
+/// `--optimize-png` pass, counting the bytes saved in every part
+struct PngOptimizer {
+    options: PngOptions,
+    /// part name, bytes before and after, in the order parts were seen
+    totals: Mutex<Vec<(String, u64, u64)>>,
+}
+
+/// Decides the part of every frame as frames arrive
+///
+/// A part ends after `--max-frames` frames, or with `--split-at-scenes` at a scene
+/// cut, unless the part would be shorter than half a second.
+struct PartSplitter {
+    max_frames: u32,
+    min_frames: u32,
+    scenes: Option<SceneDetector>,
+    sizes: Vec<u32>,
+    cuts: u32,
+}
+
+/// `--collapse-static` pass, counting the frames and bytes dropped in every part
+struct StaticCollapser {
+    threshold: f64,
+    /// part name, frames and bytes dropped
+    totals: Mutex<Vec<(String, u32, u64)>>,
+}
+
+/// Writes frames into zip parts in order, collapsing static part ends and optimizing PNGs
+struct PartWriter<'a> {
+    zip: &'a mut BootanimationWriter,
+    optimizer: Option<&'a PngOptimizer>,
+    collapser: Option<&'a StaticCollapser>,
+    run: Option<StaticRun>,
+    part: Option<String>,
+    /// static frames of the current part, written once a different frame shows up
+    pending: Vec<(String, Vec<u8>)>,
+    pauses: Vec<u32>,
+}
+
+/// Inputs decoded without FFmpeg
+enum ImageInput {
+    /// GIF, APNG or WebP file
+    Animation(Animation),
+    /// Numbered images, one per frame
+    Sequence(Vec<PathBuf>),
+}
+
+struct PartLayout {
+    name: String,
+    frames: u32,
+    audio: bool,
+    /// Static frames at the end of the part replaced by pause frames
+    pause: u32,
+}
+
+struct Template {
+    // keeps the extracted template alive while its audio is copied
+    _dir: TempDir,
+    desc: Desc,
+    parts: Vec<TemplatePart>,
+}
+
+struct TemplatePart {
+    name: String,
+    frames: u32,
+    audio: Option<PathBuf>,
+    trim: Option<Trim>,
+}
+
+/// Where a display size was read from, most authoritative first
+#[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum SizeSource {
+    /// `wm size` override, what the boot animation is actually drawn at
+    Override,
+    /// `wm size` physical panel size
+    Physical,
+    /// `dumpsys display` real size
+    DisplayInfo,
+    /// surface flinger limits in build.prop
+    BuildProp,
+}
+
+impl SizeSource {
+    fn describe(self) -> &'static str {
+        match self {
+            SizeSource::Override => "wm size override",
+            SizeSource::Physical => "wm size physical size",
+            SizeSource::DisplayInfo => "dumpsys display",
+            SizeSource::BuildProp => "build.prop",
+        }
+    }
+}
+
+fn read_config_from_bootanimation(zip_path: &Path) -> Result<Header> {
     println!("Reading configuration from {}...", zip_path.display());
-    
+
     let temp_dir = TempDir::new()?;
     let extract_dir = temp_dir.path();
-    
+
     // extract the zip
-    let file = File::open(zip_path).context("Failed to open bootanimation.zip")?;
-    let mut archive = ZipArchive::new(file)?;
-    
-    for i in 0..archive.len() {
-        let mut file = archive.by_index(i)?;
-        let outpath = extract_dir.join(file.mangled_name());
-        
-        if file.name().ends_with('/') {
-            fs::create_dir_all(&outpath)?;
-        } else {
-            if let Some(p) = outpath.parent() {
-                fs::create_dir_all(p)?;
-            }
-            let mut outfile = File::create(&outpath)?;
-            std::io::copy(&mut file, &mut outfile)?;
-        }
-    }
-    
+    extract_zip(zip_path, extract_dir).context("Failed to open bootanimation.zip")?;
+
     // parse desc.txt
     let desc_path = extract_dir.join("desc.txt");
     if !desc_path.exists() {
         bail!("desc.txt not found in bootanimation.zip");
     }
-    
-    let content = fs::read_to_string(&desc_path)?;
-    
+
+    let header = Desc::read(&desc_path)?.header;
+
+    if header.is_global_format {
+        println!("Loaded configuration (global format):");
+        println!("  Resolution: {}x{}", header.width, header.height);
+        println!("  Offsets: x={}, y={}", header.offset_x, header.offset_y);
+    } else {
+        println!("Loaded configuration (standard format):");
+        println!("  Resolution: {}x{}", header.width, header.height);
+    }
+    println!("  FPS: {}", header.fps);
+
+    Ok(header)
+}
+
+fn read_template(zip_path: &Path) -> Result<Template> {
+    println!("Reading template from {}...", zip_path.display());
+
+    let temp_dir = TempDir::new()?;
+    let extract_dir = temp_dir.path();
+
+    extract_zip(zip_path, extract_dir).context("Failed to open template bootanimation.zip")?;
+
+    let desc_path = extract_dir.join("desc.txt");
+    if !desc_path.exists() {
+        bail!("desc.txt not found in template bootanimation.zip");
+    }
+
+    let desc = Desc::read(&desc_path)?;
+    let mut parts = Vec::new();
+
+    for name in desc.part_dirs() {
+        let part_dir = extract_dir.join(name);
+        if !part_dir.is_dir() {
+            bail!("Template part {} is listed in desc.txt but missing from the zip", name);
+        }
+
+        let frames = list_frames(&part_dir)?.len() as u32;
+
+        let audio_path = part_dir.join("audio.wav");
+        let audio = audio_path.exists().then_some(audio_path);
+
+        // only a trim shared by all frames can be applied to new frames
+        let trim_path = part_dir.join("trim.txt");
+        let trim = if trim_path.exists() {
+            let trims = Trim::read_all(&trim_path)?;
+            if trims.windows(2).all(|w| w[0] == w[1]) {
+                trims.first().copied()
+            } else {
+                eprintln!(
+                    "Warning: {} trims every frame differently, new frames will not be trimmed",
+                    name
+                );
+                None
+            }
+        } else {
+            None
+        };
+
+        parts.push(TemplatePart {
+            name: name.to_string(),
+            frames,
+            audio,
+            trim,
+        });
+    }
+
+    if parts.is_empty() {
+        bail!("Template has no parts to re-skin");
+    }
+
+    let header = &desc.header;
+    println!("Loaded template:");
+    println!("  Resolution: {}x{}", header.width, header.height);
+    println!("  FPS: {}", header.fps);
+    for part in &parts {
+        let mut extras = Vec::new();
+        if part.audio.is_some() {
+            extras.push("audio".to_string());
+        }
+        if let Some(trim) = part.trim {
+            extras.push(format!("trim {}", trim));
+        }
+        if extras.is_empty() {
+            println!("  {}: {} frames", part.name, part.frames);
+        } else {
+            println!("  {}: {} frames ({})", part.name, part.frames, extras.join(", "));
+        }
+    }
+
+    Ok(Template {
+        _dir: temp_dir,
+        desc,
+        parts,
+    })
+}
+
+fn parse_size(value: &str) -> Option<(u32, u32)> {
+    // accepts "1080x2400" as well as "1080 x 2400"
+    let (w, h) = value.split_once('x')?;
+    Some((w.trim().parse().ok()?, h.trim().parse().ok()?))
+}
+
+fn parse_dumpsys_display_info(line: &str) -> Option<(u32, u32)> {
+    let tokens: Vec<&str> = line
+        .split(|c: char| c.is_whitespace() || c == ',')
+        .filter(|t| !t.is_empty())
+        .collect();
+
+    let mut size = None;
+    let mut rotation = 0;
+
+    for (i, token) in tokens.iter().enumerate() {
+        match *token {
+            "real" if size.is_none() && i + 3 < tokens.len() && tokens[i + 2] == "x" => {
+                if let (Ok(w), Ok(h)) = (tokens[i + 1].parse(), tokens[i + 3].parse()) {
+                    size = Some((w, h));
+                }
+            }
+            "rotation" if i + 1 < tokens.len() => {
+                rotation = tokens[i + 1].parse().unwrap_or(0);
+            }
+            _ => {}
+        }
+    }
+
+    // "real" is reported in the current orientation, bootanimation uses the natural one
+    if rotation % 2 == 1 {
+        size = size.map(|(w, h)| (h, w));
+    }
+
+    size
+}
+
+/// Most authoritative display size in a device dump, width and height from the same line
+///
+/// Density is not read: desc.txt has no field for it, frames are drawn pixel for pixel.
+fn parse_device_info(content: &str) -> Option<(SizeSource, (u32, u32))> {
+    let mut sizes = Vec::new();
+    let mut prop_width = None;
+    let mut prop_height = None;
+
     for line in content.lines() {
         let trimmed = line.trim();
         if trimmed.is_empty() || trimmed.starts_with('#') {
             continue;
         }
-        
-        let parts: Vec<&str> = trimmed.split_whitespace().collect();
-        
-        if parts.is_empty() {
-            continue;
+
+        // wm size
+        if let Some(value) = trimmed.strip_prefix("Physical size:") {
+            sizes.extend(parse_size(value).map(|size| (SizeSource::Physical, size)));
+        } else if let Some(value) = trimmed.strip_prefix("Override size:") {
+            sizes.extend(parse_size(value).map(|size| (SizeSource::Override, size)));
+        } else if trimmed.contains("DisplayInfo{") && trimmed.contains(" real ") {
+            // dumpsys display, first display found is the built-in one
+            if !sizes.iter().any(|(source, _)| *source == SizeSource::DisplayInfo) {
+                sizes.extend(
+                    parse_dumpsys_display_info(trimmed).map(|size| (SizeSource::DisplayInfo, size)),
+                );
+            }
+        } else if let Some((key, value)) = trimmed.split_once('=') {
+            // build.prop
+            let value = value.trim();
+            match key.trim() {
+                "ro.surface_flinger.max_graphics_width" => prop_width = value.parse().ok(),
+                "ro.surface_flinger.max_graphics_height" => prop_height = value.parse().ok(),
+                _ => {}
+            }
         }
-        
-        // parse first line (dimensions and fps)
-        if parts[0] == "g" && parts.len() >= 6 {
-            // global format: g width height offsetx offsety fps
-            let width = parts[1].parse().context("Failed to parse width")?;
-            let height = parts[2].parse().context("Failed to parse height")?;
-            let offset_x = parts[3].parse().context("Failed to parse offset_x")?;
-            let offset_y = parts[4].parse().context("Failed to parse offset_y")?;
-            let fps = parts[5].parse().context("Failed to parse fps")?;
-            
-            println!("Loaded configuration (global format):");
-            println!("  Resolution: {}x{}", width, height);
-            println!("  Offsets: x={}, y={}", offset_x, offset_y);
-            println!("  FPS: {}", fps);
-            
-            return Ok(BootAnimConfig {
-                width,
-                height,
-                fps,
-                is_global_format: true,
-                offset_x,
-                offset_y,
-            });
-        } else if parts.len() >= 3 {
-            // original format: width height fps
-            let width = parts[0].parse().context("Failed to parse width")?;
-            let height = parts[1].parse().context("Failed to parse height")?;
-            let fps = parts[2].parse().context("Failed to parse fps")?;
-            
-            println!("Loaded configuration (standard format):");
-            println!("  Resolution: {}x{}", width, height);
-            println!("  FPS: {}", fps);
-            
-            return Ok(BootAnimConfig {
-                width,
-                height,
-                fps,
-                is_global_format: false,
-                offset_x: 0,
-                offset_y: 0,
-            });
+    }
+    sizes.extend(prop_width.zip(prop_height).map(|size| (SizeSource::BuildProp, size)));
+
+    sizes.into_iter().min_by_key(|(source, _)| *source)
+}
+
+fn read_device_info(path: &Path) -> Result<(SizeSource, (u32, u32))> {
+    println!("Reading device information from {}...", path.display());
+
+    let content = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read device info file {}", path.display()))?;
+
+    match parse_device_info(&content) {
+        Some(size) => Ok(size),
+        None => bail!(
+            "No display size found in {} (expected build.prop, `wm size` or `dumpsys display` output)",
+            path.display()
+        ),
+    }
+}
+
+impl PngOptimizer {
+    fn new(options: PngOptions) -> Self {
+        PngOptimizer {
+            options,
+            totals: Mutex::new(Vec::new()),
         }
     }
-    
-    bail!("Unable to parse desc.txt")
+
+    fn optimize(&self, part: &str, data: &[u8]) -> Result<Vec<u8>> {
+        let optimized = optimize_png(data, self.options)?;
+
+        let mut totals = self.totals.lock().unwrap();
+        let index = match totals.iter().position(|(name, _, _)| name == part) {
+            Some(index) => index,
+            None => {
+                totals.push((part.to_string(), 0, 0));
+                totals.len() - 1
+            }
+        };
+        totals[index].1 += data.len() as u64;
+        totals[index].2 += optimized.len() as u64;
+
+        Ok(optimized)
+    }
+
+    /// Optimizes the frames of `part` in place
+    fn optimize_files(&self, part: &str, frames: &[PathBuf], jobs: usize) -> Result<()> {
+        run_parallel(frames, jobs, |_, frame| -> Result<()> {
+            let optimized = self.optimize(part, &fs::read(frame)?)?;
+            Ok(fs::write(frame, optimized)?)
+        })
+        .into_iter()
+        .collect()
+    }
+
+    fn print_summary(&self) {
+        let totals = self.totals.lock().unwrap();
+        let (mut before, mut after) = (0, 0);
+
+        println!("PNG optimization:");
+        for (part, part_before, part_after) in totals.iter() {
+            println!(
+                "  {}: {} -> {} ({} saved)",
+                part,
+                megabytes(*part_before),
+                megabytes(*part_after),
+                saved_percent(*part_before, *part_after)
+            );
+            before += part_before;
+            after += part_after;
+        }
+        println!(
+            "  Total: {} -> {} ({} saved)",
+            megabytes(before),
+            megabytes(after),
+            saved_percent(before, after)
+        );
+    }
+}
+
+impl PartSplitter {
+    fn new(cli: &Cli, fps: u32) -> Self {
+        PartSplitter {
+            max_frames: cli.max_frames.max(1),
+            min_frames: (fps / 2).max(1),
+            scenes: cli
+                .split_at_scenes
+                .then(|| SceneDetector::new(cli.scene_threshold)),
+            sizes: Vec::new(),
+            cuts: 0,
+        }
+    }
+
+    /// Whether `next` looks at the frame data
+    fn needs_frames(&self) -> bool {
+        self.scenes.is_some()
+    }
+
+    /// Part index of the next frame
+    fn next(&mut self, frame: &[u8]) -> Result<usize> {
+        let cut = match &mut self.scenes {
+            Some(scenes) => scenes.is_cut(frame)?,
+            None => false,
+        };
+
+        match self.sizes.last_mut() {
+            Some(size) if *size >= self.max_frames => self.sizes.push(1),
+            Some(size) if cut && *size >= self.min_frames => {
+                self.cuts += 1;
+                self.sizes.push(1);
+            }
+            Some(size) => *size += 1,
+            None => self.sizes.push(1),
+        }
+
+        Ok(self.sizes.len() - 1)
+    }
+
+    fn layout(self) -> Vec<PartLayout> {
+        if self.scenes.is_some() {
+            println!("Split at {} scene cuts", self.cuts);
+        }
+
+        self.sizes
+            .into_iter()
+            .enumerate()
+            .map(|(idx, frames)| PartLayout {
+                name: format!("part{}", idx),
+                frames,
+                audio: true,
+                pause: 0,
+            })
+            .collect()
+    }
+}
+
+impl StaticCollapser {
+    fn new(threshold: f64) -> Self {
+        StaticCollapser {
+            threshold,
+            totals: Mutex::new(Vec::new()),
+        }
+    }
+
+    fn record(&self, part: &str, frames: u32, bytes: u64) {
+        if frames > 0 {
+            self.totals.lock().unwrap().push((part.to_string(), frames, bytes));
+        }
+    }
+
+    /// Deletes the static frames at the end of `part`, returns how many were deleted
+    fn collapse_files(&self, part: &str, frames: &[PathBuf]) -> Result<u32> {
+        let mut run = StaticRun::new(self.threshold);
+        let mut first_static = None;
+        for (idx, frame) in frames.iter().enumerate() {
+            if run.is_static(&fs::read(frame)?)? {
+                first_static.get_or_insert(idx);
+            } else {
+                first_static = None;
+            }
+        }
+
+        let Some(first_static) = first_static else {
+            return Ok(0);
+        };
+        let mut bytes = 0;
+        for frame in &frames[first_static..] {
+            bytes += fs::metadata(frame)?.len();
+            fs::remove_file(frame)?;
+        }
+
+        let dropped = (frames.len() - first_static) as u32;
+        self.record(part, dropped, bytes);
+        Ok(dropped)
+    }
+
+    fn print_summary(&self) {
+        let totals = self.totals.lock().unwrap();
+        if totals.is_empty() {
+            println!("No static frames found at part ends");
+            return;
+        }
+
+        println!("Collapsed static frames into pauses:");
+        for (part, frames, bytes) in totals.iter() {
+            let unit = if *frames == 1 { "frame" } else { "frames" };
+            println!("  {}: {} {} ({} saved)", part, frames, unit, megabytes(*bytes));
+        }
+        let bytes: u64 = totals.iter().map(|(_, _, bytes)| bytes).sum();
+        println!("  Total: {} saved", megabytes(bytes));
+    }
+}
+
+impl<'a> PartWriter<'a> {
+    fn new(
+        zip: &'a mut BootanimationWriter,
+        optimizer: Option<&'a PngOptimizer>,
+        collapser: Option<&'a StaticCollapser>,
+    ) -> Self {
+        PartWriter {
+            zip,
+            optimizer,
+            collapser,
+            run: collapser.map(|c| StaticRun::new(c.threshold)),
+            part: None,
+            pending: Vec::new(),
+            pauses: Vec::new(),
+        }
+    }
+
+    fn add(&mut self, part: &str, name: &str, data: &[u8]) -> Result<()> {
+        if self.part.as_deref() != Some(part) {
+            self.end_part();
+            self.part = Some(part.to_string());
+        }
+
+        if let Some(run) = &mut self.run
+            && run.is_static(data)?
+        {
+            self.pending.push((name.to_string(), data.to_vec()));
+            return Ok(());
+        }
+
+        for (pending_name, pending_data) in std::mem::take(&mut self.pending) {
+            self.write(part, &pending_name, &pending_data)?;
+        }
+        self.write(part, name, data)
+    }
+
+    fn write(&mut self, part: &str, name: &str, data: &[u8]) -> Result<()> {
+        match self.optimizer {
+            Some(optimizer) => self.zip.add(name, &optimizer.optimize(part, data)?),
+            None => self.zip.add(name, data),
+        }
+    }
+
+    /// Static frames still pending at the end of a part are dropped for a pause
+    fn end_part(&mut self) {
+        let Some(part) = self.part.take() else {
+            return;
+        };
+        if let Some(collapser) = self.collapser {
+            let bytes = self.pending.iter().map(|(_, data)| data.len() as u64).sum();
+            collapser.record(&part, self.pending.len() as u32, bytes);
+        }
+        self.pauses.push(self.pending.len() as u32);
+        self.pending.clear();
+        if let Some(run) = &mut self.run {
+            run.reset();
+        }
+    }
+
+    /// Pause frames of every part, in order
+    fn finish(mut self) -> Vec<u32> {
+        self.end_part();
+        self.pauses
+    }
+}
+
+fn saved_percent(before: u64, after: u64) -> String {
+    if before == 0 {
+        return "0%".to_string();
+    }
+    format!("{:.1}%", (before - after) as f64 * 100.0 / before as f64)
+}
+
+/// Frame rate with at most 3 decimals, `29.97` rather than `29.97002997`
+fn describe_rate(rate: f64) -> String {
+    ((rate * 1000.0).round() / 1000.0).to_string()
+}
+
+/// Whether frames have to be converted to play at `fps` for the input's duration
+///
+/// Without probing nothing tells the rates apart; converting keeps the timing anyway.
+fn converts_frame_rate(info: Option<&MediaInfo>, fps: u32) -> bool {
+    info.is_none_or(|i| i.variable_frame_rate || (i.frame_rate - fps as f64).abs() > 1e-3)
+}
+
+/// Decoding settings for the output resolution, format and frame rate
+fn frame_request<'a>(
+    cli: &Cli,
+    header: &Header,
+    background: Option<&'a str>,
+    info: Option<&MediaInfo>,
+    quality: FrameQuality,
+) -> FrameRequest<'a> {
+    let fps = converts_frame_rate(info, header.fps).then_some(header.fps);
+    FrameRequest {
+        width: header.width,
+        height: header.height,
+        scale_mode: cli.scale_mode,
+        background,
+        format: cli.format,
+        quality,
+        fps,
+        fps_mode: cli.fps_mode,
+        start: 0,
+        count: None,
+        frame_rate: match fps {
+            Some(fps) => fps as f64,
+            None => info.map_or(0.0, |i| i.frame_rate),
+        },
+    }
 }
 
 fn extract_frames(
+    backend: &dyn MediaBackend,
     video_path: &Path,
     output_dir: &Path,
-    width: u32,
-    height: u32,
-    format: ImageFormat,
+    request: &FrameRequest,
+) -> Result<u32> {
+    let ext = request.format.extension();
+
+    println!("Extracting frames from video...");
+    let mut count = 0;
+    backend.decode_frames(video_path, request, &mut |frame| {
+        count += 1;
+        Ok(fs::write(output_dir.join(format!("{:06}.{}", count, ext)), frame)?)
+    })
+}
+
+/// Pipes decoded frames straight into the zip, split into parts by `splitter`
+///
+/// Returns the number of frames; only desc.txt and audio are left to be added.
+fn stream_frames_to_zip(
+    cli: &Cli,
+    backend: &dyn MediaBackend,
+    request: &FrameRequest,
+    splitter: &mut PartSplitter,
+    writer: &mut PartWriter,
+) -> Result<u32> {
+    let ext = request.format.extension();
+
+    println!("Streaming frames from video...");
+    let mut count = 0;
+    let total_frames = backend.decode_frames(&cli.input, request, &mut |frame| {
+        let part = format!("part{}", splitter.next(frame)?);
+        let name = format!("{}/{:06}.{}", part, count + 1, ext);
+        count += 1;
+        writer.add(&part, &name, frame)
+    })?;
+
+    if total_frames == 0 {
+        bail!("No frames found to process");
+    }
+
+    Ok(total_frames)
+}
+
+/// Same frames as `extract_frames`, extracted as `--jobs` slices of `--max-frames` frames
+fn extract_frames_parallel(
+    cli: &Cli,
+    backend: &dyn MediaBackend,
+    output_dir: &Path,
+    request: &FrameRequest,
+    info: &MediaInfo,
 ) -> Result<()> {
-    let ffmpeg = get_ffmpeg_path();
-    let ext = match format {
-        ImageFormat::Jpg => "jpg",
-        ImageFormat::Png => "png",
+    let ext = request.format.extension();
+    let slice = cli.max_frames.max(1);
+    let workers = worker_count(cli.jobs) as u32;
+
+    println!("Extracting frames from video with {} jobs...", workers);
+
+    // frames are named after their position in the video, like a single run would
+    let extract_slice = |start: u32| -> Result<u32> {
+        let request = FrameRequest {
+            start,
+            count: Some(slice),
+            ..request.clone()
+        };
+        let mut number = start;
+        backend.decode_frames(&cli.input, &request, &mut |frame| {
+            number += 1;
+            Ok(fs::write(output_dir.join(format!("{:06}.{}", number, ext)), frame)?)
+        })
     };
 
-    let output_pattern = output_dir.join(format!("%06d.{}", ext));
+    // the duration is only an estimate, one extra slice usually finds the end
+    let estimated = (info.duration * request.frame_rate).ceil() as u32;
+    let mut slices: Vec<u32> = (0..=estimated / slice).collect();
 
-    println!("Extracting frames from video...");
-    let status = Command::new(&ffmpeg)
-        .args([
-            "-hide_banner",
-            "-i",
-            video_path.to_str().unwrap(),
-            "-vf",
-            &format!("scale={}:{}", width, height),
-        ])
-        .arg(output_pattern)
-        .status()
-        .context("Failed to extract frames")?;
+    loop {
+        let counts = run_parallel(&slices, cli.jobs, |_, &idx| extract_slice(idx * slice))
+            .into_iter()
+            .collect::<Result<Vec<u32>>>()?;
 
-    if !status.success() {
-        bail!("FFmpeg failed to extract frames");
-    }
+        if let Some(last) = counts.iter().position(|&count| count < slice) {
+            if counts[last + 1..].iter().any(|&count| count > 0) {
+                bail!("Frames past the end of the video, the frame rate reported by ffprobe is not constant");
+            }
+            return Ok(());
+        }
 
-    Ok(())
+        let next = slices.last().map_or(0, |idx| idx + 1);
+        slices = (next..next + workers).collect();
+    }
 }
 
 fn extract_audio_blocks(
+    backend: &dyn MediaBackend,
     video_path: &Path,
     output_dir: &Path,
     fps: u32,
-    duration: f64,
+    layout: &[PartLayout],
+    first_frame: u32,
 ) -> Result<()> {
     if fps == 0 {
         bail!("Invalid fps: 0");
     }
 
-    let frame_block_duration = 400.0 / fps as f64;
-    let mut start_time = 0.0;
-    let mut part = 0;
-
-    println!("Extracting audio blocks...");
+    println!("Extracting audio blocks...");
+
+    let mut start_frame = first_frame;
+
+    for (part, entry) in layout.iter().enumerate() {
+        let start_time = start_frame as f64 / fps as f64;
+        let block_duration = entry.frames as f64 / fps as f64;
+        start_frame += entry.frames;
+
+        if !entry.audio {
+            continue;
+        }
+
+        let output_audio = output_dir.join(format!("audio{}.wav", part));
+        if let Err(e) = backend.decode_audio(video_path, start_time, block_duration, &output_audio)
+        {
+            eprintln!("Warning: Failed to extract audio for block {}: {}", part, e);
+        }
+    }
+
+    Ok(())
+}
+
+fn rescale(value: u32, from: u32, to: u32) -> u32 {
+    if from == to || from == 0 {
+        return value;
+    }
+    (value as f64 * to as f64 / from as f64).round() as u32
+}
+
+fn template_layout(
+    template: &Template,
+    total_frames: u32,
+    fps: u32,
+    mapping: TemplateMapping,
+) -> Result<Vec<PartLayout>> {
+    let template_fps = template.desc.header.fps;
+    let weights: Vec<u32> = template.parts.iter().map(|p| p.frames).collect();
+    let wanted_parts = weights.iter().filter(|&&w| w > 0).count() as u32;
+
+    let counts = match mapping {
+        TemplateMapping::Duration => {
+            let mut remaining = total_frames;
+            let mut counts = Vec::new();
+            for &frames in &weights {
+                let wanted = match rescale(frames, template_fps, fps) {
+                    0 if frames > 0 => 1,
+                    n => n,
+                };
+                let given = wanted.min(remaining);
+                if given == 0 && wanted > 0 {
+                    bail!(
+                        "Video is too short for the template durations ({} frames available at {} fps)",
+                        total_frames,
+                        fps
+                    );
+                }
+                remaining -= given;
+                counts.push(given);
+            }
+            if remaining > 0 {
+                println!("Template is shorter than the video, dropping the last {} frames", remaining);
+            }
+            counts
+        }
+        TemplateMapping::Proportion => {
+            if total_frames < wanted_parts {
+                bail!(
+                    "Video has {} frames but the template needs at least {}",
+                    total_frames,
+                    wanted_parts
+                );
+            }
+
+            // every non-empty part gets one frame, the rest is shared out by
+            // largest remainder so the counts add up exactly
+            let total_weight: u64 = weights.iter().map(|&w| w as u64).sum();
+            let spare = (total_frames - wanted_parts) as u64;
+            let mut counts: Vec<u32> = Vec::new();
+            let mut remainders: Vec<(u64, usize)> = Vec::new();
+            for (idx, &w) in weights.iter().enumerate() {
+                if w == 0 {
+                    counts.push(0);
+                    continue;
+                }
+                let share = spare * w as u64;
+                counts.push(1 + (share / total_weight) as u32);
+                remainders.push((share % total_weight, idx));
+            }
+            let assigned: u32 = counts.iter().sum();
+            remainders.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+            for &(_, idx) in remainders.iter().take((total_frames - assigned) as usize) {
+                counts[idx] += 1;
+            }
+            counts
+        }
+    };
+
+    Ok(template
+        .parts
+        .iter()
+        .zip(counts)
+        .map(|(part, frames)| PartLayout {
+            name: part.name.clone(),
+            frames,
+            audio: part.audio.is_some(),
+            pause: 0,
+        })
+        .collect())
+}
+
+/// `--find-loop`: keeps the frames of the most seamless loop, crossfading its seam
+///
+/// Returns the kept frames and the position of the first one in the video.
+fn find_seamless_loop(
+    cli: &Cli,
+    frames: Vec<PathBuf>,
+    fps: u32,
+    request: &FrameRequest,
+) -> Result<(Vec<PathBuf>, u32)> {
+    let seconds = cli.find_loop.unwrap_or(0.0);
+    let fade = cli.loop_crossfade as usize;
+    let total = frames.len();
+    let window = ((seconds * fps as f64).round() as usize).max(1);
+
+    // the crossfade needs the frames leading to the loop start
+    let starts = fade.min(total)..(fade + window).min(total);
+    let ends = total.saturating_sub(window).max(starts.start)..total;
+
+    println!("Looking for a seamless loop...");
+    let thumbnails = |range: std::ops::Range<usize>| -> Result<Vec<_>> {
+        run_parallel(&frames[range], cli.jobs, |_, frame| thumbnail(&fs::read(frame)?))
+            .into_iter()
+            .collect()
+    };
+    let start_thumbnails = thumbnails(starts.clone())?;
+    let end_thumbnails = thumbnails(ends.clone())?;
+
+    // frame `end` should look like frame `start`, so the loop is `start..end`
+    let Some((s, e, difference)) = best_match(&start_thumbnails, &end_thumbnails, |s, e| {
+        ends.start + e > starts.start + s + fade
+    }) else {
+        bail!(
+            "Video is too short to find a loop with a {} frame crossfade",
+            fade
+        );
+    };
+    let (start, end) = (starts.start + s, ends.start + e);
+
+    println!(
+        "Best loop: frames {}-{} ({:.2}s-{:.2}s), seam difference {:.2}",
+        start + 1,
+        end,
+        start as f64 / fps as f64,
+        end as f64 / fps as f64,
+        difference
+    );
+    if end - start > cli.max_frames as usize {
+        eprintln!(
+            "Warning: the loop is {} frames long but parts hold {} (--max-frames); every part loops on its own",
+            end - start,
+            cli.max_frames
+        );
+    }
+    if cli.loop_mode != LoopMode::LoopInfinite {
+        eprintln!("Warning: the loop only repeats with --loop-mode loop-infinite");
+    }
+
+    // the last frames turn into the ones leading to the start
+    if fade > 0 {
+        println!("Crossfading {} frames at the seam...", fade);
+    }
+    for i in 0..fade {
+        let tail = &frames[end - fade + i];
+        let lead_in = decode_encoded(&fs::read(&frames[start - fade + i])?)?;
+        let amount = (i + 1) as f64 / (fade + 1) as f64;
+        let mixed = blend(&decode_encoded(&fs::read(tail)?)?, &lead_in, amount);
+        fs::write(tail, encode_frame(&mixed, request.format, request.quality)?)?;
+    }
+
+    for frame in frames[..start].iter().chain(&frames[end..]) {
+        fs::remove_file(frame)?;
+    }
+    Ok((frames[start..end].to_vec(), start as u32))
+}
+
+fn organize_frames_into_parts(
+    frames: &[PathBuf],
+    result_dir: &Path,
+    layout: &[PartLayout],
+) -> Result<()> {
+    println!("Organizing frames into parts...");
+
+    let mut frames = frames.iter();
+
+    for part in layout {
+        let part_dir = result_dir.join(&part.name);
+        fs::create_dir_all(&part_dir)?;
+
+        for frame in frames.by_ref().take(part.frames as usize) {
+            let dest = part_dir.join(frame.file_name().unwrap());
+            fs::rename(frame, &dest)?;
+        }
+    }
+
+    Ok(())
+}
+
+fn add_audio_to_parts(audio_dir: &Path, result_dir: &Path, layout: &[PartLayout]) -> Result<()> {
+    println!("Adding audio to parts...");
+
+    for (part_idx, part) in layout.iter().enumerate() {
+        if !part.audio {
+            continue;
+        }
+
+        let audio_file = audio_dir.join(format!("audio{}.wav", part_idx));
+        let dest_audio = result_dir.join(&part.name).join("audio.wav");
+
+        if audio_file.exists() {
+            fs::copy(&audio_file, &dest_audio)
+                .context(format!("Failed to copy audio for part {}", part_idx))?;
+            println!("Added audio to {}", part.name);
+        } else {
+            eprintln!("Warning: Audio file {} not found", audio_file.display());
+        }
+    }
+
+    Ok(())
+}
+
+fn copy_template_audio(template: &Template, result_dir: &Path) -> Result<()> {
+    for part in &template.parts {
+        if let Some(ref audio) = part.audio {
+            fs::copy(audio, result_dir.join(&part.name).join("audio.wav"))
+                .context(format!("Failed to copy template audio for {}", part.name))?;
+            println!("Kept template audio for {}", part.name);
+        }
+    }
+
+    Ok(())
+}
+
+fn trim_part_frames(part_dir: &Path, trim: Trim, request: &FrameRequest) -> Result<()> {
+    let frames = list_frames(part_dir)?;
+
+    for frame in &frames {
+        let image = decode_frame(frame)?;
+        let cropped = imageops::crop_imm(&image, trim.x, trim.y, trim.width, trim.height).to_image();
+        let rgb = image::DynamicImage::ImageRgba8(cropped).into_rgb8();
+        fs::write(frame, encode_frame(&rgb, request.format, request.quality)?)?;
+    }
+
+    let trim_lines: String = frames.iter().map(|_| format!("{}\n", trim)).collect();
+    fs::write(part_dir.join("trim.txt"), trim_lines)?;
+
+    Ok(())
+}
+
+fn build_desc(
+    header: Header,
+    layout: &[PartLayout],
+    loop_mode: LoopMode,
+    background: Option<&str>,
+) -> Desc {
+    let parts = layout
+        .iter()
+        .map(|entry| {
+            let mut part = match loop_mode {
+                LoopMode::StopOnBoot => Part::new(PartKind::Interruptible, 1, 0, &entry.name),
+                LoopMode::PlayFull => Part::new(PartKind::Complete, 1, 0, &entry.name),
+                LoopMode::LoopInfinite => Part::new(PartKind::Complete, 0, 0, &entry.name),
+            };
+            part.background = background.map(|bg| bg.to_string());
+            part.pause = entry.pause;
+            part
+        })
+        .collect();
+
+    Desc {
+        header,
+        extra_lines: Vec::new(),
+        parts,
+    }
+}
+
+fn template_desc(template: &Template, header: Header, layout: &[PartLayout]) -> Desc {
+    let mut desc = template.desc.clone();
+    let template_fps = desc.header.fps;
+
+    // pauses and fades are counted in frames, keep their duration
+    for part in &mut desc.parts {
+        let collapsed = layout
+            .iter()
+            .find(|entry| entry.name == part.path)
+            .map_or(0, |entry| entry.pause);
+        part.pause = rescale(part.pause, template_fps, header.fps) + collapsed;
+        part.fade_frames = part
+            .fade_frames
+            .map(|frames| rescale(frames, template_fps, header.fps));
+    }
+
+    desc.header = header;
+    desc
+}
+
+/// Renders the frames of a decoded animation resampled to `fps`
+///
+/// Returns the source frame of every output frame, and the encoded source frames.
+fn render_animation(
+    cli: &Cli,
+    animation: &Animation,
+    header: &Header,
+    request: &FrameRequest,
+) -> Result<(Vec<usize>, Vec<Vec<u8>>)> {
+    let indices = animation.resample(header.fps);
+    let mut used = indices.clone();
+    used.dedup();
+
+    println!("Rendering {} frames in-process...", indices.len());
+    let rendered = run_parallel(&used, cli.jobs, |_, &source| {
+        render_frame(
+            &animation.frames[source],
+            request.width,
+            request.height,
+            request.scale_mode,
+            request.background,
+            request.format,
+            request.quality,
+        )
+    })
+    .into_iter()
+    .collect::<Result<Vec<_>>>()?;
+
+    // frames skipped by the resampling stay empty
+    let mut encoded = vec![Vec::new(); animation.frames.len()];
+    for (source, data) in used.into_iter().zip(rendered) {
+        encoded[source] = data;
+    }
+
+    Ok((indices, encoded))
+}
+
+/// Renders the frames of an image input in order, passing each encoded frame to `sink`
+fn render_image_input(
+    cli: &Cli,
+    input: &ImageInput,
+    header: &Header,
+    request: &FrameRequest,
+    mut sink: impl FnMut(usize, &[u8]) -> Result<()>,
+) -> Result<u32> {
+    match input {
+        ImageInput::Animation(animation) => {
+            let (indices, encoded) = render_animation(cli, animation, header, request)?;
+            for (idx, &source) in indices.iter().enumerate() {
+                sink(idx, &encoded[source])?;
+            }
+            Ok(indices.len() as u32)
+        }
+        ImageInput::Sequence(frames) => {
+            // another rate shows every image for the same time as before, each output
+            // frame shows the image playing at its time, or blends it with the next one
+            let last = frames.len() - 1;
+            let sources: Vec<(usize, f64)> = match (request.fps, cli.fps) {
+                (Some(fps), Some(rate)) => {
+                    let count = ((frames.len() as f64 * fps as f64 / rate).round() as usize).max(1);
+                    (0..count)
+                        .map(|idx| {
+                            let position = idx as f64 * rate / fps as f64;
+                            let first = (position as usize).min(last);
+                            match request.fps_mode {
+                                FpsMode::Drop => (first, 0.0),
+                                _ => (first, position - first as f64),
+                            }
+                        })
+                        .collect()
+                }
+                _ => (0..frames.len()).map(|idx| (idx, 0.0)).collect(),
+            };
+            println!("Rendering {} frames in-process...", sources.len());
+
+            // decode a few batches ahead at most, sequences can be long
+            let batch = worker_count(cli.jobs) * 4;
+            for (batch_idx, sources) in sources.chunks(batch).enumerate() {
+                let rendered = run_parallel(sources, cli.jobs, |_, &(first, amount)| {
+                    let mut image = decode_frame(&frames[first])?;
+                    if amount > 0.0 && first < last {
+                        image = blend(&image, &decode_frame(&frames[first + 1])?, amount);
+                    }
+                    render_frame(
+                        &image,
+                        request.width,
+                        request.height,
+                        request.scale_mode,
+                        request.background,
+                        request.format,
+                        request.quality,
+                    )
+                });
+                for (idx, data) in rendered.into_iter().enumerate() {
+                    sink(batch_idx * batch + idx, &data?)?;
+                }
+            }
+            Ok(sources.len() as u32)
+        }
+    }
+}
+
+/// Writes the frames of an image input into the zip, split into parts by `splitter`
+fn image_frames_to_zip(
+    cli: &Cli,
+    header: &Header,
+    request: &FrameRequest,
+    input: &ImageInput,
+    splitter: &mut PartSplitter,
+    writer: &mut PartWriter,
+) -> Result<u32> {
+    render_image_input(cli, input, header, request, |idx, data| {
+        let part = format!("part{}", splitter.next(data)?);
+        let name = format!("{}/{:06}.{}", part, idx + 1, cli.format.extension());
+        writer.add(&part, &name, data)
+    })
+}
 
-    let ffmpeg = get_ffmpeg_path();
+/// Everything an output is built from, apart from the settings `--max-size` lowers
+struct Build<'a> {
+    cli: &'a Cli,
+    backend: &'a dyn MediaBackend,
+    template: Option<&'a Template>,
+    background: Option<&'a str>,
+    has_audio: bool,
+    info: Option<&'a MediaInfo>,
+    image_input: Option<&'a ImageInput>,
+    static_threshold: Option<f64>,
+}
 
-    while start_time < duration {
-        let output_audio = output_dir.join(format!("audio{}.wav", part));
+impl Build<'_> {
+    /// Writes `cli.output`, removing it again on failure
+    fn run(&self, header: Header, settings: FrameSettings) -> Result<()> {
+        let request =
+            frame_request(self.cli, &header, self.background, self.info, settings.quality);
+        let optimizer = settings.png.map(PngOptimizer::new);
+        let collapser = self.static_threshold.map(StaticCollapser::new);
+        let result = match self.template {
+            Some(template) => self.write_from_template(
+                template,
+                header,
+                &request,
+                optimizer.as_ref(),
+                collapser.as_ref(),
+            ),
+            None => {
+                self.write_bootanimation(header, &request, optimizer.as_ref(), collapser.as_ref())
+            }
+        };
+        if result.is_err() {
+            let _ = fs::remove_file(&self.cli.output);
+        }
+        result?;
 
-        let status = Command::new(&ffmpeg)
-            .args([
-                "-hide_banner",
-                "-y",
-                "-i",
-                video_path.to_str().unwrap(),
-                "-ss",
-                &start_time.to_string(),
-                "-t",
-                &frame_block_duration.to_string(),
-                "-vn",
-                "-acodec",
-                "pcm_s16le",
-                "-ar",
-                "44100",
-                "-ac",
-                "2",
-            ])
-            .arg(&output_audio)
-            .status()
-            .context(format!("Failed to extract audio block {}", part))?;
-
-        if !status.success() {
-            eprintln!("Warning: Failed to extract audio for block {}", part);
-        }
-
-        start_time += frame_block_duration;
-        part += 1;
+        if let Some(collapser) = collapser {
+            collapser.print_summary();
+        }
+        if let Some(optimizer) = optimizer {
+            optimizer.print_summary();
+        }
+        Ok(())
     }
 
-    Ok(())
-}
+    /// Without a template nothing has to be known up front, so frames go straight into the zip
+    fn write_bootanimation(
+        &self,
+        header: Header,
+        request: &FrameRequest,
+        optimizer: Option<&PngOptimizer>,
+        collapser: Option<&StaticCollapser>,
+    ) -> Result<()> {
+        let cli = self.cli;
+        let backend = self.backend;
+        let mut zip = BootanimationWriter::create(&cli.output)?;
+        let mut splitter = PartSplitter::new(cli, header.fps);
+        // frames go through a temporary folder when the loop finder needs all of them,
+        // or when parallel extraction writes them out of order
+        let parallel = self.image_input.is_none() && self.info.is_some() && cli.jobs != 1;
+        let mut first_frame = 0;
+        let layout = if cli.find_loop.is_some() || parallel {
+            let temp_dir = TempDir::new()?;
+            let mut frames = self.extract_frames_to(temp_dir.path(), &header, request)?;
+            if cli.find_loop.is_some() {
+                (frames, first_frame) = find_seamless_loop(cli, frames, header.fps, request)?;
+            }
+            self.files_to_zip(&mut zip, &frames, splitter, optimizer, collapser)?
+        } else {
+            let mut writer = PartWriter::new(&mut zip, optimizer, collapser);
+            match self.image_input {
+                Some(input) => {
+                    image_frames_to_zip(cli, &header, request, input, &mut splitter, &mut writer)?
+                }
+                None => stream_frames_to_zip(cli, backend, request, &mut splitter, &mut writer)?,
+            };
+            let mut layout = splitter.layout();
+            for (part, pause) in layout.iter_mut().zip(writer.finish()) {
+                part.pause = pause;
+            }
+            layout
+        };
+        println!("Created {} parts", layout.len());
+
+        if self.has_audio {
+            let temp_dir = TempDir::new()?;
+            extract_audio_blocks(
+                backend,
+                &cli.input,
+                temp_dir.path(),
+                header.fps,
+                &layout,
+                first_frame,
+            )?;
+
+            println!("Adding audio to parts...");
+            for (part_idx, part) in layout.iter().enumerate() {
+                let audio_file = temp_dir.path().join(format!("audio{}.wav", part_idx));
+                if audio_file.exists() {
+                    zip.add_file(&format!("{}/audio.wav", part.name), &audio_file)?;
+                    println!("Added audio to {}", part.name);
+                } else {
+                    eprintln!("Warning: Audio file {} not found", audio_file.display());
+                }
+            }
+        }
 
-fn validate_color(color: &str) -> Result<String> {
-    let color = color.trim_start_matches('#');
+        // desc.txt is only known once every frame is written, so the archive is
+        // reordered to put it first
+        let desc = build_desc(header, &layout, cli.loop_mode, self.background);
+        zip.add("desc.txt", desc.to_string().as_bytes())?;
+        zip.finish_in_canonical_order()
+    }
+
+    /// Decodes every frame into `dir`, returns them in order
+    fn extract_frames_to(
+        &self,
+        dir: &Path,
+        header: &Header,
+        request: &FrameRequest,
+    ) -> Result<Vec<PathBuf>> {
+        let (cli, backend) = (self.cli, self.backend);
+        match (self.image_input, self.info) {
+            (Some(input), _) => {
+                render_image_input(cli, input, header, request, |idx, data| {
+                    let name = format!("{:06}.{}", idx + 1, cli.format.extension());
+                    Ok(fs::write(dir.join(name), data)?)
+                })?;
+            }
+            (None, Some(p)) if cli.jobs != 1 => {
+                extract_frames_parallel(cli, backend, dir, request, p)?
+            }
+            _ => {
+                extract_frames(backend, &cli.input, dir, request)?;
+            }
+        }
 
-    if color.len() != 6 && color.len() != 3 {
-        bail!("Invalid color format. Use #RRGGBB or #RGB");
+        let frames = list_frames(dir)?;
+        if frames.is_empty() {
+            bail!("No frames found to process");
+        }
+        Ok(frames)
     }
 
-    if !color.chars().all(|c| c.is_ascii_hexdigit()) {
-        bail!("Invalid hex color code");
+    /// Frames extracted to disk, added to the zip like streamed frames
+    fn files_to_zip(
+        &self,
+        zip: &mut BootanimationWriter,
+        frames: &[PathBuf],
+        mut splitter: PartSplitter,
+        optimizer: Option<&PngOptimizer>,
+        collapser: Option<&StaticCollapser>,
+    ) -> Result<Vec<PartLayout>> {
+        for frame in frames {
+            let data = if splitter.needs_frames() {
+                fs::read(frame)?
+            } else {
+                Vec::new()
+            };
+            splitter.next(&data)?;
+        }
+        let mut layout = splitter.layout();
+        let mut frames = frames.iter();
+        for part in &mut layout {
+            let mut part_frames: Vec<PathBuf> =
+                frames.by_ref().take(part.frames as usize).cloned().collect();
+            if let Some(collapser) = collapser {
+                part.pause = collapser.collapse_files(&part.name, &part_frames)?;
+                part_frames.truncate(part_frames.len() - part.pause as usize);
+            }
+            if let Some(optimizer) = optimizer {
+                optimizer.optimize_files(&part.name, &part_frames, self.cli.jobs)?;
+            }
+            for frame in &part_frames {
+                let name = format!("{}/{}", part.name, frame.file_name().unwrap().to_string_lossy());
+                zip.add_file(&name, frame)?;
+            }
+        }
+
+        Ok(layout)
     }
 
-    Ok(format!("#{}", color))
-}
+    fn write_from_template(
+        &self,
+        template: &Template,
+        header: Header,
+        request: &FrameRequest,
+        optimizer: Option<&PngOptimizer>,
+        collapser: Option<&StaticCollapser>,
+    ) -> Result<()> {
+        let cli = self.cli;
+        let backend = self.backend;
+
+        // create temporary directory
+        let temp_dir = TempDir::new()?;
+        let frames_dir = temp_dir.path().join("frames");
+        let audio_dir = temp_dir.path().join("audio");
+        let result_dir = temp_dir.path().join("result");
+
+        fs::create_dir_all(&frames_dir)?;
+        fs::create_dir_all(&result_dir)?;
+
+        // extract frames
+        let frames = self.extract_frames_to(&frames_dir, &header, request)?;
+        let total_frames = frames.len() as u32;
+
+        // decide how frames are split into parts
+        let mut layout =
+            template_layout(template, total_frames, header.fps, cli.template_mapping)?;
+
+        // extract audio if requested
+        if self.has_audio {
+            fs::create_dir_all(&audio_dir)?;
+            extract_audio_blocks(backend, &cli.input, &audio_dir, header.fps, &layout, 0)?;
+        }
 
-fn organize_frames_into_parts(
-    frames_dir: &Path,
-    result_dir: &Path,
-    max_frames: u32,
-) -> Result<u32> {
-    println!("Organizing frames into parts...");
+        // organize frames into parts
+        organize_frames_into_parts(&frames, &result_dir, &layout)?;
+        println!("Created {} parts", layout.len());
 
-    let mut frames: Vec<PathBuf> = fs::read_dir(frames_dir)?
-        .filter_map(|entry| entry.ok())
-        .map(|entry| entry.path())
-        .filter(|path| path.is_file())
-        .collect();
+        if let Some(collapser) = collapser {
+            for part in &mut layout {
+                let frames = list_frames(&result_dir.join(&part.name))?;
+                part.pause = collapser.collapse_files(&part.name, &frames)?;
+            }
+        }
 
-    frames.sort();
+        // add audio to parts if requested
+        if self.has_audio {
+            add_audio_to_parts(&audio_dir, &result_dir, &layout)?;
+        } else {
+            copy_template_audio(template, &result_dir)?;
+        }
 
-    if frames.is_empty() {
-        bail!("No frames found to process");
-    }
+        // apply template trims, scaled to the output resolution
+        let (template_width, template_height) =
+            (template.desc.header.width, template.desc.header.height);
+        for part in &template.parts {
+            if let Some(trim) = part.trim {
+                let trim = Trim {
+                    width: rescale(trim.width, template_width, header.width),
+                    height: rescale(trim.height, template_height, header.height),
+                    x: rescale(trim.x, template_width, header.width),
+                    y: rescale(trim.y, template_height, header.height),
+                };
+                println!("Trimming {} to {}", part.name, trim);
+                trim_part_frames(&result_dir.join(&part.name), trim, request)?;
+            }
+        }
 
-    let mut part_index = 0;
-    let mut frame_index = 0;
-    let mut part_dir = result_dir.join(format!("part{}", part_index));
-    fs::create_dir_all(&part_dir)?;
+        if let Some(optimizer) = optimizer {
+            println!("Optimizing PNG frames...");
+            for part in &layout {
+                let frames = list_frames(&result_dir.join(&part.name))?;
+                optimizer.optimize_files(&part.name, &frames, cli.jobs)?;
+            }
+        }
 
-    for frame in frames {
-        let dest = part_dir.join(frame.file_name().unwrap());
-        fs::rename(&frame, &dest)?;
+        // create desc.txt
+        let desc = template_desc(template, header, &layout);
+        desc.write(&result_dir.join("desc.txt"))?;
 
-        frame_index += 1;
+        // create bootanimation.zip
+        create_bootanimation_zip(&result_dir, &cli.output)
+    }
+}
 
-        if frame_index >= max_frames {
-            frame_index = 0;
-            part_index += 1;
-            part_dir = result_dir.join(format!("part{}", part_index));
-            fs::create_dir_all(&part_dir)?;
+/// Frame settings `--max-size` tries, from `base` down to the smallest
+///
+/// PNG frames first lose color depth, then are quantized to ever smaller palettes.
+fn quality_steps(format: ImageFormat, base: FrameSettings) -> Vec<FrameSettings> {
+    let default = FrameQuality::default();
+    match format {
+        ImageFormat::Jpg => std::iter::once(base.quality.jpeg)
+            .chain(
+                [85, 75, 65, 55, 45, 35, 25]
+                    .into_iter()
+                    .filter(|&q| base.quality.jpeg.is_none_or(|base| q < base))
+                    .map(Some),
+            )
+            .map(|jpeg| FrameSettings {
+                quality: FrameQuality { jpeg },
+                png: base.png,
+            })
+            .collect(),
+        ImageFormat::Png => {
+            let base_palette = base.png.and_then(|png| png.palette);
+            let dither = base.png.is_none_or(|png| png.dither);
+            // fewer bits do little for frames already quantized by --png-colors
+            let posterized = [8, 7, 6, 5, 4, 3]
+                .into_iter()
+                .filter(|_| base_palette.is_none())
+                .map(|bits| FrameSettings {
+                    quality: default,
+                    png: Some(PngOptions {
+                        palette: None,
+                        dither,
+                        bits: (bits < 8).then_some(bits),
+                    }),
+                });
+            let quantized = [256, 128, 64, 32, 16]
+                .into_iter()
+                .filter(|&colors| base_palette.is_none_or(|base| colors < base))
+                .map(|colors| FrameSettings {
+                    quality: default,
+                    png: Some(PngOptions {
+                        palette: Some(colors),
+                        dither,
+                        bits: None,
+                    }),
+                });
+            std::iter::once(base).chain(posterized).chain(quantized).collect()
         }
     }
+}
 
-    Ok(part_index)
+fn describe_quality(format: ImageFormat, settings: FrameSettings) -> String {
+    match (format, settings.png) {
+        (ImageFormat::Jpg, _) => match settings.quality.jpeg {
+            Some(q) => format!("JPEG quality {}", q),
+            None => "default JPEG quality".to_string(),
+        },
+        (ImageFormat::Png, None) => "default PNG compression".to_string(),
+        (ImageFormat::Png, Some(png)) => match (png.palette, png.bits) {
+            (Some(colors), _) => format!("PNG quantized to {} colors", colors),
+            (None, Some(bits)) => format!("PNG reduced to {} bits per channel", bits),
+            (None, None) => "PNG maximum compression".to_string(),
+        },
+    }
 }
 
-fn add_audio_to_parts(audio_dir: &Path, result_dir: &Path, num_parts: u32) -> Result<()> {
-    println!("Adding audio to parts...");
+/// Rebuilds the output with lower settings until it is at most `max_size` megabytes
+fn fit_size_budget(
+    build: &Build,
+    header: Header,
+    settings: FrameSettings,
+    max_size: f64,
+) -> Result<()> {
+    let cli = build.cli;
+    if max_size <= 0.0 {
+        bail!("--max-size must be greater than 0");
+    }
+    let budget = (max_size * 1024.0 * 1024.0) as u64;
 
-    for part_idx in 0..=num_parts {
-        let audio_file = audio_dir.join(format!("audio{}.wav", part_idx));
-        let part_dir = result_dir.join(format!("part{}", part_idx));
-        let dest_audio = part_dir.join("audio.wav");
+    let qualities = quality_steps(cli.format, settings);
+    let mut rates: Vec<u32> = [1.0, 0.8, 2.0 / 3.0, 0.5, 0.4, 1.0 / 3.0]
+        .iter()
+        .map(|factor| ((header.fps as f64 * factor).round() as u32).max(1))
+        .collect();
+    rates.dedup();
+    // even sizes keep video encoders happy when the result is converted back
+    let mut sizes: Vec<(u32, u32)> = [1.0, 0.9, 0.8, 0.7, 0.6, 0.5]
+        .iter()
+        .map(|factor| {
+            let scale = |value: u32| (((value as f64 * factor / 2.0).round() as u32) * 2).max(2);
+            if *factor == 1.0 {
+                (header.width, header.height)
+            } else {
+                (scale(header.width), scale(header.height))
+            }
+        })
+        .collect();
+    sizes.dedup();
+
+    let (mut quality, mut rate, mut size) = (0, 0, 0);
+    loop {
+        let fps = rates[rate];
+        let (width, height) = sizes[size];
+        let settings = format!(
+            "{}, {} fps, {}x{}",
+            describe_quality(cli.format, qualities[quality]),
+            fps,
+            width,
+            height
+        );
+        println!("\nTrying {}...", settings);
+
+        let attempt = Header {
+            fps,
+            ..header.resized(width, height)
+        };
+        build.run(attempt, qualities[quality])?;
+
+        let written = fs::metadata(&cli.output)?.len();
+        if written <= budget {
+            println!(
+                "\nFits the {} MB budget with {}: {}",
+                max_size,
+                settings,
+                megabytes(written)
+            );
+            return Ok(());
+        }
+        println!("{} is over the {} MB budget", megabytes(written), max_size);
+
+        // lower the first setting, in priority order, that has a smaller step left
+        let mut lowered = false;
+        for setting in &cli.size_priority {
+            let (current, steps) = match setting {
+                SizeSetting::Quality => (&mut quality, qualities.len()),
+                SizeSetting::Fps => (&mut rate, rates.len()),
+                SizeSetting::Resolution => (&mut size, sizes.len()),
+            };
+            if *current + 1 < steps {
+                *current += 1;
+                lowered = true;
+                break;
+            }
+        }
 
-        if audio_file.exists() {
-            fs::copy(&audio_file, &dest_audio)
-                .context(format!("Failed to copy audio for part {}", part_idx))?;
-            println!("Added audio to part{}", part_idx);
-        } else {
-            eprintln!("Warning: Audio file {} not found", audio_file.display());
+        if !lowered {
+            let _ = fs::remove_file(&cli.output);
+            bail!(
+                "Even the smallest settings ({}) give {}, over the {} MB budget",
+                settings,
+                megabytes(written),
+                max_size
+            );
         }
     }
-
-    Ok(())
 }
 
-fn create_desc_file(
-    result_dir: &Path,
-    width: u32,
-    height: u32,
-    fps: u32,
-    num_parts: u32,
-    loop_mode: LoopMode,
-    background: Option<&str>,
-    use_global_format: bool,
-    offset_x: u32,
-    offset_y: u32,
-) -> Result<()> {
-    let desc_path = result_dir.join("desc.txt");
-    let mut file = File::create(desc_path)?;
-
-    // write first line based on format type
-    if use_global_format {
-        writeln!(file, "g {} {} {} {} {}", width, height, offset_x, offset_y, fps)?;
-    } else {
-        writeln!(file, "{} {} {}", width, height, fps)?;
+/// Lowers the frame rate or resolution until `--max-total-frames` and `--max-part-memory` hold
+///
+/// Frame counts are estimated from the input duration, nothing is decoded yet.
+fn fit_frame_budget(cli: &Cli, mut header: Header, duration: f64) -> Result<Header> {
+    let frames_at = |fps: u32| (duration * fps as f64).ceil() as u32;
+    let part_frames = |fps: u32| frames_at(fps).clamp(1, cli.max_frames.max(1));
+
+    println!("\nFrame budget:");
+    if let Some(limit) = cli.max_total_frames {
+        let frames = frames_at(header.fps);
+        if frames <= limit {
+            println!(
+                "  {:.2}s at {} fps is {} frames, within the {} frame limit",
+                duration, header.fps, frames, limit
+            );
+        } else {
+            println!(
+                "  {:.2}s at {} fps is {} frames, over the {} frame limit",
+                duration, header.fps, frames, limit
+            );
+            let Some(fps) = (1..header.fps).rev().find(|&fps| frames_at(fps) <= limit) else {
+                bail!("{:.2}s does not fit in {} frames even at 1 fps", duration, limit);
+            };
+            println!(
+                "  Using {} fps ({} frames), the highest rate that fits",
+                fps,
+                frames_at(fps)
+            );
+            header.fps = fps;
+        }
     }
 
-    for part_idx in 0..=num_parts {
-        let line = match loop_mode {
-            LoopMode::StopOnBoot => {
-                if let Some(bg) = background {
-                    format!("p 1 0 part{} {}", part_idx, bg)
-                } else {
-                    format!("p 1 0 part{}", part_idx)
-                }
+    if let Some(max_memory) = cli.max_part_memory {
+        if max_memory <= 0.0 {
+            bail!("--max-part-memory must be greater than 0");
+        }
+        let budget = (max_memory * 1024.0 * 1024.0) as u64;
+        let memory = |header: &Header| part_memory(header, part_frames(header.fps));
+
+        let needed = memory(&header);
+        println!(
+            "  Parts hold up to {} frames of {}x{}: {} decoded, {} the {} MB limit",
+            part_frames(header.fps),
+            header.width,
+            header.height,
+            megabytes(needed),
+            if needed <= budget { "within" } else { "over" },
+            max_memory
+        );
+        if needed > budget {
+            let fitting_frames = header.frames_within(budget);
+            if fitting_frames > 0 {
+                println!(
+                    "  (--max-frames {} would also fit, with more but shorter parts)",
+                    fitting_frames
+                );
             }
-            LoopMode::PlayFull => {
-                if let Some(bg) = background {
-                    format!("c 1 0 part{} {}", part_idx, bg)
-                } else {
-                    format!("c 1 0 part{}", part_idx)
+
+            match cli.part_memory_fit {
+                MemoryFit::Fps => {
+                    // full parts hold --max-frames at any rate, only a shorter animation gets smaller
+                    let Some(fps) = (1..header.fps)
+                        .rev()
+                        .find(|&fps| header.frame_memory() * part_frames(fps) as u64 <= budget)
+                    else {
+                        bail!(
+                            "Parts of {}x{} need more than {} MB even at 1 fps, try --part-memory-fit resolution",
+                            header.width,
+                            header.height,
+                            max_memory
+                        );
+                    };
+                    header.fps = fps;
+                    println!(
+                        "  Using {} fps, the highest rate that fits: one part of {} frames, {} decoded",
+                        fps,
+                        part_frames(fps),
+                        megabytes(memory(&header))
+                    );
                 }
-            }
-            LoopMode::LoopInfinite => {
-                if let Some(bg) = background {
-                    format!("c 0 0 part{} {}", part_idx, bg)
-                } else {
-                    format!("c 0 0 part{}", part_idx)
+                MemoryFit::Resolution => {
+                    let (width, height) = header.size_within(part_frames(header.fps) as u64, budget);
+                    header = header.resized(width, height);
+                    if memory(&header) > budget {
+                        bail!(
+                            "{} frames per part need more than {} MB at any resolution, lower --max-frames",
+                            part_frames(header.fps),
+                            max_memory
+                        );
+                    }
+                    println!(
+                        "  Using {}x{}, the largest size with the same aspect ratio that fits: {} decoded",
+                        header.width,
+                        header.height,
+                        megabytes(memory(&header))
+                    );
                 }
             }
-        };
-        writeln!(file, "{}", line)?;
+        }
     }
 
-    Ok(())
+    Ok(header)
 }
 
-fn create_bootanimation_zip(result_dir: &Path, output_path: &Path) -> Result<()> {
-    println!("Creating bootanimation.zip...");
-
-    let file = File::create(output_path).context("Failed to create output zip file")?;
-    let mut zip = zip::ZipWriter::new(BufWriter::new(file));
-
-    // use 0 compression
-    let options = FileOptions::<()>::default().compression_method(CompressionMethod::Stored);
-
-    // add desc.txt
-    let desc_path = result_dir.join("desc.txt");
-    zip.start_file("desc.txt", options)?;
-    let desc_content = fs::read(&desc_path)?;
-    zip.write_all(&desc_content)?;
-
-    // walk through all part directories
-    for entry in fs::read_dir(result_dir)? {
-        let entry = entry?;
-        let path = entry.path();
-
-        if path.is_dir()
-            && path
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .starts_with("part")
-        {
-            let part_name = path.file_name().unwrap().to_str().unwrap();
+/// How a part gets within the limit it is over
+fn memory_advice(issue: &MemoryIssue) -> String {
+    let (width, height) = issue.max_size;
+    match issue.max_frames {
+        0 => format!("lower the resolution to {}x{}", width, height),
+        most => format!(
+            "lower --max-frames to {} or the resolution to {}x{}",
+            most, width, height
+        ),
+    }
+}
 
-            // add all files in this part directory
-            for file_entry in fs::read_dir(&path)? {
-                let file_entry = file_entry?;
-                let file_path = file_entry.path();
+/// Prints the decoded memory of every part of the output, warning or failing past the limits
+fn check_part_memory(cli: &Cli) -> Result<()> {
+    let (desc, parts) = read_part_frames(&cli.output)?;
+    let header = &desc.header;
 
-                if file_path.is_file() {
-                    let file_name = file_path.file_name().unwrap().to_str().unwrap();
-                    let zip_path = format!("{}/{}", part_name, file_name);
+    println!("\nDecoded memory per part ({}x{}):", header.width, header.height);
+    for (name, frames) in &parts {
+        let bytes = part_memory(header, *frames);
+        println!("  {}: {} frames, {}", name, frames, megabytes(bytes));
+    }
 
-                    zip.start_file(&zip_path, options)?;
-                    let content = fs::read(&file_path)?;
-                    zip.write_all(&content)?;
-                }
-            }
+    let limits = MemoryLimits {
+        warn: (cli.warn_part_memory > 0.0).then_some(cli.warn_part_memory),
+        fail: cli.fail_part_memory,
+    };
+    let mut failed = Vec::new();
+    for issue in check_parts(header, &parts, limits) {
+        let message = format!("{}; {}", issue.describe(), memory_advice(&issue));
+        match issue.severity {
+            Severity::Warning => eprintln!("Warning: {}", message),
+            Severity::Failure => failed.push(message),
         }
     }
 
-    zip.finish()?;
+    if !failed.is_empty() {
+        bail!("{}", failed.join("\n"));
+    }
     Ok(())
 }
 
 fn main() -> Result<()> {
-    let cli = Cli::parse();
+    run(&Cli::parse(), &FfmpegCli::from_env())
+}
 
-    if !cli.input.exists() {
+fn run(cli: &Cli, backend: &dyn MediaBackend) -> Result<()> {
+
+    // image sequences, GIF, APNG and WebP inputs are decoded in-process,
+    // FFmpeg is only needed for videos
+    let image_input = if let Some(frames) = find_sequence(&cli.input)? {
+        if cli.fps.is_none() {
+            bail!("--fps is required when the input is an image sequence");
+        }
+        println!("Found image sequence ({} frames)", frames.len());
+        Some(ImageInput::Sequence(frames))
+    } else if !cli.input.exists() {
         bail!("Input video file does not exist: {}", cli.input.display());
-    }
+    } else if let Some(anim) = Animation::open(&cli.input)? {
+        println!("Decoded {:?} animation ({} frames)", anim.kind, anim.frames.len());
+        Some(ImageInput::Animation(anim))
+    } else {
+        None
+    };
+
+    // load template if specified
+    let template = if let Some(ref template_path) = cli.template {
+        if !template_path.exists() {
+            bail!("Template bootanimation file does not exist: {}", template_path.display());
+        }
+        Some(read_template(template_path)?)
+    } else {
+        None
+    };
 
     // load configuration from existing bootanimation if specified
     let bootanim_config = if let Some(ref config_path) = cli.config_from {
@@ -598,9 +1926,45 @@ fn main() -> Result<()> {
         }
         Some(read_config_from_bootanimation(config_path)?)
     } else {
-        None
+        template.as_ref().map(|t| t.desc.header.clone())
     };
 
+    // load device information, the most authoritative size wins and earlier
+    // files take precedence among equally authoritative ones
+    let mut device_sizes = Vec::new();
+    for info_path in &cli.device_info {
+        device_sizes.push(read_device_info(info_path)?);
+    }
+    let device_size = device_sizes
+        .into_iter()
+        .min_by_key(|(source, _)| *source)
+        .map(|(source, (width, height))| {
+            println!("Loaded device information:");
+            println!("  Resolution: {}x{} (from {})", width, height, source.describe());
+            (width, height)
+        });
+
+    let png_options = (cli.optimize_png || cli.png_colors.is_some()).then_some(PngOptions {
+        palette: cli.png_colors,
+        dither: !cli.no_dither,
+        bits: None,
+    });
+    if png_options.is_some() && cli.format != ImageFormat::Png {
+        bail!("--optimize-png and --png-colors need --format png");
+    }
+    if cli.quality.is_some() && cli.format != ImageFormat::Jpg {
+        eprintln!("Warning: --quality only applies to JPEG frames");
+    }
+    match (&image_input, cli.fps_mode) {
+        (Some(ImageInput::Animation(_)), FpsMode::Blend | FpsMode::Interpolate) => {
+            eprintln!("Warning: --fps-mode does not apply to animations, their frame delays are kept");
+        }
+        (Some(ImageInput::Sequence(_)), FpsMode::Interpolate) => {
+            eprintln!("Warning: image sequences cannot be motion-interpolated, frames are blended instead");
+        }
+        _ => {}
+    }
+
     // validate background color if provided
     let background = if let Some(ref bg) = cli.background {
         Some(validate_color(bg)?)
@@ -609,19 +1973,64 @@ fn main() -> Result<()> {
     };
 
     // determine what information we need from video properties
-    let need_width = cli.width.is_none() && bootanim_config.as_ref().map(|c| c.width).is_none();
-    let need_height = cli.height.is_none() && bootanim_config.as_ref().map(|c| c.height).is_none();
+    let need_width = cli.width.is_none()
+        && device_size.is_none()
+        && bootanim_config.as_ref().map(|c| c.width).is_none();
+    let need_height = cli.height.is_none()
+        && device_size.is_none()
+        && bootanim_config.as_ref().map(|c| c.height).is_none();
     let need_fps = cli.fps.is_none() && bootanim_config.as_ref().map(|c| c.fps).is_none();
     let need_audio_check = cli.with_audio;
+    // frame budgets are estimated from the duration
+    let need_duration = cli.max_total_frames.is_some() || cli.max_part_memory.is_some();
+    // parallel extraction estimates its slices from the exact frame rate and
+    // blending only pays off between different rates
+    let need_frame_rate = cli.jobs != 1 || cli.fps_mode != FpsMode::Drop;
 
     // only get video properties if we actually need them
-    let props = if need_width || need_height || need_fps || need_audio_check {
+    let props = if let Some(ref input) = image_input {
+        let p = match input {
+            ImageInput::Animation(anim) => MediaInfo {
+                width: anim.width,
+                height: anim.height,
+                fps: anim.natural_fps(),
+                frame_rate: anim.natural_fps() as f64,
+                variable_frame_rate: false,
+                duration: anim.duration(),
+                has_audio: false,
+            },
+            ImageInput::Sequence(frames) => {
+                let (width, height) = frame_size(&frames[0])?;
+                let frame_rate = cli.fps.unwrap_or(1.0);
+                MediaInfo {
+                    width,
+                    height,
+                    fps: frame_rate.round() as u32,
+                    frame_rate,
+                    variable_frame_rate: false,
+                    duration: frames.len() as f64 / frame_rate,
+                    has_audio: false,
+                }
+            }
+        };
+
+        println!("Input properties:");
+        println!("  Resolution: {}x{}", p.width, p.height);
+        println!("  FPS: {}", p.fps);
+        println!("  Duration: {:.2}s", p.duration);
+
+        Some(p)
+    } else if need_width || need_height || need_fps || need_audio_check || need_frame_rate || need_duration {
         println!("Analyzing video...");
-        let p = get_video_properties(&cli.input)?;
+        let p = backend.probe(&cli.input)?;
 
         println!("Video properties:");
         println!("  Resolution: {}x{}", p.width, p.height);
-        println!("  FPS: {}", p.fps);
+        if p.variable_frame_rate {
+            println!("  FPS: {} (variable)", describe_rate(p.frame_rate));
+        } else {
+            println!("  FPS: {}", describe_rate(p.frame_rate));
+        }
         println!("  Duration: {:.2}s", p.duration);
         println!("  Has audio: {}", p.has_audio);
 
@@ -632,13 +2041,15 @@ fn main() -> Result<()> {
     };
 
     // determine output resolution and fps
-    // priority-> CLI args > config from bootanimation > video properties
+    // priority-> CLI args > device info > config from bootanimation > video properties
     let width = cli.width
+        .or(device_size.map(|(w, _)| w))
         .or_else(|| bootanim_config.as_ref().map(|c| c.width))
         .or_else(|| props.as_ref().map(|p| p.width))
         .ok_or_else(|| anyhow::anyhow!("Width not specified and could not be determined from video"))?;
     
     let height = cli.height
+        .or(device_size.map(|(_, h)| h))
         .or_else(|| bootanim_config.as_ref().map(|c| c.height))
         .or_else(|| props.as_ref().map(|p| p.height))
         .ok_or_else(|| anyhow::anyhow!("Height not specified and could not be determined from video"))?;
@@ -652,11 +2063,28 @@ fn main() -> Result<()> {
     let use_global_format = bootanim_config.as_ref().map(|c| c.is_global_format).unwrap_or(false);
     let offset_x = bootanim_config.as_ref().map(|c| c.offset_x).unwrap_or(0);
     let offset_y = bootanim_config.as_ref().map(|c| c.offset_y).unwrap_or(0);
+    let progress = bootanim_config.as_ref().and_then(|c| c.progress);
 
     println!("\nOutput configuration:");
     println!("  Resolution: {}x{}", width, height);
+    println!("  Scale mode: {:?}", cli.scale_mode);
     println!("  FPS: {}", fps);
-    println!("  Loop mode: {:?}", cli.loop_mode);
+    if image_input.is_none()
+        && let Some(ref p) = props
+        && converts_frame_rate(Some(p), fps)
+    {
+        println!(
+            "  Frame rate conversion: {} -> {} fps ({:?})",
+            describe_rate(p.frame_rate),
+            fps,
+            cli.fps_mode
+        );
+    }
+    if template.is_some() {
+        println!("  Loop mode: from template");
+    } else {
+        println!("  Loop mode: {:?}", cli.loop_mode);
+    }
     if use_global_format {
         println!("  Format: global (with offsets x={}, y={})", offset_x, offset_y);
     }
@@ -665,66 +2093,54 @@ fn main() -> Result<()> {
     }
 
     // check audio requirements
-    if cli.with_audio {
-        if let Some(ref p) = props {
-            if !p.has_audio {
-                eprintln!("Warning: Audio requested but video has no audio stream");
-            }
-        }
+    if cli.with_audio
+        && let Some(ref p) = props
+        && !p.has_audio
+    {
+        eprintln!("Warning: Audio requested but video has no audio stream");
     }
 
-    // Get duration for audio extraction if needed
-    let duration = if cli.with_audio {
-        props.as_ref().map(|p| p.duration).unwrap_or(0.0)
-    } else {
-        0.0
-    };
-
     let has_audio = cli.with_audio && props.as_ref().map(|p| p.has_audio).unwrap_or(false);
 
-    // create temporary directory
-    let temp_dir = TempDir::new()?;
-    let frames_dir = temp_dir.path().join("frames");
-    let audio_dir = temp_dir.path().join("audio");
-    let result_dir = temp_dir.path().join("result");
-
-    fs::create_dir_all(&frames_dir)?;
-    fs::create_dir_all(&result_dir)?;
-
-    // extract frames
-    extract_frames(&cli.input, &frames_dir, width, height, cli.format)?;
-
-    // extract audio if requested
-    if has_audio {
-        fs::create_dir_all(&audio_dir)?;
-        extract_audio_blocks(&cli.input, &audio_dir, fps, duration)?;
-    }
-
-    // organize frames into parts
-    let num_parts = organize_frames_into_parts(&frames_dir, &result_dir, cli.max_frames)?;
-    println!("Created {} parts", num_parts + 1);
-
-    // add audio to parts if requested
-    if has_audio {
-        add_audio_to_parts(&audio_dir, &result_dir, num_parts)?;
-    }
-
-    // create desc.txt
-    create_desc_file(
-        &result_dir,
+    let header = Header {
         width,
         height,
         fps,
-        num_parts,
-        cli.loop_mode,
-        background.as_deref(),
-        use_global_format,
+        progress,
+        is_global_format: use_global_format,
         offset_x,
         offset_y,
-    )?;
+    };
+
+    let header = match props {
+        Some(ref p) if need_duration => fit_frame_budget(cli, header, p.duration)?,
+        _ => header,
+    };
+
+    let build = Build {
+        cli,
+        backend,
+        template: template.as_ref(),
+        background: background.as_deref(),
+        has_audio,
+        info: props.as_ref(),
+        image_input: image_input.as_ref(),
+        static_threshold: cli.collapse_static.then_some(cli.static_threshold),
+    };
+
+    let settings = FrameSettings {
+        quality: FrameQuality { jpeg: cli.quality },
+        png: png_options,
+    };
+    match cli.max_size {
+        Some(max_size) => fit_size_budget(&build, header, settings, max_size)?,
+        None => build.run(header, settings)?,
+    }
 
-    // create bootanimation.zip
-    create_bootanimation_zip(&result_dir, &cli.output)?;
+    if let Err(err) = check_part_memory(cli) {
+        let _ = fs::remove_file(&cli.output);
+        return Err(err);
+    }
 
     println!(
         "Successfully created bootanimation: {}",
@@ -733,3 +2149,651 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bootanimation_tools::media::FakeBackend;
+
+    #[test]
+    fn device_size_prefers_override_over_physical_and_build_prop() {
+        let dump = "ro.surface_flinger.max_graphics_width=720\n\
+                    ro.surface_flinger.max_graphics_height=1280\n\
+                    Physical size: 1440x3200\n\
+                    Override size: 1080x2400\n";
+        assert_eq!(
+            parse_device_info(dump),
+            Some((SizeSource::Override, (1080, 2400)))
+        );
+    }
+
+    #[test]
+    fn device_size_falls_back_by_source_priority() {
+        let build_prop = "ro.surface_flinger.max_graphics_width=720\n\
+                          ro.surface_flinger.max_graphics_height=1280\n";
+        let physical = "Physical size: 1440x3200\n";
+        let display = "DisplayInfo{\"Built-in Screen\", real 1200 x 2670, rotation 0}\n";
+        let wm_override = "Override size: 1080x2400\n";
+
+        // every source in one dump, in any order, the override wins
+        let all = format!("{}{}{}{}", wm_override, display, build_prop, physical);
+        assert_eq!(
+            parse_device_info(&all),
+            Some((SizeSource::Override, (1080, 2400)))
+        );
+        let all = format!("{}{}{}{}", build_prop, display, physical, wm_override);
+        assert_eq!(
+            parse_device_info(&all),
+            Some((SizeSource::Override, (1080, 2400)))
+        );
+
+        let without_override = format!("{}{}{}", build_prop, display, physical);
+        assert_eq!(
+            parse_device_info(&without_override),
+            Some((SizeSource::Physical, (1440, 3200)))
+        );
+        let without_wm = format!("{}{}", build_prop, display);
+        assert_eq!(
+            parse_device_info(&without_wm),
+            Some((SizeSource::DisplayInfo, (1200, 2670)))
+        );
+        assert_eq!(
+            parse_device_info(build_prop),
+            Some((SizeSource::BuildProp, (720, 1280)))
+        );
+    }
+
+    #[test]
+    fn device_size_never_mixes_sources() {
+        // only the width is in build.prop, so the dumpsys size is used as a whole
+        let dump = "ro.surface_flinger.max_graphics_width=720\n\
+                    DisplayInfo{\"Built-in Screen\", real 2400 x 1080, rotation 1, density 420}\n";
+        assert_eq!(
+            parse_device_info(dump),
+            Some((SizeSource::DisplayInfo, (1080, 2400)))
+        );
+        assert_eq!(parse_device_info("Physical density: 420\n"), None);
+    }
+
+    /// File names and contents of extracted frames
+    type FrameList = Vec<(String, Vec<u8>)>;
+
+    fn frame_list(dir: &Path) -> FrameList {
+        let mut frames: Vec<_> = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| {
+                let path = entry.unwrap().path();
+                let name = path.file_name().unwrap().to_string_lossy().to_string();
+                (name, fs::read(&path).unwrap())
+            })
+            .collect();
+        frames.sort();
+        frames
+    }
+
+    fn extract_both_ways(backend: &FakeBackend, fps: Option<u32>) -> (FrameList, FrameList) {
+        let cli = Cli::parse_from([
+            "vid2boot",
+            "-i",
+            "in.mp4",
+            "-o",
+            "out.zip",
+            "-j",
+            "3",
+            "--max-frames",
+            "4",
+        ]);
+        let request = FrameRequest {
+            width: 8,
+            height: 8,
+            scale_mode: ScaleMode::Stretch,
+            background: None,
+            format: ImageFormat::Png,
+            quality: FrameQuality::default(),
+            fps,
+            fps_mode: FpsMode::Drop,
+            start: 0,
+            count: None,
+            frame_rate: fps.map_or(backend.info.frame_rate, |fps| fps as f64),
+        };
+
+        let sequential = TempDir::new().unwrap();
+        let parallel = TempDir::new().unwrap();
+        extract_frames(backend, &cli.input, sequential.path(), &request).unwrap();
+        extract_frames_parallel(&cli, backend, parallel.path(), &request, &backend.info).unwrap();
+        (frame_list(sequential.path()), frame_list(parallel.path()))
+    }
+
+    #[test]
+    fn parallel_extraction_matches_a_single_run() {
+        // 23 frames at 25 fps become 28 frames at 30 fps, the last slice is partial
+        let backend = FakeBackend::new(8, 8, 25, 23, false);
+        let (sequential, parallel) = extract_both_ways(&backend, Some(30));
+        assert_eq!(sequential.len(), 28);
+        assert_eq!(parallel, sequential);
+
+        let (sequential, parallel) = extract_both_ways(&backend, None);
+        assert_eq!(sequential.len(), 23);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn parallel_slices_seeking_into_the_video_are_numbered_like_a_single_run() {
+        // 6 s at 25 fps, slices after the first second start past the seek margin
+        let backend = FakeBackend::new(8, 8, 25, 150, false);
+        let (sequential, parallel) = extract_both_ways(&backend, Some(30));
+        assert_eq!(sequential.len(), 180);
+        assert_eq!(parallel, sequential);
+
+        let (sequential, parallel) = extract_both_ways(&backend, None);
+        assert_eq!(sequential.len(), 150);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn parallel_extraction_continues_past_a_short_duration_estimate() {
+        let mut backend = FakeBackend::new(8, 8, 25, 23, false);
+        backend.info.duration = 0.2;
+        let (sequential, parallel) = extract_both_ways(&backend, None);
+        assert_eq!(sequential.len(), 23);
+        assert_eq!(parallel, sequential);
+    }
+
+    #[test]
+    fn resampled_image_sequences_fill_the_parts_at_the_new_rate() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("sequence");
+        fs::create_dir_all(&input).unwrap();
+        for idx in 0..10u8 {
+            image::RgbImage::from_pixel(16, 16, image::Rgb([idx * 20, 0, 0]))
+                .save(input.join(format!("frame{}.png", idx)))
+                .unwrap();
+        }
+        let output = temp.path().join("bootanimation.zip");
+
+        // the frame budget halves the rate, the sequence becomes 5 frames
+        let cli = Cli::parse_from([
+            "vid2boot".as_ref(),
+            "-i".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+            "--fps".as_ref(),
+            "10".as_ref(),
+            "--max-total-frames".as_ref(),
+            "5".as_ref(),
+            "--max-frames".as_ref(),
+            "2".as_ref(),
+        ]);
+        run(&cli, &FakeBackend::new(16, 16, 10, 0, false)).unwrap();
+
+        let (desc, parts) = read_part_frames(&output).unwrap();
+        assert_eq!(desc.header.fps, 5);
+        let counts: Vec<u32> = parts.iter().map(|(_, count)| *count).collect();
+        assert_eq!(counts, [2, 2, 1]);
+    }
+
+    #[test]
+    fn videos_are_split_into_parts_with_matching_audio() {
+        let temp = TempDir::new().unwrap();
+        // not a GIF/APNG/WebP, so it is read through the media backend
+        let input = temp.path().join("in.mp4");
+        fs::write(&input, "video").unwrap();
+        let output = temp.path().join("bootanimation.zip");
+
+        let cli = Cli::parse_from([
+            "vid2boot".as_ref(),
+            "-i".as_ref(),
+            input.as_os_str(),
+            "-o".as_ref(),
+            output.as_os_str(),
+            "--format".as_ref(),
+            "png".as_ref(),
+            "--max-frames".as_ref(),
+            "30".as_ref(),
+            "--with-audio".as_ref(),
+        ]);
+        // 75 frames at 30 fps, 2.5 seconds
+        run(&cli, &FakeBackend::new(16, 16, 30, 75, true)).unwrap();
+
+        let (desc, parts) = read_part_frames(&output).unwrap();
+        assert_eq!((desc.header.width, desc.header.height, desc.header.fps), (16, 16, 30));
+        let counts: Vec<u32> = parts.iter().map(|(_, count)| *count).collect();
+        assert_eq!(counts, [30, 30, 15]);
+
+        // every part gets the audio playing during its frames, 44100 stereo samples per second
+        let mut zip = zip::ZipArchive::new(fs::File::open(&output).unwrap()).unwrap();
+        for ((name, _), seconds) in parts.iter().zip([1.0, 1.0, 0.5]) {
+            let audio = zip.by_name(&format!("{}/audio.wav", name)).unwrap();
+            assert_eq!(audio.size(), 44 + (44100.0 * seconds) as u64 * 4);
+        }
+    }
+
+    #[test]
+    fn max_size_lowers_png_frames_until_they_fit() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("sequence");
+        fs::create_dir_all(&input).unwrap();
+        // noise keeps the frames from compressing, only fewer colors make them smaller
+        let mut seed = 1u32;
+        for idx in 0..6 {
+            image::RgbImage::from_fn(32, 32, |_, _| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let [r, g, b, _] = seed.to_be_bytes();
+                image::Rgb([r, g, b])
+            })
+            .save(input.join(format!("frame{}.png", idx)))
+            .unwrap();
+        }
+
+        let build = |output: &Path, max_size: Option<f64>| -> Result<u64> {
+            let mut args = vec![
+                "vid2boot".into(),
+                "-i".into(),
+                input.clone().into_os_string(),
+                "-o".into(),
+                output.as_os_str().to_owned(),
+                "--fps".into(),
+                "6".into(),
+                "--format".into(),
+                "png".into(),
+                "--size-priority".into(),
+                "quality".into(),
+            ];
+            if let Some(max_size) = max_size {
+                args.extend(["--max-size".into(), max_size.to_string().into()]);
+            }
+            run(&Cli::parse_from(args), &FakeBackend::new(32, 32, 6, 0, false))?;
+            Ok(fs::metadata(output)?.len())
+        };
+
+        let full = build(&temp.path().join("full.zip"), None).unwrap();
+        // a third of the size is out of reach of color depth alone
+        let budget = full / 3;
+        let fitted = build(
+            &temp.path().join("fitted.zip"),
+            Some(budget as f64 / (1024.0 * 1024.0)),
+        )
+        .unwrap();
+        assert!(fitted <= budget, "{} bytes is over the {} byte budget", fitted, budget);
+        let (desc, parts) = read_part_frames(&temp.path().join("fitted.zip")).unwrap();
+        assert_eq!((desc.header.width, desc.header.fps), (32, 6));
+        assert_eq!(parts[0].1, 6);
+
+        assert!(build(&temp.path().join("tiny.zip"), Some(100.0 / (1024.0 * 1024.0))).is_err());
+        assert!(!temp.path().join("tiny.zip").exists());
+    }
+
+    /// Template with `frames` in every part directory of `desc`
+    fn template(desc: &str, frames: &[u32]) -> Template {
+        let desc = Desc::parse(desc).unwrap();
+        let parts = desc
+            .part_dirs()
+            .into_iter()
+            .zip(frames)
+            .map(|(name, &frames)| TemplatePart {
+                name: name.to_string(),
+                frames,
+                audio: None,
+                trim: None,
+            })
+            .collect();
+        Template {
+            _dir: TempDir::new().unwrap(),
+            desc,
+            parts,
+        }
+    }
+
+    fn layout_frames(
+        template: &Template,
+        total: u32,
+        fps: u32,
+        mapping: TemplateMapping,
+    ) -> Vec<u32> {
+        template_layout(template, total, fps, mapping)
+            .unwrap()
+            .iter()
+            .map(|part| part.frames)
+            .collect()
+    }
+
+    const TEMPLATE: &str = "1080 1920 30\np 1 0 part0\np 0 0 part1\nc 1 6 part2\n";
+
+    #[test]
+    fn template_proportions_share_out_every_source_frame() {
+        let template = template(TEMPLATE, &[30, 60, 10]);
+        // fewer source frames than the template has, every part keeps at least one
+        assert_eq!(layout_frames(&template, 50, 30, TemplateMapping::Proportion), [15, 29, 6]);
+        assert_eq!(layout_frames(&template, 3, 30, TemplateMapping::Proportion), [1, 1, 1]);
+        assert!(template_layout(&template, 2, 30, TemplateMapping::Proportion).is_err());
+        // more source frames than the template has
+        assert_eq!(layout_frames(&template, 200, 30, TemplateMapping::Proportion), [60, 119, 21]);
+
+        // empty template parts stay empty
+        let template = self::template(TEMPLATE, &[30, 0, 10]);
+        assert_eq!(layout_frames(&template, 8, 30, TemplateMapping::Proportion), [6, 0, 2]);
+    }
+
+    #[test]
+    fn template_durations_follow_the_output_rate() {
+        let template = template(TEMPLATE, &[30, 60, 10]);
+        // at 15 fps the parts last 15, 30 and 5 frames, extra source frames are dropped
+        assert_eq!(layout_frames(&template, 100, 15, TemplateMapping::Duration), [15, 30, 5]);
+        // a short source cuts the last part short
+        assert_eq!(layout_frames(&template, 47, 15, TemplateMapping::Duration), [15, 30, 2]);
+        // and fails once a part would get nothing
+        assert!(template_layout(&template, 45, 15, TemplateMapping::Duration).is_err());
+    }
+
+    #[test]
+    fn template_desc_keeps_counts_and_rescales_pauses() {
+        let template = template(TEMPLATE, &[30, 60, 10]);
+        let mut layout = template_layout(&template, 50, 15, TemplateMapping::Proportion).unwrap();
+        // two static frames collapsed at the end of part2
+        layout[2].pause = 2;
+        let header = Header {
+            fps: 15,
+            ..template.desc.header.clone()
+        };
+
+        let desc = template_desc(&template, header, &layout);
+        let parts: Vec<(char, u32, u32, &str)> = desc
+            .parts
+            .iter()
+            .map(|part| (part.kind.as_char(), part.count, part.pause, part.path.as_str()))
+            .collect();
+        assert_eq!(
+            parts,
+            [('p', 1, 0, "part0"), ('p', 0, 0, "part1"), ('c', 1, 5, "part2")]
+        );
+        assert_eq!(desc.header.fps, 15);
+    }
+
+    #[test]
+    fn streamed_output_is_reproducible_and_in_canonical_order() {
+        let temp = TempDir::new().unwrap();
+        let input = temp.path().join("in.mp4");
+        fs::write(&input, "video").unwrap();
+
+        let build = |name: &str| -> PathBuf {
+            let output = temp.path().join(name);
+            let cli = Cli::parse_from([
+                "vid2boot".as_ref(),
+                "-i".as_ref(),
+                input.as_os_str(),
+                "-o".as_ref(),
+                output.as_os_str(),
+                "--max-frames".as_ref(),
+                "2".as_ref(),
+                "--with-audio".as_ref(),
+            ]);
+            run(&cli, &FakeBackend::new(8, 8, 10, 3, true)).unwrap();
+            output
+        };
+        let first = build("first.zip");
+        let second = build("second.zip");
+        assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+
+        // audio is extracted after the frames but still lands inside its part
+        let mut zip = zip::ZipArchive::new(fs::File::open(&first).unwrap()).unwrap();
+        let names: Vec<String> = (0..zip.len())
+            .map(|i| zip.by_index(i).unwrap().name().to_string())
+            .collect();
+        assert_eq!(
+            names,
+            [
+                "desc.txt",
+                "part0/000001.jpg",
+                "part0/000002.jpg",
+                "part0/audio.wav",
+                "part1/000003.jpg",
+                "part1/audio.wav",
+            ]
+        );
+    }
+
+    /// Fake video frame `idx` as a PNG, `nudge` added to every channel
+    fn fake_frame(idx: u32, nudge: u8) -> Vec<u8> {
+        let color = FakeBackend::frame_color(idx).map(|c| c.saturating_add(nudge));
+        let image = image::RgbImage::from_pixel(8, 8, image::Rgb(color));
+        encode_frame(&image, ImageFormat::Png, FrameQuality::default()).unwrap()
+    }
+
+    /// Pauses and kept frame counts of `parts` collapsed while streaming into a zip
+    fn collapse_streamed(threshold: f64, parts: &[Vec<Vec<u8>>]) -> (Vec<u32>, Vec<u32>) {
+        let temp = TempDir::new().unwrap();
+        let output = temp.path().join("bootanimation.zip");
+        let mut zip = BootanimationWriter::create(&output).unwrap();
+        let collapser = StaticCollapser::new(threshold);
+        let mut writer = PartWriter::new(&mut zip, None, Some(&collapser));
+        let mut count = 0;
+        for (idx, frames) in parts.iter().enumerate() {
+            let part = format!("part{}", idx);
+            for frame in frames {
+                count += 1;
+                let name = format!("{}/{:06}.png", part, count);
+                writer.add(&part, &name, frame).unwrap();
+            }
+        }
+        let pauses = writer.finish();
+        zip.add("desc.txt", b"8 8 30\n").unwrap();
+        zip.finish().unwrap();
+
+        let zip = zip::ZipArchive::new(fs::File::open(&output).unwrap()).unwrap();
+        let kept = (0..parts.len())
+            .map(|idx| {
+                let prefix = format!("part{}/", idx);
+                zip.file_names().filter(|name| name.starts_with(&prefix)).count() as u32
+            })
+            .collect();
+        (pauses, kept)
+    }
+
+    /// Same as `collapse_streamed`, for frames extracted to folders first
+    fn collapse_files(threshold: f64, parts: &[Vec<Vec<u8>>]) -> (Vec<u32>, Vec<u32>) {
+        let temp = TempDir::new().unwrap();
+        let collapser = StaticCollapser::new(threshold);
+        let mut pauses = Vec::new();
+        let mut kept = Vec::new();
+        for (idx, frames) in parts.iter().enumerate() {
+            let part = format!("part{}", idx);
+            let dir = temp.path().join(&part);
+            fs::create_dir_all(&dir).unwrap();
+            let files: Vec<PathBuf> = frames
+                .iter()
+                .enumerate()
+                .map(|(n, frame)| {
+                    let file = dir.join(format!("{:06}.png", n + 1));
+                    fs::write(&file, frame).unwrap();
+                    file
+                })
+                .collect();
+            pauses.push(collapser.collapse_files(&part, &files).unwrap());
+            kept.push(list_frames(&dir).unwrap().len() as u32);
+        }
+        (pauses, kept)
+    }
+
+    #[test]
+    fn static_part_ends_become_pauses() {
+        let frames = |indices: &[u32]| -> Vec<Vec<u8>> {
+            indices.iter().map(|&idx| fake_frame(idx, 0)).collect()
+        };
+        let parts = [
+            frames(&[0, 1, 1, 1]),
+            // starts on the frame the previous part ended with, at a part boundary
+            frames(&[1, 2, 3]),
+            // static from its first frame on, the whole tail is one pause
+            frames(&[4, 4, 4]),
+        ];
+
+        let (pauses, kept) = collapse_streamed(0.0, &parts);
+        assert_eq!(pauses, [2, 0, 2]);
+        assert_eq!(kept, [2, 3, 1]);
+        assert_eq!(collapse_files(0.0, &parts), (pauses.clone(), kept.clone()));
+
+        let layout: Vec<PartLayout> = kept
+            .iter()
+            .zip(&pauses)
+            .enumerate()
+            .map(|(idx, (&frames, &pause))| PartLayout {
+                name: format!("part{}", idx),
+                frames,
+                audio: false,
+                pause,
+            })
+            .collect();
+        let header = Header {
+            width: 8,
+            height: 8,
+            fps: 30,
+            progress: None,
+            is_global_format: false,
+            offset_x: 0,
+            offset_y: 0,
+        };
+        let desc = build_desc(header, &layout, LoopMode::StopOnBoot, None);
+        assert_eq!(
+            desc.to_string().lines().skip(1).collect::<Vec<_>>(),
+            ["p 1 2 part0", "p 1 0 part1", "p 1 2 part2"]
+        );
+    }
+
+    #[test]
+    fn static_threshold_collapses_nearly_identical_frames() {
+        let parts = [vec![
+            fake_frame(5, 0),
+            fake_frame(6, 0),
+            fake_frame(6, 2),
+            fake_frame(6, 1),
+        ]];
+
+        // only identical frames count without a threshold
+        assert_eq!(collapse_streamed(0.0, &parts), (vec![0], vec![4]));
+        assert_eq!(collapse_files(0.0, &parts), (vec![0], vec![4]));
+
+        assert_eq!(collapse_streamed(3.0, &parts), (vec![2], vec![2]));
+        assert_eq!(collapse_files(3.0, &parts), (vec![2], vec![2]));
+    }
+
+    /// Part sizes for frames of the given gray levels, at 10 fps with scene splitting
+    fn split_sizes(levels: &[u8]) -> (Vec<u32>, u32) {
+        let cli = Cli::parse_from([
+            "vid2boot",
+            "-i",
+            "in.mp4",
+            "-o",
+            "out.zip",
+            "--max-frames",
+            "10",
+            "--split-at-scenes",
+        ]);
+        let mut splitter = PartSplitter::new(&cli, 10);
+        for &level in levels {
+            let image = image::RgbImage::from_pixel(8, 8, image::Rgb([level; 3]));
+            let frame = encode_frame(&image, ImageFormat::Png, FrameQuality::default()).unwrap();
+            splitter.next(&frame).unwrap();
+        }
+        let cuts = splitter.cuts;
+        let sizes = splitter.layout().iter().map(|part| part.frames).collect();
+        (sizes, cuts)
+    }
+
+    #[test]
+    fn parts_split_at_scene_cuts_at_least_half_a_second_apart() {
+        let scene = |level: u8, frames: usize| vec![level; frames];
+        let levels = [scene(0, 7), scene(255, 3), scene(100, 8)].concat();
+        // the cut to 100 comes 3 frames into the second part, too short to end it,
+        // so the part runs on to --max-frames
+        assert_eq!(split_sizes(&levels), (vec![7, 10, 1], 1));
+    }
+
+    #[test]
+    fn parts_without_cuts_only_split_at_max_frames() {
+        let levels: Vec<u8> = (0..23).map(|idx| idx * 5).collect();
+        assert_eq!(split_sizes(&levels), (vec![10, 10, 3], 0));
+    }
+
+    /// Finds the loop in frames of the given gray levels at 10 fps, searching one
+    /// second at each end; returns the kept levels and the first kept frame
+    fn find_loop_in(levels: &[u8], fade: u32) -> Result<(Vec<u8>, u32)> {
+        let temp = TempDir::new().unwrap();
+        let frames: Vec<PathBuf> = levels
+            .iter()
+            .enumerate()
+            .map(|(idx, &level)| {
+                let path = temp.path().join(format!("{:06}.png", idx + 1));
+                image::RgbImage::from_pixel(8, 8, image::Rgb([level; 3]))
+                    .save(&path)
+                    .unwrap();
+                path
+            })
+            .collect();
+        let fade = fade.to_string();
+        let cli = Cli::parse_from([
+            "vid2boot",
+            "-i",
+            "in.mp4",
+            "-o",
+            "out.zip",
+            "--find-loop",
+            "1",
+            "--loop-crossfade",
+            &fade,
+            "--loop-mode",
+            "loop-infinite",
+        ]);
+        let request = FrameRequest {
+            width: 8,
+            height: 8,
+            scale_mode: ScaleMode::Stretch,
+            background: None,
+            format: ImageFormat::Png,
+            quality: FrameQuality::default(),
+            fps: None,
+            fps_mode: FpsMode::Drop,
+            start: 0,
+            count: None,
+            frame_rate: 10.0,
+        };
+
+        let (kept, first) = find_seamless_loop(&cli, frames, 10, &request)?;
+        assert_eq!(list_frames(temp.path()).unwrap(), kept);
+        let levels = kept
+            .iter()
+            .map(|frame| decode_encoded(&fs::read(frame).unwrap()).unwrap().get_pixel(0, 0)[0])
+            .collect();
+        Ok((levels, first))
+    }
+
+    #[test]
+    fn loops_end_on_the_frame_matching_their_start() {
+        // frame 17 repeats frame 3, every other frame is 10 levels from its neighbours
+        let mut levels: Vec<u8> = (0..20).map(|idx| idx * 10).collect();
+        levels[17] = levels[3];
+
+        let (kept, first) = find_loop_in(&levels, 0).unwrap();
+        assert_eq!(first, 3);
+        assert_eq!(kept, levels[3..17]);
+
+        // the last two frames fade into the two leading to the start
+        let (kept, first) = find_loop_in(&levels, 2).unwrap();
+        assert_eq!(first, 3);
+        assert_eq!(kept.len(), 14);
+        assert_eq!(kept[..12], levels[3..15]);
+        // 150 one third of the way to 10, 160 two thirds of the way to 20
+        assert_eq!(kept[12..], [103, 67]);
+    }
+
+    #[test]
+    fn loops_need_more_frames_than_the_crossfade() {
+        let levels: Vec<u8> = (0..6).map(|idx| idx * 10).collect();
+        for frames in [3, 6] {
+            let err = find_loop_in(&levels[..frames], 5).unwrap_err();
+            assert!(err.to_string().contains("too short"), "{}", err);
+        }
+        // without a crossfade the same frames hold a loop
+        assert!(find_loop_in(&levels[..3], 0).is_ok());
+    }
+}